@@ -0,0 +1,685 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use color_eyre::eyre::Result;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{Method, StatusCode};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+
+use crate::config::{Config, Diff, Job, JobSchedule, Service};
+use crate::docker::api::StartedContainerDetails;
+use crate::docker::models::ContainerId;
+use crate::ipc::MessageBus;
+use crate::jobs::{JobRun, JobRuns};
+use crate::service_registry::ServiceRegistry;
+
+/// The service definitions an instance is currently running with, as returned by `GET /config`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServiceDefinitions {
+    pub services: HashMap<String, Service>,
+}
+
+impl ServiceDefinitions {
+    /// Collects the definitions with each environment value replaced by its SHA-256 digest, so
+    /// that secrets are not revealed but changes to them can still be compared.
+    pub fn redacted(services: &HashMap<String, Service>) -> Self {
+        let services = services
+            .iter()
+            .map(|(name, service)| {
+                let environment = service
+                    .environment
+                    .iter()
+                    .map(|(key, value)| {
+                        let digest = hex::encode(Sha256::digest(value));
+                        (key.clone(), format!("sha256:{digest}"))
+                    })
+                    .collect();
+
+                let service = Service {
+                    environment,
+                    ..service.clone()
+                };
+
+                (name.clone(), service)
+            })
+            .collect();
+
+        Self { services }
+    }
+}
+
+/// Serves a JSON API for inspecting and controlling the running services, which requires the
+/// bearer token from the configuration on every request.
+pub struct AdminServer {
+    registry: Arc<RwLock<ServiceRegistry>>,
+    job_runs: Arc<RwLock<JobRuns>>,
+    config: Arc<ArcSwap<Config>>,
+    message_bus: Arc<MessageBus>,
+}
+
+impl AdminServer {
+    pub fn new(
+        registry: Arc<RwLock<ServiceRegistry>>,
+        job_runs: Arc<RwLock<JobRuns>>,
+        config: Arc<ArcSwap<Config>>,
+        message_bus: Arc<MessageBus>,
+    ) -> Self {
+        Self {
+            registry,
+            job_runs,
+            config,
+            message_bus,
+        }
+    }
+
+    pub async fn run(self, listener: TcpListener) -> Result<()> {
+        let server = Arc::new(self);
+
+        tracing::info!("starting admin server on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, client_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!(%e, "failed to accept admin connection");
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&server);
+
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let server = Arc::clone(&server);
+                    async move { server.handle(req).await }
+                });
+
+                if let Err(e) = Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    tracing::warn!(%client_addr, %e, "error handling admin connection");
+                }
+            });
+        }
+    }
+
+    async fn handle<B>(&self, req: Request<B>) -> Result<Response<Full<Bytes>>> {
+        if !self.is_authorized(&req) {
+            tracing::warn!(method = %req.method(), uri = %req.uri(), "rejecting unauthorized admin request");
+
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                json!({ "error": "missing or invalid bearer token" }),
+            );
+        }
+
+        let path = req.uri().path().trim_matches('/');
+        let segments: Vec<_> = path.split('/').collect();
+
+        tracing::info!(method = %req.method(), %path, "handling admin request");
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["services"]) => self.list_services().await,
+            (&Method::GET, ["config"]) => {
+                let definitions = ServiceDefinitions::redacted(&self.config.load().services);
+
+                json_response(StatusCode::OK, serde_json::to_value(definitions)?)
+            }
+            (&Method::GET, ["reconciliation"]) => self.last_reconciliation().await,
+            (&Method::GET, ["jobs"]) => self.list_jobs().await,
+            (&Method::POST, ["reconciliation"]) => {
+                let request = self.message_bus.send_reconciliation_request()?;
+
+                json_response(
+                    StatusCode::ACCEPTED,
+                    json!({ "request": request.to_string() }),
+                )
+            }
+            (&Method::POST, ["certificates"]) => {
+                let request = self.message_bus.send_certificate_update_request()?;
+
+                json_response(
+                    StatusCode::ACCEPTED,
+                    json!({ "request": request.to_string() }),
+                )
+            }
+            (&Method::POST, ["containers", id, "drain"]) => {
+                let id = ContainerId((*id).to_owned());
+
+                if !self.registry.write().await.drain(&id) {
+                    return unknown_container(&id);
+                }
+
+                json_response(StatusCode::OK, json!({ "id": id.0, "draining": true }))
+            }
+            (&Method::POST, ["containers", id, "undrain"]) => {
+                let id = ContainerId((*id).to_owned());
+
+                if !self.registry.write().await.undrain(&id) {
+                    return unknown_container(&id);
+                }
+
+                json_response(StatusCode::OK, json!({ "id": id.0, "draining": false }))
+            }
+            (&Method::POST, ["containers", id, "restart"]) => {
+                let id = ContainerId((*id).to_owned());
+
+                if self.registry.read().await.find_container(&id).is_none() {
+                    return unknown_container(&id);
+                }
+
+                let request = self.message_bus.send_container_restart_request(id)?;
+
+                json_response(
+                    StatusCode::ACCEPTED,
+                    json!({ "request": request.to_string() }),
+                )
+            }
+            _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+        }
+    }
+
+    /// Checks the bearer token on a request, comparing digests so the time taken does not depend
+    /// on how much of the token was correct.
+    fn is_authorized<B>(&self, req: &Request<B>) -> bool {
+        let config = self.config.load();
+
+        let Some(admin) = config.alb.admin.as_ref() else {
+            return false;
+        };
+
+        let Some(presented) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        Sha256::digest(presented) == Sha256::digest(&admin.token)
+    }
+
+    async fn list_services(&self) -> Result<Response<Full<Bytes>>> {
+        let registry = self.registry.read().await;
+
+        let mut names: Vec<_> = registry.get_definitions().keys().collect();
+        names.sort();
+
+        let services: Vec<_> = names
+            .into_iter()
+            .filter_map(|name| {
+                let definition = registry.get_definitions().get(name)?;
+
+                Some(describe_service(&registry, name, definition))
+            })
+            .collect();
+
+        json_response(StatusCode::OK, json!({ "services": services }))
+    }
+
+    async fn last_reconciliation(&self) -> Result<Response<Full<Bytes>>> {
+        let registry = self.registry.read().await;
+
+        let Some(record) = registry.get_last_reconciliation() else {
+            return json_response(StatusCode::OK, json!({ "reconciliation": null }));
+        };
+
+        let changes: Vec<_> = record.changes.iter().map(describe_diff).collect();
+
+        json_response(
+            StatusCode::OK,
+            json!({
+                "reconciliation": {
+                    "timestamp": record.timestamp.to_rfc3339(),
+                    "changes": changes,
+                    "failures": record.failures,
+                }
+            }),
+        )
+    }
+
+    async fn list_jobs(&self) -> Result<Response<Full<Bytes>>> {
+        let config = self.config.load();
+        let job_runs = self.job_runs.read().await;
+
+        let mut names: Vec<_> = config.jobs.keys().collect();
+        names.sort();
+
+        let jobs: Vec<_> = names
+            .into_iter()
+            .map(|name| describe_job(name, &config.jobs[name], job_runs.get(name)))
+            .collect();
+
+        json_response(StatusCode::OK, json!({ "jobs": jobs }))
+    }
+}
+
+fn describe_job(name: &str, job: &Job, run: Option<&JobRun>) -> Value {
+    let schedule = match &job.schedule {
+        JobSchedule::Cron { expression } => json!({
+            "trigger": "cron",
+            "expression": expression.as_str(),
+        }),
+        JobSchedule::OnDeploy => json!({ "trigger": "on_deploy" }),
+    };
+
+    let last_run = run.map(|run| {
+        json!({
+            "container": run.container.0,
+            "started": run.started.to_rfc3339(),
+            "finished": run.outcome.as_ref().map(|outcome| outcome.finished.to_rfc3339()),
+            "exit_code": run.outcome.as_ref().map(|outcome| outcome.exit_code),
+            "logs": run.outcome.as_ref().map(|outcome| &outcome.logs),
+        })
+    });
+
+    json!({
+        "name": name,
+        "image": job.image,
+        "tag": job.tag,
+        "schedule": schedule,
+        "last_run": last_run,
+    })
+}
+
+fn describe_service(registry: &ServiceRegistry, name: &str, definition: &Service) -> Value {
+    let mut routes: Vec<_> = definition
+        .routes
+        .iter()
+        .map(|route| {
+            json!({
+                "host": route.host,
+                "prefix": route.prefix,
+                "port": route.port,
+                "aliases": route.aliases,
+            })
+        })
+        .collect();
+
+    routes.sort_by_key(|route| route.to_string());
+
+    let describe_container = |details: &StartedContainerDetails, track: &str| {
+        json!({
+            "id": details.id.0,
+            "addr": details.addr,
+            "track": track,
+            "healthy": registry.is_healthy(&details.id),
+            "draining": registry.is_draining(&details.id),
+        })
+    };
+
+    let stable = registry
+        .get_running_containers(name)
+        .into_iter()
+        .flatten()
+        .map(|details| describe_container(details, "stable"));
+
+    let canary = registry
+        .get_canary_containers(name)
+        .into_iter()
+        .flatten()
+        .map(|details| describe_container(details, "canary"));
+
+    json!({
+        "name": name,
+        "image": definition.image,
+        "tag": definition.tag,
+        "replicas": definition.replicas.get(),
+        "canary": definition.canary.as_ref().map(|canary| json!({
+            "tag": canary.tag,
+            "replicas": canary.replicas.get(),
+            "weight": *canary.weight,
+        })),
+        "routes": routes,
+        "containers": stable.chain(canary).collect::<Vec<_>>(),
+    })
+}
+
+fn describe_diff(diff: &Diff) -> Value {
+    match diff {
+        Diff::Addition { name, definition } => json!({
+            "kind": "addition",
+            "service": name,
+            "tag": definition.tag,
+        }),
+        Diff::Alteration {
+            name,
+            old_definition,
+            new_definition,
+        } => json!({
+            "kind": "alteration",
+            "service": name,
+            "old_tag": old_definition.tag,
+            "new_tag": new_definition.tag,
+        }),
+        Diff::Removal { name } => json!({
+            "kind": "removal",
+            "service": name,
+        }),
+    }
+}
+
+fn unknown_container(id: &ContainerId) -> Result<Response<Full<Bytes>>> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({ "error": format!("container {id} is not registered") }),
+    )
+}
+
+fn json_response(status: StatusCode, body: Value) -> Result<Response<Full<Bytes>>> {
+    let response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(body.to_string()))?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use color_eyre::eyre::Result;
+    use http::header::AUTHORIZATION;
+    use http::{Method, Request, StatusCode};
+    use http_body_util::BodyExt;
+    use tokio::sync::RwLock;
+
+    use crate::admin::{AdminServer, ServiceDefinitions};
+    use crate::config::{AdminConfig, AlbConfig, Config, Job, JobSchedule, Route, Scheme, Service};
+    use crate::docker::api::StartedContainerDetails;
+    use crate::docker::models::ContainerId;
+    use crate::ipc::MessageBus;
+    use crate::jobs::JobRuns;
+    use crate::service_registry::ServiceRegistry;
+
+    const TOKEN: &str = "secret-token";
+
+    fn create_server(registry: ServiceRegistry) -> (AdminServer, Arc<MessageBus>) {
+        create_server_with_job_runs(registry, JobRuns::default())
+    }
+
+    fn create_server_with_job_runs(
+        registry: ServiceRegistry,
+        job_runs: JobRuns,
+    ) -> (AdminServer, Arc<MessageBus>) {
+        let config = Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::new(),
+                tls: None,
+                mtls: None,
+                acme: None,
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: Some(AdminConfig {
+                    addr: Ipv4Addr::LOCALHOST,
+                    port: 5001,
+                    token: String::from(TOKEN),
+                }),
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services: HashMap::new(),
+        };
+
+        let message_bus = MessageBus::new();
+        let server = AdminServer::new(
+            Arc::new(RwLock::new(registry)),
+            Arc::new(RwLock::new(job_runs)),
+            Arc::new(ArcSwap::from_pointee(config)),
+            Arc::clone(&message_bus),
+        );
+
+        (server, message_bus)
+    }
+
+    fn create_registry() -> (ServiceRegistry, ContainerId) {
+        let mut registry = ServiceRegistry::new();
+        let id = ContainerId::random();
+
+        let service = Service {
+            image: String::from("myapp"),
+            tag: String::from("v1"),
+            routes: HashSet::from([Route {
+                host: String::from("example.com"),
+                port: 8080,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        registry.define("backend", service);
+        registry.add_container(
+            "backend",
+            StartedContainerDetails {
+                id: id.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        (registry, id)
+    }
+
+    fn request(method: Method, path: &str, token: Option<&str>) -> Result<Request<()>> {
+        let mut builder = Request::builder().method(method).uri(path);
+
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        Ok(builder.body(())?)
+    }
+
+    async fn send(
+        server: &AdminServer,
+        req: Request<()>,
+    ) -> Result<(StatusCode, serde_json::Value)> {
+        let response = server.handle(req).await?;
+        let status = response.status();
+        let bytes = response.into_body().collect().await?.to_bytes();
+
+        Ok((status, serde_json::from_slice(&bytes)?))
+    }
+
+    #[tokio::test]
+    async fn requests_without_the_token_are_rejected() -> Result<()> {
+        let (server, _) = create_server(ServiceRegistry::new());
+
+        let (status, _) = send(&server, request(Method::GET, "/services", None)?).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = send(&server, request(Method::GET, "/services", Some("wrong"))?).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = send(&server, request(Method::GET, "/services", Some(TOKEN))?).await?;
+        assert_eq!(status, StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn services_are_listed_with_their_containers() -> Result<()> {
+        let (mut registry, id) = create_registry();
+        registry.mark_unhealthy(&id);
+
+        let (server, _) = create_server(registry);
+        let (status, body) = send(&server, request(Method::GET, "/services", Some(TOKEN))?).await?;
+
+        assert_eq!(status, StatusCode::OK);
+
+        let service = &body["services"][0];
+
+        assert_eq!(service["name"], "backend");
+        assert_eq!(service["tag"], "v1");
+        assert_eq!(service["routes"][0]["host"], "example.com");
+        assert_eq!(service["containers"][0]["id"], id.0.as_str());
+        assert_eq!(service["containers"][0]["healthy"], false);
+        assert_eq!(service["containers"][0]["draining"], false);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn containers_can_be_drained() -> Result<()> {
+        let (registry, id) = create_registry();
+        let (server, _) = create_server(registry);
+
+        let path = format!("/containers/{id}/drain");
+        let (status, _) = send(&server, request(Method::POST, &path, Some(TOKEN))?).await?;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(server.registry.read().await.is_draining(&id));
+
+        let (status, _) = send(
+            &server,
+            request(Method::POST, "/containers/unknown/drain", Some(TOKEN))?,
+        )
+        .await?;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Draining can be undone to send traffic to the container again
+        let path = format!("/containers/{id}/undrain");
+        let (status, body) = send(&server, request(Method::POST, &path, Some(TOKEN))?).await?;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["draining"], false);
+        assert!(!server.registry.read().await.is_draining(&id));
+
+        let (status, _) = send(
+            &server,
+            request(Method::POST, "/containers/unknown/undrain", Some(TOKEN))?,
+        )
+        .await?;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconciliations_and_restarts_are_sent_to_the_reconciler() -> Result<()> {
+        let (registry, id) = create_registry();
+        let (server, message_bus) = create_server(registry);
+
+        let (status, body) = send(
+            &server,
+            request(Method::POST, "/reconciliation", Some(TOKEN))?,
+        )
+        .await?;
+
+        message_bus.receive_reconciliation_request().await?;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(body["request"].is_string());
+
+        let path = format!("/containers/{id}/restart");
+        let (status, _) = send(&server, request(Method::POST, &path, Some(TOKEN))?).await?;
+
+        let received = message_bus.receive_container_restart_request().await?;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(received.into_content().id, id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn running_definitions_can_be_fetched() -> Result<()> {
+        let (registry, _) = create_registry();
+        let (server, _) = create_server(registry);
+
+        let service = Service {
+            image: String::from("myapp"),
+            tag: String::from("v2"),
+            environment: HashMap::from([(
+                String::from("DATABASE_PASSWORD"),
+                String::from("hunter2"),
+            )]),
+            ..Default::default()
+        };
+
+        let mut config = Config::clone(&server.config.load());
+        config
+            .services
+            .insert(String::from("backend"), service.clone());
+        server.config.store(Arc::new(config));
+
+        let (status, body) = send(&server, request(Method::GET, "/config", Some(TOKEN))?).await?;
+
+        // Environment values are only shared as digests, which still change along with them
+        assert!(!body.to_string().contains("hunter2"));
+
+        let definitions: ServiceDefinitions = serde_json::from_value(body)?;
+        let backend = definitions.services.get("backend");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(backend.map(|backend| &backend.tag), Some(&service.tag));
+        assert_eq!(
+            definitions.services,
+            ServiceDefinitions::redacted(&HashMap::from([(String::from("backend"), service)]))
+                .services
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jobs_are_listed_with_their_last_run() -> Result<()> {
+        let mut job_runs = JobRuns::default();
+        let id = ContainerId::random();
+
+        job_runs.record_started("migrate", id.clone());
+        job_runs.record_finished("migrate", &id, 1, String::from("connection refused"));
+
+        let (server, _) = create_server_with_job_runs(ServiceRegistry::new(), job_runs);
+
+        let job = Job {
+            image: String::from("myapp"),
+            tag: String::from("v1"),
+            schedule: JobSchedule::OnDeploy,
+            environment: HashMap::new(),
+            volumes: HashMap::new(),
+            args: Vec::new(),
+            container: Default::default(),
+        };
+
+        let mut config = Config::clone(&server.config.load());
+        config.jobs.insert(String::from("migrate"), job);
+        server.config.store(Arc::new(config));
+
+        let (status, body) = send(&server, request(Method::GET, "/jobs", Some(TOKEN))?).await?;
+        let job = &body["jobs"][0];
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(job["name"], "migrate");
+        assert_eq!(job["schedule"]["trigger"], "on_deploy");
+        assert_eq!(job["last_run"]["container"], id.0);
+        assert_eq!(job["last_run"]["exit_code"], 1);
+        assert_eq!(job["last_run"]["logs"], "connection refused");
+
+        Ok(())
+    }
+}
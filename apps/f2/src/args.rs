@@ -7,6 +7,29 @@ use crate::config::ExternalBytes;
 
 pub struct Args {
     pub config_location: ExternalBytes,
+    pub command: Command,
+}
+
+/// What to do with the configuration once it has been located.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Runs the load balancer and services.
+    Run,
+    /// Validates the configuration and previews the changes it would make, without running
+    /// anything.
+    Check { compare_with: Option<Comparison> },
+    /// Encrypts a value read from stdin into a secret reference for the configuration, using the
+    /// given public key or the one belonging to the configured private key.
+    Encrypt { public_key: Option<ExternalBytes> },
+}
+
+/// What to compare a configuration against when checking it.
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    /// Another configuration, such as the one currently deployed.
+    Config(ExternalBytes),
+    /// The admin API of a running instance, such as `http://localhost:9000`.
+    Instance { url: String },
 }
 
 impl Args {
@@ -21,28 +44,67 @@ impl TryFrom<pico_args::Arguments> for Args {
     type Error = Report;
 
     fn try_from(mut args: pico_args::Arguments) -> Result<Self> {
-        let config: String = args.value_from_str("--config")?;
-
-        let config_location = match config.strip_prefix("s3://") {
-            Some(bucket_and_key) => {
-                let (bucket, key) = bucket_and_key
-                    .split_once('/')
-                    .ok_or_else(|| eyre!("invalid s3 bucket and key provided: {bucket_and_key}"))?;
-
-                ExternalBytes::S3 {
-                    bucket: bucket.to_owned(),
-                    key: key.to_owned(),
-                }
+        let subcommand = args.subcommand()?;
+        let config_location = parse_location(args.value_from_str("--config")?)?;
+
+        let command = match subcommand.as_deref() {
+            None => Command::Run,
+            Some("check") => {
+                let compare_with = match (
+                    args.opt_value_from_str("--against")?,
+                    args.opt_value_from_str("--instance")?,
+                ) {
+                    (Some(_), Some(_)) => {
+                        return Err(eyre!(
+                            "only one of --against and --instance can be provided"
+                        ))
+                    }
+                    (Some(location), None) => Some(Comparison::Config(parse_location(location)?)),
+                    (None, Some(url)) => Some(Comparison::Instance { url }),
+                    (None, None) => None,
+                };
+
+                Command::Check { compare_with }
+            }
+            Some("encrypt") => {
+                let public_key = args
+                    .opt_value_from_str("--public-key")?
+                    .map(parse_location)
+                    .transpose()?;
+
+                Command::Encrypt { public_key }
             }
-            None => ExternalBytes::Filesystem {
-                path: PathBuf::from(config),
-            },
+            Some(other) => return Err(eyre!("unknown subcommand provided: {other}")),
         };
 
-        Ok(Self { config_location })
+        Ok(Self {
+            config_location,
+            command,
+        })
     }
 }
 
+/// Parses the location of a configuration file, which is either a path or an `s3://` URI.
+fn parse_location(location: String) -> Result<ExternalBytes> {
+    let location = match location.strip_prefix("s3://") {
+        Some(bucket_and_key) => {
+            let (bucket, key) = bucket_and_key
+                .split_once('/')
+                .ok_or_else(|| eyre!("invalid s3 bucket and key provided: {bucket_and_key}"))?;
+
+            ExternalBytes::S3 {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+            }
+        }
+        None => ExternalBytes::Filesystem {
+            path: PathBuf::from(location),
+        },
+    };
+
+    Ok(location)
+}
+
 #[cfg(test)]
 mod tests {
     use std::ffi::OsString;
@@ -50,7 +112,7 @@ mod tests {
 
     use color_eyre::Result;
 
-    use crate::args::Args;
+    use crate::args::{Args, Command, Comparison};
     use crate::config::ExternalBytes;
 
     #[test]
@@ -125,4 +187,97 @@ mod tests {
             "invalid s3 bucket and key provided: some-bucket"
         );
     }
+
+    #[test]
+    fn running_is_the_default_command() -> Result<()> {
+        let raw_args = vec![OsString::from("--config"), OsString::from("f2.yaml")];
+
+        let parsed = Args::try_from(pico_args::Arguments::from_vec(raw_args))?;
+
+        assert_eq!(parsed.command, Command::Run);
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_check_command() -> Result<()> {
+        let parse = |raw_args: &[&str]| {
+            let raw_args = raw_args.iter().map(OsString::from).collect();
+            Args::try_from(pico_args::Arguments::from_vec(raw_args))
+        };
+
+        let standalone = parse(&["check", "--config", "f2.yaml"])?;
+
+        assert_eq!(standalone.command, Command::Check { compare_with: None });
+
+        let against_file = parse(&[
+            "check",
+            "--config",
+            "f2.yaml",
+            "--against",
+            "s3://bucket/f2.yaml",
+        ])?;
+
+        assert_eq!(
+            against_file.command,
+            Command::Check {
+                compare_with: Some(Comparison::Config(ExternalBytes::S3 {
+                    bucket: String::from("bucket"),
+                    key: String::from("f2.yaml"),
+                }))
+            }
+        );
+
+        let against_instance = parse(&[
+            "check",
+            "--config",
+            "f2.yaml",
+            "--instance",
+            "http://localhost:9000",
+        ])?;
+
+        assert_eq!(
+            against_instance.command,
+            Command::Check {
+                compare_with: Some(Comparison::Instance {
+                    url: String::from("http://localhost:9000")
+                })
+            }
+        );
+
+        assert!(parse(&["unknown", "--config", "f2.yaml"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_encrypt_command() -> Result<()> {
+        let parse = |raw_args: &[&str]| {
+            let raw_args = raw_args.iter().map(OsString::from).collect();
+            Args::try_from(pico_args::Arguments::from_vec(raw_args))
+        };
+
+        let from_config = parse(&["encrypt", "--config", "f2.yaml"])?;
+
+        assert_eq!(from_config.command, Command::Encrypt { public_key: None });
+
+        let with_key = parse(&[
+            "encrypt",
+            "--config",
+            "f2.yaml",
+            "--public-key",
+            "public.pem",
+        ])?;
+
+        assert_eq!(
+            with_key.command,
+            Command::Encrypt {
+                public_key: Some(ExternalBytes::Filesystem {
+                    path: PathBuf::from("public.pem")
+                })
+            }
+        );
+
+        Ok(())
+    }
 }
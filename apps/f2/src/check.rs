@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::iter::once;
+
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use http::header::AUTHORIZATION;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use rsa::RsaPrivateKey;
+
+use crate::admin::ServiceDefinitions;
+use crate::args::Comparison;
+use crate::config::{Config, Diff, ExternalBytes, Scheme, Service};
+use crate::secrets::SecretSource;
+
+/// The environment variable holding the admin token, for comparing against a running instance.
+const ADMIN_TOKEN_VARIABLE: &str = "F2_ADMIN_TOKEN";
+
+/// Validates a configuration and prints the changes applying it would make, without touching
+/// Docker.
+pub async fn run(location: &ExternalBytes, compare_with: Option<&Comparison>) -> Result<()> {
+    let config = Config::from_location(location).await?;
+    let private_key = config
+        .get_private_key()
+        .await
+        .wrap_err("failed to load the private key for secrets")?;
+
+    let mut problems = validate(&config);
+    problems.extend(check_secrets(&config, private_key.as_ref()).await);
+
+    for problem in &problems {
+        println!("error: {problem}");
+    }
+
+    if problems.is_empty() {
+        println!("configuration is valid");
+    }
+
+    if let Some(comparison) = compare_with {
+        let (current, proposed) = match comparison {
+            Comparison::Config(location) => {
+                (Config::from_location(location).await?, config.clone())
+            }
+            // Running instances only share digests of environment values, so compare those
+            Comparison::Instance { url } => (
+                Config {
+                    services: fetch_running_services(url).await?,
+                    ..config.clone()
+                },
+                Config {
+                    services: ServiceDefinitions::redacted(&config.services).services,
+                    ..config.clone()
+                },
+            ),
+        };
+
+        let mut changes = current.diff(&proposed).unwrap_or_default();
+        changes.sort_by(|left, right| left.name().cmp(right.name()));
+
+        if changes.is_empty() {
+            println!("no changes to services");
+        }
+
+        for change in &changes {
+            println!("{}", summarise(change));
+        }
+    }
+
+    ensure!(
+        problems.is_empty(),
+        "found {} problem(s) in the configuration",
+        problems.len()
+    );
+
+    Ok(())
+}
+
+/// Finds problems with the routes and listeners that would stop the configuration working as
+/// intended.
+fn validate(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.alb.ports.is_empty() {
+        problems.push(String::from("no listener ports are configured"));
+    }
+
+    let uses_https = config.alb.tls.is_some()
+        || config.alb.acme.is_some()
+        || config.alb.https_redirect
+        || config
+            .services
+            .values()
+            .flat_map(|service| &service.routes)
+            .any(|route| route.https_redirect == Some(true));
+
+    if uses_https && !config.alb.ports.contains_key(&Scheme::Https) {
+        problems.push(String::from(
+            "HTTPS is configured but there is no port for the https listener",
+        ));
+    }
+
+    let mut names: Vec<_> = config.services.keys().collect();
+    names.sort();
+
+    let mut claimed = HashMap::new();
+
+    for name in names {
+        let service = &config.services[name];
+
+        let mut routes: Vec<_> = service.routes.iter().collect();
+        routes.sort_by_key(|route| (&route.host, &route.prefix, route.port));
+
+        for route in routes {
+            let prefix = route.prefix.as_deref().unwrap_or_default();
+
+            if route.port == 0 {
+                problems.push(format!(
+                    "route {}{prefix} in '{name}' does not have a container port",
+                    route.host
+                ));
+            }
+
+            for host in once(&route.host).chain(&route.aliases) {
+                let key = (host.to_ascii_lowercase(), prefix);
+
+                if let Some(existing) = claimed.insert(key, name) {
+                    problems.push(format!(
+                        "route {host}{prefix} is defined more than once, in '{existing}' and '{name}'"
+                    ));
+                }
+            }
+        }
+
+        if service
+            .health_check
+            .as_ref()
+            .is_some_and(|health_check| health_check.port == 0)
+        {
+            problems.push(format!("health check in '{name}' does not have a port"));
+        }
+    }
+
+    problems
+}
+
+/// Decrypts every encrypted `secret:` value in the service and job environments, reporting those
+/// that cannot be. Secrets fetched from elsewhere are only checked for being well formed, as they
+/// may only exist on the host f2 runs on.
+async fn check_secrets(config: &Config, private_key: Option<&RsaPrivateKey>) -> Vec<String> {
+    let services = config
+        .services
+        .iter()
+        .map(|(name, service)| (name, &service.environment));
+    let jobs = config
+        .jobs
+        .iter()
+        .map(|(name, job)| (name, &job.environment));
+
+    let mut secrets: Vec<_> = services
+        .chain(jobs)
+        .flat_map(|(name, environment)| {
+            environment.iter().filter_map(move |(key, value)| {
+                let source = SecretSource::parse(value)?;
+
+                Some((name, key, source))
+            })
+        })
+        .collect();
+
+    secrets.sort_by(|left, right| (left.0, left.1).cmp(&(right.0, right.1)));
+
+    let mut problems = Vec::new();
+
+    for (name, key, source) in secrets {
+        let source = match source {
+            Ok(source) if source.is_encrypted() => source,
+            Ok(_) => continue,
+            Err(e) => {
+                problems.push(format!("'{key}' in '{name}' is not a valid secret: {e}"));
+                continue;
+            }
+        };
+
+        if private_key.is_none() {
+            problems.push(format!(
+                "'{key}' in '{name}' is a secret but no private key is configured"
+            ));
+            continue;
+        }
+
+        if let Err(e) = source.resolve(private_key).await {
+            problems.push(format!("failed to decrypt '{key}' in '{name}': {e}"));
+        }
+    }
+
+    problems
+}
+
+/// Fetches the service definitions a running instance is using through its admin API.
+async fn fetch_running_services(url: &str) -> Result<HashMap<String, Service>> {
+    let token = std::env::var(ADMIN_TOKEN_VARIABLE).wrap_err_with(|| {
+        format!("{ADMIN_TOKEN_VARIABLE} must be set to compare against a running instance")
+    })?;
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let req = Request::get(format!("{}/config", url.trim_end_matches('/')))
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .body(Empty::<Bytes>::new())?;
+
+    let response = client
+        .request(req)
+        .await
+        .wrap_err_with(|| format!("failed to reach the admin API at {url}"))?;
+
+    ensure!(
+        response.status().is_success(),
+        "admin API at {url} responded with {}",
+        response.status()
+    );
+
+    let bytes = response.into_body().collect().await?.to_bytes();
+    let definitions: ServiceDefinitions = serde_json::from_slice(&bytes)?;
+
+    Ok(definitions.services)
+}
+
+/// Describes a change to a service on a single line.
+fn summarise(diff: &Diff) -> String {
+    match diff {
+        Diff::Addition { name, definition } => {
+            format!("+ {name} ({}:{})", definition.image, definition.tag)
+        }
+        Diff::Removal { name } => format!("- {name}"),
+        Diff::Alteration {
+            name,
+            old_definition,
+            new_definition,
+        } => format!(
+            "~ {name} ({}:{} -> {}:{}): {}",
+            old_definition.image,
+            old_definition.tag,
+            new_definition.image,
+            new_definition.tag,
+            changed_fields(old_definition, new_definition).join(", ")
+        ),
+    }
+}
+
+fn changed_fields(old: &Service, new: &Service) -> Vec<&'static str> {
+    let fields = [
+        ("image", old.image != new.image),
+        ("tag", old.tag != new.tag),
+        ("replicas", old.replicas != new.replicas),
+        ("routes", old.routes != new.routes),
+        ("environment", old.environment != new.environment),
+        ("volumes", old.volumes != new.volumes),
+        ("shutdown_mode", old.shutdown_mode != new.shutdown_mode),
+        ("args", old.args != new.args),
+        ("health_check", old.health_check != new.health_check),
+        ("load_balancing", old.load_balancing != new.load_balancing),
+        ("deployment", old.deployment != new.deployment),
+        ("canary", old.canary != new.canary),
+        (
+            "max_connections",
+            old.max_connections != new.max_connections,
+        ),
+        ("container", old.container != new.container),
+    ];
+
+    fields
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::net::Ipv4Addr;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use color_eyre::eyre::Result;
+    use rand::rngs::ThreadRng;
+    use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+
+    use crate::check::{check_secrets, summarise, validate};
+    use crate::config::{AlbConfig, Config, Diff, Route, Scheme, Service};
+
+    fn create_config(services: HashMap<String, Service>) -> Config {
+        Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::new(),
+                tls: None,
+                mtls: None,
+                acme: None,
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services,
+        }
+    }
+
+    fn service_with_route(host: &str, prefix: Option<&str>, port: u16) -> Service {
+        Service {
+            image: String::from("myapp"),
+            tag: String::from("v1"),
+            routes: HashSet::from([Route {
+                host: String::from(host),
+                prefix: prefix.map(String::from),
+                port,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn valid_configurations_have_no_problems() {
+        let config = create_config(HashMap::from([
+            (
+                String::from("frontend"),
+                service_with_route("example.com", None, 3000),
+            ),
+            (
+                String::from("backend"),
+                service_with_route("example.com", Some("/api"), 8080),
+            ),
+        ]));
+
+        assert!(validate(&config).is_empty());
+    }
+
+    #[test]
+    fn duplicate_routes_and_missing_ports_are_reported() {
+        let mut config = create_config(HashMap::from([
+            (
+                String::from("frontend"),
+                service_with_route("example.com", None, 3000),
+            ),
+            (
+                String::from("backend"),
+                service_with_route("Example.com", None, 0),
+            ),
+        ]));
+
+        config.alb.https_redirect = true;
+
+        assert_eq!(
+            validate(&config),
+            vec![
+                String::from("HTTPS is configured but there is no port for the https listener"),
+                String::from("route Example.com in 'backend' does not have a container port"),
+                String::from(
+                    "route example.com is defined more than once, in 'backend' and 'frontend'"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn secrets_are_test_decrypted() -> Result<()> {
+        let mut rng: ThreadRng = rand::rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 1024)?;
+        let public_key = RsaPublicKey::from(&private_key);
+
+        let encrypted = public_key.encrypt(&mut rng, Pkcs1v15Encrypt, b"password")?;
+
+        let service = Service {
+            environment: HashMap::from([
+                (String::from("PLAIN"), String::from("value")),
+                (
+                    String::from("VALID"),
+                    format!("secret:{}", STANDARD.encode(encrypted)),
+                ),
+                (
+                    String::from("INVALID"),
+                    String::from("secret:bm90IGVuY3J5cHRlZA=="),
+                ),
+                (
+                    String::from("FROM_FILE"),
+                    String::from("secret:file:/run/secrets/password"),
+                ),
+                (
+                    String::from("MALFORMED"),
+                    String::from("secret:vault:password"),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let config = create_config(HashMap::from([(String::from("backend"), service)]));
+
+        let problems = check_secrets(&config, Some(&private_key)).await;
+
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("failed to decrypt 'INVALID' in 'backend'"));
+        assert_eq!(
+            problems[1],
+            "'MALFORMED' in 'backend' is not a valid secret: unknown secret source: vault"
+        );
+
+        assert_eq!(
+            check_secrets(&config, None).await,
+            vec![
+                String::from("'INVALID' in 'backend' is a secret but no private key is configured"),
+                String::from(
+                    "'MALFORMED' in 'backend' is not a valid secret: unknown secret source: vault"
+                ),
+                String::from("'VALID' in 'backend' is a secret but no private key is configured"),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn changes_are_summarised() {
+        let old_definition = service_with_route("example.com", None, 3000);
+        let new_definition = Service {
+            tag: String::from("v2"),
+            ..service_with_route("example.com", None, 4000)
+        };
+
+        let alteration = Diff::Alteration {
+            name: String::from("backend"),
+            old_definition,
+            new_definition: new_definition.clone(),
+        };
+
+        let addition = Diff::Addition {
+            name: String::from("backend"),
+            definition: new_definition,
+        };
+
+        assert_eq!(
+            summarise(&alteration),
+            "~ backend (myapp:v1 -> myapp:v2): tag, routes"
+        );
+        assert_eq!(summarise(&addition), "+ backend (myapp:v2)");
+        assert_eq!(
+            summarise(&Diff::Removal {
+                name: String::from("backend")
+            }),
+            "- backend"
+        );
+    }
+}
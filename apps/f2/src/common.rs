@@ -1,10 +1,11 @@
 use std::{collections::HashMap, fmt};
 
-use color_eyre::eyre::{eyre, Result, WrapErr};
+use color_eyre::eyre::{Result, WrapErr};
 use rsa::RsaPrivateKey;
+use sha2::{Digest, Sha256};
 
-use crate::config::{Service, VolumeDefinition};
-use crate::crypto::decrypt;
+use crate::config::{ContainerOptions, ExternalBytes, Job, Service, VolumeDefinition};
+use crate::secrets::SecretSource;
 
 #[derive(Clone)]
 pub struct EncryptedEnvironment {
@@ -12,20 +13,19 @@ pub struct EncryptedEnvironment {
 }
 
 impl EncryptedEnvironment {
-    pub fn decrypt(&self, private_key: Option<&RsaPrivateKey>) -> Result<Environment> {
+    /// Resolves every `secret:` value, fetching or decrypting it from its source.
+    pub async fn decrypt(&self, private_key: Option<&RsaPrivateKey>) -> Result<Environment> {
         let mut variables = HashMap::new();
 
         for (key, value) in self.variables.clone().into_iter() {
             tracing::info!(%key, "resolving secret");
 
-            let value = match value.strip_prefix("secret:") {
-                Some(value) => {
-                    let private_key = private_key
-                        .ok_or_else(|| eyre!("Tried to decrypt secret without a key"))?;
-
-                    decrypt(value, private_key)
-                        .wrap_err_with(|| format!("Failed to decrypt secret value for '{key}'"))?
-                }
+            let value = match SecretSource::parse(&value) {
+                Some(source) => source
+                    .wrap_err_with(|| format!("Invalid secret reference for '{key}'"))?
+                    .resolve(private_key)
+                    .await
+                    .wrap_err_with(|| format!("Failed to decrypt secret value for '{key}'"))?,
                 None => value,
             };
 
@@ -47,6 +47,72 @@ pub struct Container {
     pub environment: EncryptedEnvironment,
     pub volumes: HashMap<String, VolumeDefinition>,
     pub args: Vec<String>,
+    pub options: ContainerOptions,
+}
+
+impl Container {
+    /// Computes a stable hash of everything used to create the container, so that containers left
+    /// running by a previous instance of f2 can be matched against the current configuration.
+    pub fn config_hash(&self, tag: &str) -> String {
+        let mut hasher = Sha256::new();
+
+        // Prefix each value with its length so that adjacent values cannot be confused
+        let mut update = |value: &[u8]| {
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        };
+
+        update(self.image.as_bytes());
+        update(tag.as_bytes());
+
+        let mut variables: Vec<_> = self.environment.variables.iter().collect();
+        variables.sort();
+
+        for (key, value) in variables {
+            update(key.as_bytes());
+            update(value.as_bytes());
+        }
+
+        let mut volumes: Vec<_> = self.volumes.iter().collect();
+        volumes.sort_by_key(|(name, _)| *name);
+
+        for (name, definition) in volumes {
+            update(name.as_bytes());
+            update(definition.target.as_bytes());
+
+            // Only hashed when set, so containers from before templates existed still match
+            if definition.template {
+                update(b"template");
+            }
+
+            match &definition.source {
+                ExternalBytes::Filesystem { path } => {
+                    update(b"filesystem");
+                    update(path.as_os_str().as_encoded_bytes());
+                }
+                ExternalBytes::S3 { bucket, key } => {
+                    update(b"s3");
+                    update(bucket.as_bytes());
+                    update(key.as_bytes());
+                }
+            }
+        }
+
+        for arg in &self.args {
+            update(arg.as_bytes());
+        }
+
+        // Only hashed when set, so containers from before they existed still match
+        if self.options != ContainerOptions::default() {
+            let options = serde_json::to_value(&self.options)
+                .expect("container options can always be serialized");
+
+            update(b"options");
+            update(options.to_string().as_bytes());
+        }
+
+        hex::encode(hasher.finalize())
+    }
 }
 
 impl fmt::Debug for Container {
@@ -55,6 +121,7 @@ impl fmt::Debug for Container {
             .field("image", &self.image)
             .field("volumes", &self.volumes)
             .field("args", &self.args)
+            .field("options", &self.options)
             .finish()
     }
 }
@@ -68,6 +135,21 @@ impl From<&Service> for Container {
             },
             volumes: service.volumes.clone(),
             args: service.args.clone(),
+            options: service.container.clone(),
+        }
+    }
+}
+
+impl From<&Job> for Container {
+    fn from(job: &Job) -> Self {
+        Self {
+            image: job.image.clone(),
+            environment: EncryptedEnvironment {
+                variables: job.environment.clone(),
+            },
+            volumes: job.volumes.clone(),
+            args: job.args.clone(),
+            options: job.container.clone(),
         }
     }
 }
@@ -80,7 +162,8 @@ mod tests {
     use rand::rngs::ThreadRng;
     use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
 
-    use super::EncryptedEnvironment;
+    use super::{Container, EncryptedEnvironment};
+    use crate::config::{ContainerOptions, Service};
 
     fn generate_keys() -> Result<(RsaPublicKey, RsaPrivateKey)> {
         let mut rng: ThreadRng = rand::rng();
@@ -102,8 +185,8 @@ mod tests {
         Ok(general_purpose::STANDARD.encode(encrypted))
     }
 
-    #[test]
-    fn environments_can_be_decrypted() -> Result<()> {
+    #[tokio::test]
+    async fn environments_can_be_decrypted() -> Result<()> {
         let (public, private) = generate_keys()?;
 
         let plaintext = "foobar";
@@ -114,7 +197,7 @@ mod tests {
 
         let encrypted_environment = EncryptedEnvironment { variables };
 
-        let decrypted = encrypted_environment.decrypt(Some(&private))?;
+        let decrypted = encrypted_environment.decrypt(Some(&private)).await?;
         let value = decrypted
             .variables
             .get("key")
@@ -125,14 +208,14 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn unencrypted_keys_are_left_alone() -> Result<()> {
+    #[tokio::test]
+    async fn unencrypted_keys_are_left_alone() -> Result<()> {
         let mut variables = HashMap::new();
         variables.insert(String::from("key"), String::from("value"));
 
         let encrypted_environment = EncryptedEnvironment { variables };
 
-        let decrypted = encrypted_environment.decrypt(None)?;
+        let decrypted = encrypted_environment.decrypt(None).await?;
         let value = decrypted
             .variables
             .get("key")
@@ -143,8 +226,8 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn decryption_errors_if_secrets_exist_without_private_key() -> Result<()> {
+    #[tokio::test]
+    async fn decryption_errors_if_secrets_exist_without_private_key() -> Result<()> {
         let (public, _) = generate_keys()?;
 
         let plaintext = "foobar";
@@ -155,15 +238,15 @@ mod tests {
 
         let encrypted_environment = EncryptedEnvironment { variables };
 
-        let decrypted = encrypted_environment.decrypt(None);
+        let decrypted = encrypted_environment.decrypt(None).await;
 
         assert!(decrypted.is_err());
 
         Ok(())
     }
 
-    #[test]
-    fn decryption_failures_return_an_error() -> Result<()> {
+    #[tokio::test]
+    async fn decryption_failures_return_an_error() -> Result<()> {
         let (public, _) = generate_keys()?;
         let (_, unrelated_private) = generate_keys()?;
 
@@ -175,10 +258,37 @@ mod tests {
 
         let encrypted_environment = EncryptedEnvironment { variables };
 
-        let decrypted = encrypted_environment.decrypt(Some(&unrelated_private));
+        let decrypted = encrypted_environment
+            .decrypt(Some(&unrelated_private))
+            .await;
 
         assert!(decrypted.is_err());
 
         Ok(())
     }
+
+    #[test]
+    fn container_options_only_change_the_hash_when_set() {
+        let service = Service {
+            image: String::from("myapp"),
+            ..Default::default()
+        };
+
+        let limited = Service {
+            container: ContainerOptions {
+                memory_mb: Some(256),
+                ..Default::default()
+            },
+            ..service.clone()
+        };
+
+        let hash = Container::from(&service).config_hash("v1");
+
+        // The same hash as before the options existed, so running containers are still adopted
+        assert_eq!(
+            hash,
+            "e9a75b9ded334f1b684c32f4a4c0f5b6e91564a7d3b80f49a42cf1be5b35b7dc"
+        );
+        assert_ne!(Container::from(&limited).config_hash("v1"), hash);
+    }
 }
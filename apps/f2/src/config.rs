@@ -1,17 +1,26 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::net::Ipv4Addr;
 use std::num::NonZeroU8;
 use std::ops::Deref;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use aws_config::BehaviorVersion;
 use color_eyre::eyre::{eyre, Context, Result};
+use croner::Cron;
+use foundation_metrics::MetricsConfig;
+use regex::Regex;
 use rsa::RsaPrivateKey;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::crypto::parse_private_key;
 
+/// The header used to pass the common name of mutual TLS clients downstream by default.
+const DEFAULT_COMMON_NAME_HEADER: &str = "x-client-common-name";
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[allow(clippy::large_enum_variant)]
 pub enum Diff {
@@ -29,11 +38,38 @@ pub enum Diff {
     },
 }
 
+impl Diff {
+    /// Gets the name of the service that changed.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Alteration { name, .. }
+            | Self::Addition { name, .. }
+            | Self::Removal { name } => name,
+        }
+    }
+
+    /// Gets the definition the service has once the change is applied, if it still exists.
+    pub fn new_definition(&self) -> Option<&Service> {
+        match self {
+            Self::Alteration { new_definition, .. } => Some(new_definition),
+            Self::Addition { definition, .. } => Some(definition),
+            Self::Removal { .. } => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub alb: AlbConfig,
     pub secrets: Option<SecretConfig>,
+    /// Where to export metrics to over OTLP, if anywhere.
+    pub metrics: Option<MetricsConfig>,
+    /// How often to check the configuration for changes, rather than waiting to be told to.
+    pub polling: Option<PollingConfig>,
     pub services: HashMap<String, Service>,
+    /// Containers that run to completion, rather than serving traffic.
+    #[serde(default)]
+    pub jobs: HashMap<String, Job>,
 }
 
 impl Config {
@@ -111,9 +147,84 @@ pub enum Scheme {
 pub struct AlbConfig {
     pub addr: Ipv4Addr,
     pub ports: HashMap<Scheme, u16>,
+    /// The path that a `PUT` request triggers a reconciliation on, which is ignored along with
+    /// `PUT /certificates` once the admin API is configured.
     pub reconciliation: String,
     pub tls: Option<TlsConfig>,
     pub mtls: Option<MtlsConfig>,
+    pub acme: Option<AcmeConfig>,
+    /// The rate limit to apply to routes that do not define their own.
+    pub rate_limit: Option<RateLimit>,
+    pub access_log: Option<AccessLogConfig>,
+    /// Whether to redirect plaintext requests to HTTPS for routes that do not say otherwise.
+    #[serde(default)]
+    pub https_redirect: bool,
+    /// The `Strict-Transport-Security` policy for routes that do not define their own.
+    pub hsts: Option<HstsPolicy>,
+    pub admin: Option<AdminConfig>,
+}
+
+impl AlbConfig {
+    /// Gets the header that carries the common name of mutual TLS clients downstream, which is
+    /// stripped from incoming requests even when mutual TLS is not configured.
+    pub fn common_name_header(&self) -> &str {
+        self.mtls
+            .as_ref()
+            .map_or(DEFAULT_COMMON_NAME_HEADER, |mtls| &mtls.common_name_header)
+    }
+}
+
+/// Settings for the admin API, which is served on its own listener.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct AdminConfig {
+    /// The address to listen on, which defaults to only accepting local connections.
+    #[serde(default = "AdminConfig::default_addr")]
+    pub addr: Ipv4Addr,
+    pub port: u16,
+    /// The bearer token that requests must present in their `Authorization` header.
+    pub token: String,
+}
+
+impl AdminConfig {
+    fn default_addr() -> Ipv4Addr {
+        Ipv4Addr::LOCALHOST
+    }
+}
+
+/// Settings for recording every proxied request and TLS session.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// The file to append entries to, which defaults to standard output.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// One JSON object per line.
+    #[default]
+    Json,
+    /// A format similar to the combined log format used by Apache and nginx.
+    Combined,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct PollingConfig {
+    #[serde(default = "PollingConfig::default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl PollingConfig {
+    fn default_interval_secs() -> u64 {
+        30
+    }
+
+    /// Gets the time between checks, which is at least a second.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
@@ -149,15 +260,54 @@ impl TlsSecrets {
     }
 }
 
+/// Settings for obtaining certificates for route hosts automatically using ACME HTTP-01 challenges.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct AcmeConfig {
+    /// The email address to register the ACME account with.
+    pub contact: String,
+    /// Which Let's Encrypt environment to request certificates from.
+    #[serde(default)]
+    pub environment: AcmeEnvironment,
+    /// The directory to store issued certificates in, so they survive restarts.
+    pub storage: PathBuf,
+    /// How many days before expiry to renew a certificate.
+    #[serde(default = "AcmeConfig::default_renew_before_days")]
+    pub renew_before_days: u32,
+}
+
+impl AcmeConfig {
+    fn default_renew_before_days() -> u32 {
+        30
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcmeEnvironment {
+    Staging,
+    #[default]
+    Production,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
 pub struct MtlsConfig {
     /// The certificate to use as the trust anchor when validating incoming requests.
     pub anchor: ExternalBytes,
-    /// The domains to apply mTLS to.
+    /// The domains to apply mTLS to, which can be wildcards such as `*.example.com`. Every host of
+    /// a route that matches one of them, including its aliases, requires mTLS as well.
     pub domains: HashSet<String>,
+    /// The header to pass the common name of verified client certificates downstream in.
+    #[serde(default = "MtlsConfig::default_common_name_header")]
+    pub common_name_header: String,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+impl MtlsConfig {
+    fn default_common_name_header() -> String {
+        String::from(DEFAULT_COMMON_NAME_HEADER)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "location", rename_all = "lowercase")]
 pub enum ExternalBytes {
     Filesystem { path: PathBuf },
@@ -184,9 +334,42 @@ impl ExternalBytes {
 
         Ok(bytes)
     }
+
+    /// Gets a marker that changes whenever the content does, without fetching the content itself.
+    pub async fn revision(&self) -> Result<Revision> {
+        let revision = match self {
+            Self::Filesystem { path } => {
+                let metadata = tokio::fs::metadata(path)
+                    .await
+                    .wrap_err_with(|| eyre!("failed to read metadata for {}", path.display()))?;
+
+                Revision::Modified(metadata.modified()?)
+            }
+            Self::S3 { bucket, key } => {
+                let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+                let client = aws_sdk_s3::Client::new(&config);
+
+                let response = client.head_object().bucket(bucket).key(key).send().await?;
+                let etag = response
+                    .e_tag()
+                    .ok_or_else(|| eyre!("no ETag returned for s3://{bucket}/{key}"))?;
+
+                Revision::ETag(etag.to_owned())
+            }
+        };
+
+        Ok(revision)
+    }
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+/// Identifies a version of some external content, which is cheaper to fetch than the content.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Revision {
+    Modified(SystemTime),
+    ETag(String),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct ReplicaCount(NonZeroU8);
 
 impl TryFrom<u8> for ReplicaCount {
@@ -213,7 +396,7 @@ impl Deref for ReplicaCount {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ShutdownMode {
     Graceful,
@@ -221,14 +404,166 @@ pub enum ShutdownMode {
     Forceful,
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq, Hash, Deserialize)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub struct Route {
     pub host: String,
     pub prefix: Option<String>,
     pub port: u16,
+    pub rate_limit: Option<RateLimit>,
+    /// Other hosts for the route, which are redirected to `host`.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Whether to redirect plaintext requests to HTTPS, overriding the load balancer default.
+    pub https_redirect: Option<bool>,
+    pub hsts: Option<HstsPolicy>,
+    /// Whether to remove the prefix from the path before forwarding requests downstream.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// A rewrite to apply to the path before forwarding requests, after any prefix is stripped.
+    pub rewrite: Option<PathRewrite>,
+    /// Whether to keep responses in memory for as long as their `Cache-Control` header allows.
+    #[serde(default)]
+    pub cache: bool,
+}
+
+impl Route {
+    /// Gets the path to forward a request downstream with, after stripping the prefix and applying
+    /// any rewrite.
+    pub fn downstream_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let mut path = Cow::Borrowed(path);
+
+        if let Some(prefix) = self.prefix.as_deref().filter(|_| self.strip_prefix) {
+            if let Some(stripped) = path.strip_prefix(prefix) {
+                path = if stripped.starts_with('/') {
+                    Cow::Owned(stripped.to_owned())
+                } else {
+                    Cow::Owned(format!("/{stripped}"))
+                };
+            }
+        }
+
+        if let Some(rewrite) = &self.rewrite {
+            if let Cow::Owned(rewritten) = rewrite.pattern.replace(&path, &rewrite.replacement) {
+                path = Cow::Owned(rewritten);
+            }
+        }
+
+        path
+    }
+}
+
+/// Rewrites paths matching a regular expression, where the replacement can refer to capture groups
+/// such as `$1`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct PathRewrite {
+    pub pattern: RewritePattern,
+    pub replacement: String,
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+/// A regular expression that is compiled when the configuration is parsed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RewritePattern(Regex);
+
+impl Deref for RewritePattern {
+    type Target = Regex;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for RewritePattern {
+    type Error = regex::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Regex::new(&value).map(Self)
+    }
+}
+
+impl From<RewritePattern> for String {
+    fn from(value: RewritePattern) -> Self {
+        value.0.as_str().to_owned()
+    }
+}
+
+impl PartialEq for RewritePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for RewritePattern {}
+
+impl Hash for RewritePattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+/// The `Strict-Transport-Security` header to add to responses sent over HTTPS.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct HstsPolicy {
+    /// How long browsers should only use HTTPS for the host, in seconds.
+    #[serde(default = "HstsPolicy::default_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl HstsPolicy {
+    fn default_max_age() -> u64 {
+        31_536_000
+    }
+
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+
+        if self.preload {
+            value.push_str("; preload");
+        }
+
+        value
+    }
+}
+
+/// Limits how often each client can make requests to a route, using a token bucket per client.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct RateLimit {
+    /// The number of requests each client can make per second on average.
+    pub requests_per_second: u32,
+    /// The number of requests a client can make at once, which defaults to `requests_per_second`.
+    pub burst: Option<u32>,
+    /// How to identify clients, where requests without the key are identified by their address.
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+impl RateLimit {
+    pub fn capacity(&self) -> u32 {
+        self.burst.unwrap_or(self.requests_per_second)
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    ClientIp,
+    /// The common name of the client certificate, for routes using mutual TLS.
+    CommonName,
+    Header {
+        name: String,
+    },
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Service {
     pub image: String,
     pub tag: String,
@@ -243,6 +578,42 @@ pub struct Service {
     pub shutdown_mode: ShutdownMode,
     #[serde(default)]
     pub args: Vec<String>,
+    pub health_check: Option<HealthCheckDefinition>,
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+    #[serde(default)]
+    pub deployment: DeploymentStrategy,
+    pub canary: Option<CanaryDefinition>,
+    /// The maximum number of requests or connections each container handles at once.
+    pub max_connections: Option<usize>,
+    /// Limits and runtime settings for each container, passed to Docker when it is created.
+    #[serde(default)]
+    pub container: ContainerOptions,
+}
+
+impl Service {
+    /// Gets the definition for the canary containers of this service, if it has a canary.
+    pub fn canary_definition(&self) -> Option<Service> {
+        let canary = self.canary.as_ref()?;
+
+        Some(Service {
+            tag: canary.tag.clone(),
+            replicas: canary.replicas,
+            canary: None,
+            ..self.clone()
+        })
+    }
+
+    /// Checks whether two definitions only differ in their canary, meaning the existing stable
+    /// containers can be left running.
+    pub fn differs_only_in_canary(&self, other: &Service) -> bool {
+        let strip = |service: &Service| Service {
+            canary: None,
+            ..service.clone()
+        };
+
+        strip(self) == strip(other)
+    }
 }
 
 impl Hash for Service {
@@ -252,12 +623,258 @@ impl Hash for Service {
     }
 }
 
+/// Settings for how Docker runs the containers of a service.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ContainerOptions {
+    /// The CPU time available to each container, in thousandths of a CPU.
+    pub millicpus: Option<u64>,
+    /// The memory available to each container, in megabytes.
+    pub memory_mb: Option<u64>,
+    /// Resource limits for the processes in each container, keyed by name such as `nofile`.
+    #[serde(default)]
+    pub ulimits: HashMap<String, Ulimit>,
+    /// The user and optionally group to run as, such as `1000:1000`.
+    pub user: Option<String>,
+    pub working_dir: Option<String>,
+    /// Whether to mount the root filesystem as read only.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Labels to add to each container, alongside the ones f2 uses to track them.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Kernel capabilities to add, such as `NET_BIND_SERVICE`.
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+    /// Kernel capabilities to remove, where `ALL` removes every one that is not added.
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+    /// Whether Docker restarts containers that exit, before f2 notices and replaces them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    /// A command Docker runs inside each container to check its health, which is reported by
+    /// Docker alongside the health checks f2 makes itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<ContainerHealthCheck>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "name", rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    No,
+    Always,
+    UnlessStopped,
+    OnFailure {
+        /// The number of times to restart a container, where 0 means there is no limit.
+        #[serde(default)]
+        max_retries: u32,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ContainerHealthCheck {
+    /// The command to run, without a shell, where exiting with 0 means the container is healthy.
+    pub command: Vec<String>,
+    /// How long to wait between checks, in milliseconds.
+    pub interval_ms: Option<u64>,
+    /// How long a check can run before it counts as failed, in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// The number of failed checks in a row before the container is unhealthy.
+    pub retries: Option<u32>,
+    /// How long to give a container to start before failed checks count, in milliseconds.
+    pub start_period_ms: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Ulimit {
+    pub soft: i64,
+    pub hard: i64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct HealthCheckDefinition {
+    /// The path to send health check requests to.
+    pub path: String,
+    /// The port on the container to send health check requests to.
+    pub port: u16,
+    /// How long to wait between health check requests, in milliseconds.
+    #[serde(default = "HealthCheckDefinition::default_period_ms")]
+    pub period_ms: u64,
+    /// The number of successful responses required for a container to be considered healthy.
+    #[serde(default = "HealthCheckDefinition::default_success_threshold")]
+    pub success_threshold: u32,
+    /// The number of failed responses before a container is considered unhealthy.
+    #[serde(default = "HealthCheckDefinition::default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+impl HealthCheckDefinition {
+    fn default_period_ms() -> u64 {
+        1000
+    }
+
+    fn default_success_threshold() -> u32 {
+        1
+    }
+
+    fn default_failure_threshold() -> u32 {
+        10
+    }
+}
+
+/// A container that runs to completion, either on a schedule or whenever its definition changes.
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub struct Job {
+    pub image: String,
+    pub tag: String,
+    pub schedule: JobSchedule,
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+    #[serde(default)]
+    pub volumes: HashMap<String, VolumeDefinition>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub container: ContainerOptions,
+}
+
+/// When a job runs, which never overlaps with a previous run that is still going.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(tag = "trigger", rename_all = "snake_case")]
+pub enum JobSchedule {
+    /// Runs whenever the time in UTC matches a cron expression, such as `0 3 * * *`.
+    Cron { expression: CronSchedule },
+    /// Runs once for each version of the definition, such as for database migrations. Services are
+    /// only rolled out once the run has succeeded, and a failed run is retried when reconciling.
+    OnDeploy,
+}
+
+/// A cron expression that is parsed when the configuration is, boxed as the parsed form is large.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CronSchedule(Box<Cron>);
+
+impl Deref for CronSchedule {
+    type Target = Cron;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = croner::errors::CronError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Cron::from_str(&value).map(|cron| Self(Box::new(cron)))
+    }
+}
+
+impl PartialEq for CronSchedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for CronSchedule {}
+
+/// How to choose which replica of a service handles each request or connection.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    #[default]
+    RoundRobin,
+    LeastOutstandingRequests,
+    /// Sends requests with the same key to the same replica, for services that need sticky sessions.
+    ConsistentHash {
+        key: HashKey,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum HashKey {
+    ClientIp,
+    Header { name: String },
+}
+
+/// A second set of containers running a different tag, which receives a share of the traffic.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct CanaryDefinition {
+    /// The tag to run the canary containers with.
+    pub tag: String,
+    /// The number of canary containers to run.
+    #[serde(default)]
+    pub replicas: ReplicaCount,
+    /// The percentage of requests to send to the canary containers, from 0 to 100.
+    pub weight: CanaryWeight,
+}
+
+/// A percentage of traffic, which is checked to be at most 100 when parsing the configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct CanaryWeight(u8);
+
+impl TryFrom<u8> for CanaryWeight {
+    type Error = color_eyre::Report;
+
+    fn try_from(value: u8) -> Result<Self> {
+        if value > 100 {
+            return Err(eyre!("canary weight must be a percentage, got {value}"));
+        }
+
+        Ok(Self(value))
+    }
+}
+
+impl From<CanaryWeight> for u8 {
+    fn from(value: CanaryWeight) -> Self {
+        value.0
+    }
+}
+
+impl Deref for CanaryWeight {
+    type Target = u8;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// How to replace the containers of a service when its definition changes.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum DeploymentStrategy {
+    /// Replaces containers in batches, keeping the total and available counts within limits.
+    Rolling {
+        /// The number of containers that can be started above the desired replica count.
+        #[serde(default = "DeploymentStrategy::default_max_surge")]
+        max_surge: u8,
+        /// The number of containers that can be missing below the desired replica count.
+        #[serde(default)]
+        max_unavailable: u8,
+    },
+    /// Starts a full set of new containers, only removing the old ones once they are all healthy.
+    #[default]
+    BlueGreen,
+}
+
+impl DeploymentStrategy {
+    fn default_max_surge() -> u8 {
+        1
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct VolumeDefinition {
     /// The source of the volume, which can be a filesystem path or an S3 bucket/key.
     pub source: ExternalBytes,
     /// The target path inside the container where the volume will be mounted.
     pub target: String,
+    /// Whether to render the content as a template, replacing `{{ env.NAME }}` with variables from
+    /// the decrypted environment and `{{ f2.service }}`, `{{ f2.image }}`, `{{ f2.tag }}` or
+    /// `{{ f2.replica }}` with details of the container.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub template: bool,
 }
 
 #[cfg(test)]
@@ -265,7 +882,10 @@ mod tests {
     use std::collections::HashMap;
     use std::net::Ipv4Addr;
 
-    use crate::config::{AlbConfig, Config, Diff, Scheme, Service};
+    use crate::config::{
+        AlbConfig, CanaryDefinition, Config, ContainerHealthCheck, DeploymentStrategy, Diff, Job,
+        JobSchedule, RateLimit, RateLimitKey, RestartPolicy, Route, Scheme, Service, Ulimit,
+    };
 
     fn some_config() -> Config {
         let mut services = HashMap::new();
@@ -278,8 +898,17 @@ mod tests {
                 reconciliation: String::new(),
                 tls: None,
                 mtls: None,
+                acme: None,
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
             },
             secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
             services,
         }
     }
@@ -370,4 +999,169 @@ mod tests {
             }])
         )
     }
+
+    #[test]
+    fn can_parse_deployment_strategies() -> color_eyre::Result<()> {
+        let rolling: DeploymentStrategy =
+            serde_yaml::from_str("mode: rolling\nmax_unavailable: 2")?;
+        let blue_green: DeploymentStrategy = serde_yaml::from_str("mode: blue_green")?;
+
+        assert_eq!(
+            rolling,
+            DeploymentStrategy::Rolling {
+                max_surge: 1,
+                max_unavailable: 2
+            }
+        );
+        assert_eq!(blue_green, DeploymentStrategy::BlueGreen);
+
+        Ok(())
+    }
+
+    #[test]
+    fn canary_weights_must_be_percentages() -> color_eyre::Result<()> {
+        let canary: CanaryDefinition = serde_yaml::from_str("tag: v2\nweight: 100")?;
+        assert_eq!(*canary.weight, 100);
+
+        let invalid = serde_yaml::from_str::<CanaryDefinition>("tag: v2\nweight: 101");
+        assert!(invalid.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_rate_limits() -> color_eyre::Result<()> {
+        let by_ip: RateLimit = serde_yaml::from_str("requests_per_second: 10")?;
+        let by_header: RateLimit = serde_yaml::from_str(
+            "requests_per_second: 5\nburst: 20\nkey:\n  source: header\n  name: x-api-key",
+        )?;
+
+        assert_eq!(by_ip.key, RateLimitKey::ClientIp);
+        assert_eq!(by_ip.capacity(), 10);
+
+        assert_eq!(
+            by_header.key,
+            RateLimitKey::Header {
+                name: String::from("x-api-key")
+            }
+        );
+        assert_eq!(by_header.capacity(), 20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn routes_can_strip_prefixes_and_rewrite_paths() -> color_eyre::Result<()> {
+        let stripped: Route =
+            serde_yaml::from_str("host: example.com\nprefix: /app\nport: 80\nstrip_prefix: true")?;
+        let rewritten: Route = serde_yaml::from_str(
+            "host: example.com\nprefix: /api\nport: 80\nrewrite:\n  pattern: ^/api/v1/(.*)$\n  replacement: /v2/$1",
+        )?;
+
+        assert_eq!(stripped.downstream_path("/app/users"), "/users");
+        assert_eq!(stripped.downstream_path("/app"), "/");
+        assert_eq!(rewritten.downstream_path("/api/v1/users"), "/v2/users");
+        assert_eq!(rewritten.downstream_path("/api/v3/users"), "/api/v3/users");
+
+        // Invalid patterns are rejected when parsing the configuration
+        let invalid = serde_yaml::from_str::<Route>(
+            "host: example.com\nport: 80\nrewrite:\n  pattern: (\n  replacement: /",
+        );
+
+        assert!(invalid.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_container_options() -> color_eyre::Result<()> {
+        let service: Service = serde_yaml::from_str(
+            r#"
+            image: myapp
+            tag: v1
+            replicas: 1
+            container:
+              millicpus: 500
+              memory_mb: 256
+              ulimits:
+                nofile: { soft: 1024, hard: 2048 }
+              user: "1000:1000"
+              read_only: true
+              cap_drop: [ALL]
+              restart_policy:
+                name: on-failure
+                max_retries: 3
+              healthcheck:
+                command: [/bin/healthcheck, --quiet]
+                interval_ms: 5000
+            "#,
+        )?;
+
+        let options = service.container;
+
+        assert_eq!(options.millicpus, Some(500));
+        assert_eq!(options.memory_mb, Some(256));
+        assert_eq!(
+            options.ulimits.get("nofile"),
+            Some(&Ulimit {
+                soft: 1024,
+                hard: 2048
+            })
+        );
+        assert_eq!(options.user.as_deref(), Some("1000:1000"));
+        assert_eq!(options.working_dir, None);
+        assert!(options.read_only);
+        assert_eq!(options.cap_drop, vec![String::from("ALL")]);
+        assert_eq!(
+            options.restart_policy,
+            Some(RestartPolicy::OnFailure { max_retries: 3 })
+        );
+        assert_eq!(
+            options.healthcheck,
+            Some(ContainerHealthCheck {
+                command: vec![String::from("/bin/healthcheck"), String::from("--quiet")],
+                interval_ms: Some(5000),
+                timeout_ms: None,
+                retries: None,
+                start_period_ms: None,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_job_schedules() -> color_eyre::Result<()> {
+        let jobs: HashMap<String, Job> = serde_yaml::from_str(
+            r#"
+            backup:
+              image: backup
+              tag: v1
+              schedule:
+                trigger: cron
+                expression: "0 3 * * *"
+            migrate:
+              image: myapp
+              tag: v2
+              args: [migrate]
+              schedule:
+                trigger: on_deploy
+            "#,
+        )?;
+
+        let JobSchedule::Cron { expression } = &jobs["backup"].schedule else {
+            return Err(color_eyre::eyre::eyre!("expected a cron schedule"));
+        };
+
+        assert_eq!(expression.as_str(), "0 3 * * *");
+        assert_eq!(jobs["migrate"].schedule, JobSchedule::OnDeploy);
+        assert_eq!(jobs["migrate"].args, vec![String::from("migrate")]);
+
+        // Invalid expressions are rejected when parsing the configuration
+        let invalid = serde_yaml::from_str::<JobSchedule>("trigger: cron\nexpression: 0 3 * *");
+
+        assert!(invalid.is_err());
+
+        Ok(())
+    }
 }
@@ -1,9 +1,20 @@
 use base64::alphabet::STANDARD;
 use base64::engine::{GeneralPurpose, GeneralPurposeConfig};
 use base64::Engine;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use rsa::pkcs8::DecodePrivateKey;
-use rsa::{Pkcs1v15Encrypt, RsaPrivateKey};
+use rand::rngs::ThreadRng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+
+/// The bytes of padding PKCS#1 v1.5 adds, which limits how much can be encrypted with a key.
+const PKCS1V15_PADDING: usize = 11;
+
+/// The length of the random AES-256 keys used for envelopes.
+const DATA_KEY_LEN: usize = 32;
 
 pub fn parse_private_key(bytes: &[u8]) -> Result<RsaPrivateKey> {
     let utf8 = std::str::from_utf8(bytes)?;
@@ -14,6 +25,13 @@ pub fn parse_private_key(bytes: &[u8]) -> Result<RsaPrivateKey> {
     Ok(parsed)
 }
 
+pub fn parse_public_key(bytes: &[u8]) -> Result<RsaPublicKey> {
+    let utf8 = std::str::from_utf8(bytes)?;
+    let parsed = RsaPublicKey::from_public_key_pem(utf8)?;
+
+    Ok(parsed)
+}
+
 pub fn decrypt(secret: &str, key: &RsaPrivateKey) -> Result<String> {
     tracing::debug!(%secret, "decrypting some content");
 
@@ -24,9 +42,122 @@ pub fn decrypt(secret: &str, key: &RsaPrivateKey) -> Result<String> {
     Ok(decoded)
 }
 
+/// Encrypts a value directly with the public key, returning it encoded as base64.
+pub fn encrypt(value: &str, key: &RsaPublicKey) -> Result<String> {
+    let mut rng: ThreadRng = rand::rng();
+    let encrypted = key.encrypt(&mut rng, Pkcs1v15Encrypt, value.as_bytes())?;
+
+    Ok(base64_engine().encode(encrypted))
+}
+
+/// Checks whether a value is small enough to be encrypted directly with the public key.
+pub fn fits_in_key(value: &str, key: &RsaPublicKey) -> bool {
+    value.len() + PKCS1V15_PADDING <= key.size()
+}
+
+/// Encrypts a value of any size with a random AES-256-GCM key, which is itself encrypted with the
+/// public key. Returns the encrypted key and the nonce followed by the ciphertext, both encoded as
+/// base64.
+pub fn seal(value: &str, key: &RsaPublicKey) -> Result<(String, String)> {
+    let random = SystemRandom::new();
+
+    let mut data_key = [0; DATA_KEY_LEN];
+    let mut nonce = [0; NONCE_LEN];
+
+    random
+        .fill(&mut data_key)
+        .and_then(|()| random.fill(&mut nonce))
+        .map_err(|_| eyre!("failed to generate a key for the envelope"))?;
+
+    let sealing_key = envelope_key(&data_key)?;
+    let mut in_out = value.as_bytes().to_vec();
+
+    sealing_key
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut in_out,
+        )
+        .map_err(|_| eyre!("failed to encrypt the envelope"))?;
+
+    let mut rng: ThreadRng = rand::rng();
+    let encrypted_key = key.encrypt(&mut rng, Pkcs1v15Encrypt, &data_key)?;
+
+    let engine = base64_engine();
+    let payload = [nonce.as_slice(), &in_out].concat();
+
+    Ok((engine.encode(encrypted_key), engine.encode(payload)))
+}
+
+/// Reverses [`seal`], decrypting the AES-256-GCM key before using it to decrypt the payload.
+pub fn open(encrypted_key: &str, payload: &str, key: &RsaPrivateKey) -> Result<String> {
+    let data_key = key.decrypt(Pkcs1v15Encrypt, &base64_decode(encrypted_key)?)?;
+    let payload = base64_decode(payload)?;
+
+    if payload.len() < NONCE_LEN {
+        return Err(eyre!("envelope payload is too short to contain a nonce"));
+    }
+
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| eyre!("envelope has an invalid nonce"))?;
+
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = envelope_key(&data_key)?
+        .open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| eyre!("failed to decrypt the envelope, it may have been modified"))?;
+
+    Ok(String::from_utf8(plaintext.to_vec())?)
+}
+
+fn envelope_key(bytes: &[u8]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, bytes)
+        .map_err(|_| eyre!("envelope key must be {DATA_KEY_LEN} bytes"))?;
+
+    Ok(LessSafeKey::new(key))
+}
+
+fn base64_engine() -> GeneralPurpose {
+    GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new())
+}
+
 fn base64_decode(value: &str) -> Result<Vec<u8>> {
-    let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
-    let decoded = engine.decode(value)?;
+    let decoded = base64_engine().decode(value)?;
 
     Ok(decoded)
 }
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+    use rand::rngs::ThreadRng;
+    use rsa::{RsaPrivateKey, RsaPublicKey};
+
+    use crate::crypto::{decrypt, encrypt, fits_in_key, open, seal};
+
+    #[test]
+    fn values_larger_than_the_key_can_be_sealed() -> Result<()> {
+        let mut rng: ThreadRng = rand::rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 1024)?;
+        let public_key = RsaPublicKey::from(&private_key);
+
+        let small = "password";
+        let large = "certificate ".repeat(100);
+
+        assert!(fits_in_key(small, &public_key));
+        assert!(!fits_in_key(&large, &public_key));
+
+        assert_eq!(decrypt(&encrypt(small, &public_key)?, &private_key)?, small);
+
+        let (encrypted_key, payload) = seal(&large, &public_key)?;
+
+        assert_eq!(open(&encrypted_key, &payload, &private_key)?, large);
+
+        // Envelopes are authenticated, so modified payloads are rejected
+        let (_, other_payload) = seal("something else", &public_key)?;
+
+        assert!(open(&encrypted_key, &other_payload, &private_key).is_err());
+
+        Ok(())
+    }
+}
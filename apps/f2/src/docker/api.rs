@@ -1,17 +1,114 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::Permissions;
+use std::io::ErrorKind;
 use std::net::Ipv4Addr;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use color_eyre::eyre::{eyre, Context, Result};
 use rsa::RsaPrivateKey;
+use tokio::io::AsyncWriteExt;
 
-use crate::common::Container;
+use crate::common::{Container, Environment};
 use crate::config::{ExternalBytes, VolumeDefinition};
 use crate::docker::client::{DockerClient, DOCKER_NETWORK_NAME};
-use crate::docker::models::ContainerId;
+use crate::docker::models::{ContainerId, ContainerSummary};
 
 use super::models::NetworkId;
 
+/// The label used to record which service a container belongs to.
+pub const SERVICE_LABEL: &str = "f2.service";
+
+/// The label used to record the hash of the configuration a container was created with.
+pub const CONFIG_HASH_LABEL: &str = "f2.config-hash";
+
+/// The label used to record which job a container was started for.
+pub const JOB_LABEL: &str = "f2.job";
+
+/// The label used to record the replica slot a container was started in.
+pub const REPLICA_LABEL: &str = "f2.replica";
+
+/// Where volumes rendered from templates are written, in a directory for each owner and slot.
+const RENDERED_VOLUMES_DIRECTORY: &str = "/tmp/f2/rendered";
+
+/// Gets the replica slot a container was started in, if it was labelled with one.
+pub fn replica_slot(summary: &ContainerSummary) -> Option<usize> {
+    summary.labels.get(REPLICA_LABEL)?.parse().ok()
+}
+
+/// Takes the lowest replica slot that is not in use, marking it as used.
+pub fn take_free_slot(used: &mut BTreeSet<usize>) -> usize {
+    let slot = (0..).find(|slot| !used.contains(slot)).unwrap_or_default();
+    used.insert(slot);
+
+    slot
+}
+
+/// Gets the directory the templated volumes of a container are rendered into, which is reused by
+/// the next container to start in the same slot.
+fn rendered_directory(owner_label: &str, owner: &str, replica: usize) -> PathBuf {
+    Path::new(RENDERED_VOLUMES_DIRECTORY)
+        .join(owner_label)
+        .join(owner)
+        .join(replica.to_string())
+}
+
+/// Gets the directory a container's templated volumes were rendered into from its labels.
+fn rendered_directory_of(summary: &ContainerSummary) -> Option<PathBuf> {
+    let replica = replica_slot(summary)?;
+
+    [SERVICE_LABEL, JOB_LABEL].into_iter().find_map(|label| {
+        let owner = summary.labels.get(label)?;
+        Some(rendered_directory(label, owner, replica))
+    })
+}
+
+/// Removes a container along with the volumes rendered for it, unless another running container
+/// has since started in the same slot and is using them.
+pub async fn remove_container_and_volumes<C: DockerClient>(
+    client: &C,
+    id: &ContainerId,
+) -> Result<()> {
+    // Failing to find the rendered volumes should not stop the container being removed
+    let summaries = client
+        .list_containers(REPLICA_LABEL)
+        .await
+        .unwrap_or_else(|error| {
+            tracing::warn!(%id, %error, "failed to list containers to find rendered volumes");
+            Vec::new()
+        });
+
+    client.remove_container(id).await?;
+
+    let Some(directory) = summaries
+        .iter()
+        .find(|summary| summary.id == *id)
+        .and_then(rendered_directory_of)
+    else {
+        return Ok(());
+    };
+
+    let in_use = summaries.iter().any(|summary| {
+        summary.id != *id
+            && summary.state == "running"
+            && rendered_directory_of(summary).as_ref() == Some(&directory)
+    });
+
+    if in_use {
+        return Ok(());
+    }
+
+    match tokio::fs::remove_dir_all(&directory).await {
+        Ok(()) => tracing::info!(%id, ?directory, "removed the rendered volumes of a container"),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(error) => {
+            tracing::warn!(%id, ?directory, %error, "failed to remove rendered volumes");
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct StartedContainerDetails {
     pub id: ContainerId,
@@ -21,15 +118,66 @@ pub struct StartedContainerDetails {
 #[tracing::instrument(skip(client, private_key))]
 pub async fn create_and_start_container<C: DockerClient>(
     client: &C,
+    service: &str,
     container: &Container,
     tag: &str,
+    replica: usize,
     private_key: Option<&RsaPrivateKey>,
 ) -> Result<StartedContainerDetails> {
+    let id = create_and_start_labelled_container(
+        client,
+        container,
+        tag,
+        replica,
+        private_key,
+        (SERVICE_LABEL, service),
+    )
+    .await?;
+
+    // Get the container itself and the port details
+    let addr = client.get_container_ip(&id).await?;
+
+    tracing::info!(
+        image = %container.image,
+        %tag,
+        %id,
+        %addr,
+        network = %DOCKER_NETWORK_NAME,
+        "started container"
+    );
+
+    Ok(StartedContainerDetails { id, addr })
+}
+
+/// Starts a container for a run of a job, which is left to exit by itself.
+#[tracing::instrument(skip(client, private_key))]
+pub async fn start_job_container<C: DockerClient>(
+    client: &C,
+    job: &str,
+    container: &Container,
+    tag: &str,
+    private_key: Option<&RsaPrivateKey>,
+) -> Result<ContainerId> {
+    create_and_start_labelled_container(client, container, tag, 0, private_key, (JOB_LABEL, job))
+        .await
+}
+
+/// Creates and starts a container on the Docker network, labelled with what it belongs to and
+/// the hash of its configuration.
+async fn create_and_start_labelled_container<C: DockerClient>(
+    client: &C,
+    container: &Container,
+    tag: &str,
+    replica: usize,
+    private_key: Option<&RsaPrivateKey>,
+    (owner_label, owner): (&str, &str),
+) -> Result<ContainerId> {
     let Container {
         image,
         environment,
         volumes,
         args,
+        options,
     } = &container;
 
     // Ensure the image exists locally
@@ -42,10 +190,26 @@ pub async fn create_and_start_container<C: DockerClient>(
     let name = format!("{image}:{tag}");
 
     let hostname = generate_hostname(image);
-    let environment = environment.decrypt(private_key)?;
-    let volumes = format_volumes(image, tag, volumes, private_key).await?;
+    let environment = environment.decrypt(private_key).await?;
+
+    let variables = TemplateVariables {
+        environment: &environment,
+        owner,
+        image,
+        tag,
+        replica,
+    };
+
+    let rendered = rendered_directory(owner_label, owner, replica);
+    let volumes = format_volumes(volumes, &variables, &rendered, private_key).await?;
 
-    tracing::debug!(%name, ?volumes, "creating container with the following details");
+    // The labels used to track containers take precedence over any configured ones
+    let mut labels = options.labels.clone();
+    labels.insert(owner_label.to_owned(), owner.to_owned());
+    labels.insert(CONFIG_HASH_LABEL.to_owned(), container.config_hash(tag));
+    labels.insert(REPLICA_LABEL.to_owned(), replica.to_string());
+
+    tracing::debug!(%name, ?volumes, ?labels, "creating container with the following details");
 
     let id = client
         .create_container(
@@ -54,6 +218,8 @@ pub async fn create_and_start_container<C: DockerClient>(
             &volumes,
             Some((&network_id, &hostname)),
             args,
+            &labels,
+            options,
         )
         .await?;
 
@@ -61,20 +227,7 @@ pub async fn create_and_start_container<C: DockerClient>(
 
     tracing::info!(%id, %name, %hostname, "created and started a container");
 
-    // Get the container itself and the port details
-    let addr = client.get_container_ip(&id).await?;
-
-    tracing::info!(
-        %image,
-        %tag,
-        %id,
-        %addr,
-        %hostname,
-        network = %DOCKER_NETWORK_NAME,
-        "started container"
-    );
-
-    Ok(StartedContainerDetails { id, addr })
+    Ok(id)
 }
 
 /// Fetches the Docker network ID by its name, returning an error if it does not exist.
@@ -102,27 +255,49 @@ fn generate_hostname(image: &str) -> String {
         .to_string()
 }
 
-/// Formats the volumes for a container, resolving their content and writing it to a temporary file.
+/// Formats the volumes for a container, resolving their content and writing it to a temporary file
+/// when it comes from S3 or is rendered as a template. Rendered volumes are written under the
+/// `rendered` directory of the container.
 async fn format_volumes(
-    image: &str,
-    tag: &str,
     volumes: &HashMap<String, VolumeDefinition>,
+    variables: &TemplateVariables<'_>,
+    rendered: &Path,
     private_key: Option<&RsaPrivateKey>,
 ) -> Result<HashMap<String, String>> {
+    let TemplateVariables { image, tag, .. } = variables;
+
     let mut resolved_volumes = HashMap::new();
 
     for (name, definition) in volumes {
         let span = tracing::info_span!("processing a volume definition", %name, ?definition);
         let _guard = span.enter();
 
-        // if the content is from S3, we need to write it to a temporary file
-        let path = match &definition.source {
-            ExternalBytes::S3 { .. } => {
-                let raw_content = definition.source.resolve().await?;
-                let content = decrypt_content(&raw_content, private_key)
-                    .wrap_err_with(|| format!("failed to decrypt content for volume '{name}'"))?;
+        // Files on the host are mounted directly unless they need rendering
+        let path = match (&definition.source, definition.template) {
+            (ExternalBytes::Filesystem { path }, false) => PathBuf::from(path),
+            (source, template) => {
+                let raw_content = source.resolve().await?;
 
-                tracing::info!(bytes = %content.len(), "decrypted content for volume");
+                // Rendered content can differ between containers, so each gets its own copy
+                let (content, directory) = if template {
+                    let content = render_content(&raw_content, Some(variables), private_key)
+                        .wrap_err_with(|| {
+                            format!("failed to render template for volume '{name}'")
+                        })?;
+
+                    (content, rendered.join(name))
+                } else {
+                    let content =
+                        render_content(&raw_content, None, private_key).wrap_err_with(|| {
+                            format!("failed to decrypt content for volume '{name}'")
+                        })?;
+
+                    let directory = format!("/tmp/f2/{image}/{tag}/{name}");
+
+                    (content, PathBuf::from(directory))
+                };
+
+                tracing::info!(bytes = %content.len(), %template, "resolved content for volume");
 
                 // Ensure we're handling paths correctly regardless of trailing slashes
                 let clean_target = definition.target.trim_end_matches('/');
@@ -131,18 +306,14 @@ async fn format_volumes(
                     .ok_or_else(|| eyre!("invalid target path: {}", definition.target))?;
 
                 // write the content to a temporary file
-                let directory: PathBuf = format!("/tmp/f2/{image}/{tag}/{name}").into();
                 let path = directory.join(target_filename);
 
-                // Ensure the directory exists and write the content
-                std::fs::create_dir_all(&directory)?;
-                std::fs::write(&path, content)?;
+                write_private_file(&directory, &path, &content).await?;
 
                 tracing::info!(?directory, ?path, "wrote volume content to temporary file");
 
                 path
             }
-            ExternalBytes::Filesystem { path } => PathBuf::from(path),
         };
 
         resolved_volumes.insert(
@@ -154,6 +325,30 @@ async fn format_volumes(
     Ok(resolved_volumes)
 }
 
+/// Writes the content of a volume, which can include secrets, such that only the owner can read it.
+async fn write_private_file(directory: &Path, path: &Path, content: &[u8]) -> Result<()> {
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)
+        .await?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+
+    // Files written before this was enforced are tightened as well
+    file.set_permissions(Permissions::from_mode(0o600)).await?;
+    file.write_all(content).await?;
+    file.flush().await?;
+
+    Ok(())
+}
+
 #[tracing::instrument(skip(client))]
 async fn pull_image_if_needed<C: DockerClient>(
     client: &C,
@@ -185,30 +380,77 @@ async fn pull_image_if_needed<C: DockerClient>(
     Ok(())
 }
 
+/// The values templated volumes can refer to, such as `{{ env.DATABASE_URL }}` for a variable from
+/// the decrypted environment or `{{ f2.replica }}` for details of the container.
+#[derive(Debug)]
+struct TemplateVariables<'a> {
+    environment: &'a Environment,
+    /// The service or job the container belongs to.
+    owner: &'a str,
+    image: &'a str,
+    tag: &'a str,
+    /// The slot of the container among the running containers of its owner, from 0, which is the
+    /// lowest one that was free when it started.
+    replica: usize,
+}
+
+impl TemplateVariables<'_> {
+    /// Looks up a variable, returning `None` if the name does not refer to one at all.
+    fn get(&self, name: &str) -> Option<Result<String>> {
+        if let Some(variable) = name.strip_prefix("env.") {
+            let value = self.environment.variables.get(variable).cloned();
+
+            return Some(value.ok_or_else(|| eyre!("unknown environment variable: {variable}")));
+        }
+
+        let value = match name.strip_prefix("f2.")? {
+            "service" => Ok(self.owner.to_owned()),
+            "image" => Ok(self.image.to_owned()),
+            "tag" => Ok(self.tag.to_owned()),
+            "replica" => Ok(self.replica.to_string()),
+            other => Err(eyre!("unknown f2 variable: {other}")),
+        };
+
+        Some(value)
+    }
+}
+
 /// Finds occurrances of content wrapped in `{{ <secret> }}` and decrypts them using the provided
-/// private key, replacing the original content with the decrypted one.
-fn decrypt_content(content: &[u8], private_key: Option<&RsaPrivateKey>) -> Result<Vec<u8>> {
-    let Some(private_key) = private_key else {
+/// private key, replacing the original content with the decrypted one. Templates can also refer to
+/// variables in the same way, which cannot be confused with secrets as base64 has no dots.
+fn render_content(
+    content: &[u8],
+    variables: Option<&TemplateVariables>,
+    private_key: Option<&RsaPrivateKey>,
+) -> Result<Vec<u8>> {
+    if variables.is_none() && private_key.is_none() {
         return Ok(content.to_vec());
-    };
+    }
 
     let Ok(content) = std::str::from_utf8(content) else {
         return Err(eyre!("content is not valid UTF-8"));
     };
 
     let segments = find_replaceable_segments(content);
-    let mut decrypted_content = String::new();
+    let mut rendered_content = String::new();
 
     for segment in segments {
         let content = match segment {
             Segment::Text(text) => text,
-            Segment::Secret { encrypted } => crate::crypto::decrypt(&encrypted, private_key)?,
+            Segment::Secret { encrypted } => {
+                match (variables.and_then(|v| v.get(&encrypted)), private_key) {
+                    (Some(value), _) => value?,
+                    (None, Some(private_key)) => crate::crypto::decrypt(&encrypted, private_key)?,
+                    // Left as it was, since there is nothing to decrypt it with
+                    (None, None) => format!("{{{{ {encrypted} }}}}"),
+                }
+            }
         };
 
-        decrypted_content.push_str(&content);
+        rendered_content.push_str(&content);
     }
 
-    Ok(decrypted_content.into_bytes())
+    Ok(rendered_content.into_bytes())
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -258,7 +500,17 @@ fn find_replaceable_segments(content: &str) -> Vec<Segment> {
 
 #[cfg(test)]
 mod tests {
-    use crate::docker::api::{find_replaceable_segments, generate_hostname, Segment};
+    use std::collections::{BTreeSet, HashMap};
+    use std::os::unix::fs::PermissionsExt;
+
+    use color_eyre::eyre::Result;
+
+    use crate::common::Environment;
+    use crate::config::{ExternalBytes, VolumeDefinition};
+    use crate::docker::api::{
+        find_replaceable_segments, format_volumes, generate_hostname, render_content,
+        take_free_slot, Segment, TemplateVariables,
+    };
 
     #[test]
     fn can_find_replaceable_content_correctly() {
@@ -299,4 +551,101 @@ mod tests {
     fn can_generate_container_names_with_slash_and_colon() {
         assert_eq!(generate_hostname("company/nginx:tag"), "nginx");
     }
+
+    #[test]
+    fn templates_can_refer_to_the_environment_and_container() -> Result<()> {
+        let environment = Environment {
+            variables: HashMap::from([(
+                String::from("DATABASE_URL"),
+                String::from("postgres://db/app"),
+            )]),
+        };
+
+        let variables = TemplateVariables {
+            environment: &environment,
+            owner: "backend",
+            image: "company/backend",
+            tag: "v1",
+            replica: 2,
+        };
+
+        let template = "url = {{ env.DATABASE_URL }}\nnode = {{ f2.service }}-{{ f2.replica }}\n";
+        let rendered = render_content(template.as_bytes(), Some(&variables), None)?;
+
+        assert_eq!(
+            String::from_utf8(rendered)?,
+            "url = postgres://db/app\nnode = backend-2\n"
+        );
+
+        // Secrets are left alone without a key, while unknown variables are an error
+        let secret = render_content(b"{{ c2VjcmV0 }}", Some(&variables), None)?;
+
+        assert_eq!(secret, b"{{ c2VjcmV0 }}");
+        assert!(render_content(b"{{ env.MISSING }}", Some(&variables), None).is_err());
+        assert!(render_content(b"{{ f2.unknown }}", Some(&variables), None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn free_slots_are_taken_lowest_first() {
+        let mut used = BTreeSet::from([0, 2]);
+
+        assert_eq!(take_free_slot(&mut used), 1);
+        assert_eq!(take_free_slot(&mut used), 3);
+        assert_eq!(used, BTreeSet::from([0, 1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn rendered_volumes_are_private_to_each_slot() -> Result<()> {
+        let source = tempfile::NamedTempFile::new()?;
+        std::fs::write(source.path(), "node = {{ f2.service }}-{{ f2.replica }}\n")?;
+
+        let volumes = HashMap::from([(
+            String::from("config"),
+            VolumeDefinition {
+                source: ExternalBytes::Filesystem {
+                    path: source.path().to_owned(),
+                },
+                target: String::from("/etc/app/config.toml"),
+                template: true,
+            },
+        )]);
+
+        let environment = Environment {
+            variables: HashMap::new(),
+        };
+        let variables = |replica| TemplateVariables {
+            environment: &environment,
+            owner: "backend",
+            image: "company/backend",
+            tag: "v1",
+            replica,
+        };
+
+        let directory = tempfile::tempdir()?;
+        let slot = |replica: usize| directory.path().join(replica.to_string());
+
+        let first = format_volumes(&volumes, &variables(0), &slot(0), None).await?;
+        let second = format_volumes(&volumes, &variables(1), &slot(1), None).await?;
+        let reused = format_volumes(&volumes, &variables(0), &slot(0), None).await?;
+
+        let first = first.keys().next().cloned().unwrap_or_default();
+        let second = second.keys().next().cloned().unwrap_or_default();
+        let reused = reused.keys().next().cloned().unwrap_or_default();
+
+        // Each slot gets its own copy, which the next container in the slot writes over
+        assert_ne!(first, second);
+        assert_eq!(first, reused);
+        assert_eq!(std::fs::read_to_string(&first)?, "node = backend-0\n");
+        assert_eq!(std::fs::read_to_string(&second)?, "node = backend-1\n");
+
+        let mode = std::fs::metadata(&first)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mode = std::fs::metadata(slot(0))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        Ok(())
+    }
 }
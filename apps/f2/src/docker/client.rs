@@ -6,15 +6,17 @@ use color_eyre::Section;
 use http::Response;
 use http_body_util::{BodyExt, Full};
 use hyper::body::{Bytes, Incoming};
-use hyper::{Method, Request, Uri};
+use hyper::{Method, Request, StatusCode, Uri};
 use hyper_util::client::legacy::Client as HyperClient;
 use hyperlocal::{UnixClientExt, UnixConnector};
 use serde::de::DeserializeOwned;
 
 use crate::common::Environment;
+use crate::config::{self, ContainerOptions};
 use crate::docker::models::{
-    CreateContainerOptions, CreateContainerResponse, EndpointConfig, HostConfig, ImageSummary,
-    InspectContainerResponse, Network, NetworkId, NetworkingConfig,
+    ContainerState, ContainerSummary, CreateContainerOptions, CreateContainerResponse,
+    EndpointConfig, Healthcheck, HostConfig, ImageSummary, InspectContainerResponse, Network,
+    NetworkId, NetworkingConfig, RestartPolicy, Ulimit,
 };
 
 use super::models::ContainerId;
@@ -28,6 +30,7 @@ pub trait DockerClient {
 
     async fn get_network_by_name(&self, name: &str) -> Result<Option<NetworkId>>;
 
+    #[allow(clippy::too_many_arguments)]
     async fn create_container(
         &self,
         image: &str,
@@ -35,12 +38,23 @@ pub trait DockerClient {
         docker_volumes: &HashMap<String, String>,
         network: Option<(&NetworkId, &str)>,
         args: &[String],
+        labels: &HashMap<String, String>,
+        options: &ContainerOptions,
     ) -> Result<ContainerId>;
 
+    /// Lists all containers with the given label, including those that are no longer running.
+    async fn list_containers(&self, label: &str) -> Result<Vec<ContainerSummary>>;
+
     async fn start_container(&self, id: &ContainerId) -> Result<()>;
 
     async fn get_container_ip(&self, id: &ContainerId) -> Result<Ipv4Addr>;
 
+    /// Gets the current state of a container, or `None` if it no longer exists.
+    async fn get_container_state(&self, id: &ContainerId) -> Result<Option<ContainerState>>;
+
+    /// Gets the last `tail` lines a container wrote to stdout and stderr.
+    async fn get_container_logs(&self, id: &ContainerId, tail: usize) -> Result<String>;
+
     async fn stop_container(&self, id: &ContainerId) -> Result<()>;
 
     async fn remove_container(&self, id: &ContainerId) -> Result<()>;
@@ -123,6 +137,7 @@ impl DockerClient for Client {
         Ok(network.map(|n| NetworkId(n.id.clone())))
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(skip(self, environment))]
     async fn create_container(
         &self,
@@ -131,17 +146,13 @@ impl DockerClient for Client {
         docker_volumes: &HashMap<String, String>,
         network: Option<(&NetworkId, &str)>,
         args: &[String],
+        labels: &HashMap<String, String>,
+        options: &ContainerOptions,
     ) -> Result<ContainerId> {
         let uri = self.build_uri("/containers/create");
 
         let env = format_environment_variables(environment);
-
-        let host_config = HostConfig {
-            binds: docker_volumes
-                .iter()
-                .map(|(host_path, container_path)| format!("{host_path}:{container_path}"))
-                .collect(),
-        };
+        let host_config = format_host_config(docker_volumes, options);
 
         tracing::info!(?host_config, "creating a container");
 
@@ -164,9 +175,13 @@ impl DockerClient for Client {
             image: String::from(image),
             env,
             volumes: &HashMap::new(),
+            labels,
             host_config,
             networking_config,
             cmd: args.to_vec(),
+            user: options.user.clone(),
+            working_dir: options.working_dir.clone(),
+            healthcheck: options.healthcheck.as_ref().map(format_healthcheck),
         };
 
         let body = serde_json::to_vec(&options)?;
@@ -187,6 +202,19 @@ impl DockerClient for Client {
         Ok(body.id)
     }
 
+    async fn list_containers(&self, label: &str) -> Result<Vec<ContainerSummary>> {
+        let filter = urlencoding::encode(&format!(r#"{{"label":["{label}"]}}"#)).into_owned();
+        let uri = self.build_uri(&format!("/containers/json?all=true&filters={filter}"));
+
+        tracing::info!(%label, "listing containers with a label");
+
+        let response = self.client.get(uri).await?;
+
+        deserialize_body(response)
+            .await
+            .wrap_err_with(|| format!("failed to list containers with label {label}"))
+    }
+
     async fn start_container(&self, id: &ContainerId) -> Result<()> {
         let path = format!("/containers/{id}/start");
         let uri = self.build_uri(&path);
@@ -232,6 +260,46 @@ impl DockerClient for Client {
         Ok(ip_address)
     }
 
+    async fn get_container_state(&self, id: &ContainerId) -> Result<Option<ContainerState>> {
+        let path = format!("/containers/{id}/json");
+        let uri = self.build_uri(&path);
+
+        tracing::debug!(%id, "fetching the state of a container");
+
+        let response = self.client.get(uri).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            read_body(response).await?;
+            return Ok(None);
+        }
+
+        let payload: InspectContainerResponse = deserialize_body(response)
+            .await
+            .wrap_err_with(|| format!("failed to inspect container {id}"))?;
+
+        Ok(Some(payload.state))
+    }
+
+    async fn get_container_logs(&self, id: &ContainerId, tail: usize) -> Result<String> {
+        let path = format!("/containers/{id}/logs?stdout=true&stderr=true&tail={tail}");
+        let uri = self.build_uri(&path);
+
+        tracing::debug!(%id, "fetching the logs of a container");
+
+        let response = self.client.get(uri).await?;
+        let status = response.status();
+        let bytes = read_body(response).await?;
+
+        if !status.is_success() {
+            eyre::bail!(
+                "failed to fetch logs for container {id} with status code {status}: {}",
+                String::from_utf8_lossy(&bytes)
+            );
+        }
+
+        Ok(demultiplex_logs(&bytes))
+    }
+
     async fn stop_container(&self, id: &ContainerId) -> Result<()> {
         let path = format!("/containers/{id}/stop?signal=SIGTERM&t=15");
         let uri = self.build_uri(&path);
@@ -294,6 +362,90 @@ fn format_environment_variables(environment: &Option<Environment>) -> Vec<String
         .collect()
 }
 
+/// Builds the host configuration for a container from its volumes and the limits for the service.
+fn format_host_config(
+    docker_volumes: &HashMap<String, String>,
+    options: &ContainerOptions,
+) -> HostConfig {
+    let mut ulimits: Vec<_> = options
+        .ulimits
+        .iter()
+        .map(|(name, limit)| Ulimit {
+            name: name.clone(),
+            soft: limit.soft,
+            hard: limit.hard,
+        })
+        .collect();
+
+    ulimits.sort_by(|left, right| left.name.cmp(&right.name));
+
+    HostConfig {
+        binds: docker_volumes
+            .iter()
+            .map(|(host_path, container_path)| format!("{host_path}:{container_path}"))
+            .collect(),
+        nano_cpus: options.millicpus.map(|millicpus| millicpus * 1_000_000),
+        memory: options.memory_mb.map(|memory_mb| memory_mb * 1024 * 1024),
+        ulimits,
+        readonly_rootfs: options.read_only,
+        cap_add: options.cap_add.clone(),
+        cap_drop: options.cap_drop.clone(),
+        restart_policy: options.restart_policy.map(format_restart_policy),
+    }
+}
+
+fn format_restart_policy(policy: config::RestartPolicy) -> RestartPolicy {
+    let (name, maximum_retry_count) = match policy {
+        config::RestartPolicy::No => ("no", None),
+        config::RestartPolicy::Always => ("always", None),
+        config::RestartPolicy::UnlessStopped => ("unless-stopped", None),
+        config::RestartPolicy::OnFailure { max_retries } => ("on-failure", Some(max_retries)),
+    };
+
+    RestartPolicy {
+        name,
+        maximum_retry_count,
+    }
+}
+
+/// Builds the healthcheck for a container, which Docker runs without a shell.
+fn format_healthcheck(healthcheck: &config::ContainerHealthCheck) -> Healthcheck {
+    let nanoseconds = |ms: Option<u64>| ms.map(|ms| ms * 1_000_000);
+
+    Healthcheck {
+        test: std::iter::once(String::from("CMD"))
+            .chain(healthcheck.command.iter().cloned())
+            .collect(),
+        interval: nanoseconds(healthcheck.interval_ms),
+        timeout: nanoseconds(healthcheck.timeout_ms),
+        retries: healthcheck.retries,
+        start_period: nanoseconds(healthcheck.start_period_ms),
+    }
+}
+
+/// Joins the frames of a log stream, which Docker prefixes with the stream they came from and
+/// their length when the container has no TTY.
+fn demultiplex_logs(bytes: &[u8]) -> String {
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut remaining = bytes;
+
+    while let [0..=2, 0, 0, 0, a, b, c, d, rest @ ..] = remaining {
+        let length = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+
+        if length > rest.len() {
+            break;
+        }
+
+        output.extend_from_slice(&rest[..length]);
+        remaining = &rest[length..];
+    }
+
+    // Anything that does not look like a frame is kept as it is
+    output.extend_from_slice(remaining);
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
 async fn read_body(response: Response<Incoming>) -> Result<Bytes> {
     let collected = response
         .into_body()
@@ -325,3 +477,97 @@ where
 
     Ok(json)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use color_eyre::eyre::Result;
+    use serde_json::json;
+
+    use crate::config::{ContainerHealthCheck, ContainerOptions, RestartPolicy, Ulimit};
+    use crate::docker::client::{demultiplex_logs, format_healthcheck, format_host_config};
+
+    #[test]
+    fn container_options_map_to_the_host_config() -> Result<()> {
+        let options = ContainerOptions {
+            millicpus: Some(1500),
+            memory_mb: Some(512),
+            ulimits: HashMap::from([(
+                String::from("nofile"),
+                Ulimit {
+                    soft: 1024,
+                    hard: 2048,
+                },
+            )]),
+            read_only: true,
+            cap_add: vec![String::from("NET_BIND_SERVICE")],
+            cap_drop: vec![String::from("ALL")],
+            restart_policy: Some(RestartPolicy::OnFailure { max_retries: 3 }),
+            ..Default::default()
+        };
+
+        let volumes = HashMap::from([(String::from("/srv/data"), String::from("/data"))]);
+        let host_config = serde_json::to_value(format_host_config(&volumes, &options))?;
+
+        assert_eq!(
+            host_config,
+            json!({
+                "Binds": ["/srv/data:/data"],
+                "NanoCpus": 1_500_000_000_u64,
+                "Memory": 536_870_912_u64,
+                "Ulimits": [{ "Name": "nofile", "Soft": 1024, "Hard": 2048 }],
+                "ReadonlyRootfs": true,
+                "CapAdd": ["NET_BIND_SERVICE"],
+                "CapDrop": ["ALL"],
+                "RestartPolicy": { "Name": "on-failure", "MaximumRetryCount": 3 },
+            })
+        );
+
+        // Nothing is sent for settings that are not configured
+        let defaults = serde_json::to_value(format_host_config(
+            &HashMap::new(),
+            &ContainerOptions::default(),
+        ))?;
+
+        assert_eq!(defaults, json!({ "Binds": [] }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn healthchecks_run_their_command_directly() -> Result<()> {
+        let healthcheck = ContainerHealthCheck {
+            command: vec![String::from("/bin/healthcheck"), String::from("--quiet")],
+            interval_ms: Some(5000),
+            timeout_ms: Some(500),
+            retries: Some(3),
+            start_period_ms: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(format_healthcheck(&healthcheck))?,
+            json!({
+                "Test": ["CMD", "/bin/healthcheck", "--quiet"],
+                "Interval": 5_000_000_000_u64,
+                "Timeout": 500_000_000_u64,
+                "Retries": 3,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_frames_are_joined() {
+        let mut bytes = vec![1, 0, 0, 0, 0, 0, 0, 6];
+        bytes.extend_from_slice(b"hello\n");
+        bytes.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 7]);
+        bytes.extend_from_slice(b"failed\n");
+
+        assert_eq!(demultiplex_logs(&bytes), "hello\nfailed\n");
+
+        // Containers with a TTY send their output as it is
+        assert_eq!(demultiplex_logs(b"plain output\n"), "plain output\n");
+    }
+}
@@ -0,0 +1,46 @@
+use std::io::Read;
+
+use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
+use rsa::RsaPublicKey;
+
+use crate::config::{Config, ExternalBytes};
+use crate::crypto::parse_public_key;
+use crate::secrets::encrypt_secret;
+
+/// Encrypts a value read from stdin and prints the `secret:` reference to put in the
+/// configuration, so values never need to appear in shell history.
+pub async fn run(location: &ExternalBytes, public_key: Option<&ExternalBytes>) -> Result<()> {
+    let public_key = match public_key {
+        Some(public_key) => parse_public_key(&public_key.resolve().await?)
+            .wrap_err("failed to parse the public key")?,
+        None => {
+            let config = Config::from_location(location).await?;
+            let private_key = config
+                .get_private_key()
+                .await
+                .wrap_err("failed to load the private key for secrets")?
+                .ok_or_else(|| {
+                    eyre!("no private key is configured, so a public key must be provided")
+                })?;
+
+            RsaPublicKey::from(&private_key)
+        }
+    };
+
+    let mut value = String::new();
+    std::io::stdin()
+        .read_to_string(&mut value)
+        .wrap_err("failed to read the value to encrypt from stdin")?;
+
+    // Values piped in with `echo` end with a newline that is not part of the secret
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+
+    ensure!(
+        !value.is_empty(),
+        "no value to encrypt was provided on stdin"
+    );
+
+    println!("{}", encrypt_secret(value, &public_key)?);
+
+    Ok(())
+}
@@ -1,5 +1,4 @@
-#![allow(dead_code)]
-
+use std::net::Ipv4Addr;
 use std::time::Duration;
 
 use color_eyre::eyre::Result;
@@ -10,12 +9,28 @@ use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::TokioExecutor;
 
+use crate::config::HealthCheckDefinition;
+
+pub use monitor::HealthMonitor;
+
+mod monitor;
+
+/// How long to wait for a response to a single health check request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type HealthCheckClient = Client<HttpConnector, BoxBody<Bytes, hyper::Error>>;
+
+fn build_client() -> HealthCheckClient {
+    Client::builder(TokioExecutor::new()).build_http()
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum HealthCheckResult {
     Success,
     Failure,
 }
 
+#[derive(Copy, Clone, Debug)]
 pub struct HealthCheckConfiguration {
     period: Duration,
     success_threshold: u32,
@@ -32,6 +47,17 @@ impl HealthCheckConfiguration {
     }
 }
 
+impl From<&HealthCheckDefinition> for HealthCheckConfiguration {
+    fn from(definition: &HealthCheckDefinition) -> Self {
+        Self::new(
+            Duration::from_millis(definition.period_ms),
+            definition.success_threshold,
+            definition.failure_threshold,
+        )
+    }
+}
+
+#[derive(Debug)]
 pub struct HealthCheck {
     target: Uri,
     configuration: HealthCheckConfiguration,
@@ -45,9 +71,26 @@ impl HealthCheck {
         }
     }
 
+    /// Builds a health check for a container at the given address from a service definition.
+    pub fn for_container(definition: &HealthCheckDefinition, addr: Ipv4Addr) -> Result<Self> {
+        let target = format!("http://{addr}:{}{}", definition.port, definition.path).parse()?;
+        let configuration = HealthCheckConfiguration::from(definition);
+
+        Ok(Self::new(target, configuration))
+    }
+
+    /// Sends a single request to the target, treating errors and timeouts as failures.
+    async fn probe(&self, client: &HealthCheckClient) -> HealthCheckResult {
+        let request = client.get(self.target.clone());
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, request).await {
+            Ok(Ok(res)) if res.status().is_success() => HealthCheckResult::Success,
+            _ => HealthCheckResult::Failure,
+        }
+    }
+
     pub async fn run(&self) -> Result<HealthCheckResult> {
-        let client: Client<HttpConnector, BoxBody<Bytes, hyper::Error>> =
-            Client::builder(TokioExecutor::new()).build_http();
+        let client = build_client();
 
         let mut successes = 0;
         let mut failures = 0;
@@ -55,9 +98,9 @@ impl HealthCheck {
         loop {
             tokio::time::sleep(self.configuration.period).await;
 
-            match client.get(self.target.clone()).await {
-                Ok(res) if res.status().is_success() => successes += 1,
-                _ => failures += 1,
+            match self.probe(&client).await {
+                HealthCheckResult::Success => successes += 1,
+                HealthCheckResult::Failure => failures += 1,
             }
 
             if successes >= self.configuration.success_threshold {
@@ -72,7 +115,7 @@ impl HealthCheck {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
     use std::str::FromStr;
     use std::sync::atomic::{AtomicU32, Ordering};
@@ -90,7 +133,7 @@ mod tests {
 
     use crate::health::{HealthCheck, HealthCheckConfiguration, HealthCheckResult};
 
-    async fn spawn_server<F: Fn(u32) -> StatusCode + Copy + Send + Sync + 'static>(
+    pub async fn spawn_server<F: Fn(u32) -> StatusCode + Copy + Send + Sync + 'static>(
         behaviour: F,
     ) -> Result<SocketAddr> {
         let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use opentelemetry::metrics::{Counter, Gauge, Meter};
+use opentelemetry::KeyValue;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+use crate::config::HealthCheckDefinition;
+use crate::docker::api::StartedContainerDetails;
+use crate::docker::models::ContainerId;
+use crate::health::{build_client, HealthCheck, HealthCheckClient, HealthCheckResult};
+use crate::service_registry::ServiceRegistry;
+
+/// The longest time to wait before checking the registry for new containers to probe.
+const MAX_IDLE_PERIOD: Duration = Duration::from_secs(1);
+
+struct HealthMetrics {
+    probes: Counter<u64>,
+    transitions: Counter<u64>,
+    unhealthy_containers: Gauge<u64>,
+}
+
+impl HealthMetrics {
+    fn new(meter: &Meter) -> Self {
+        Self {
+            probes: meter
+                .u64_counter("f2_health_check_probes_total")
+                .with_description("Total health check probes sent to containers by result")
+                .build(),
+            transitions: meter
+                .u64_counter("f2_health_check_transitions_total")
+                .with_description("Total changes in container health by new status")
+                .build(),
+            unhealthy_containers: meter
+                .u64_gauge("f2_unhealthy_containers")
+                .with_description("Number of containers excluded from the load balancer")
+                .build(),
+        }
+    }
+}
+
+/// The recent probe results for a container, used to decide whether its health has changed.
+#[derive(Debug)]
+struct ProbeState {
+    consecutive_successes: u32,
+    consecutive_failures: u32,
+    next_probe: Instant,
+}
+
+impl ProbeState {
+    fn new(next_probe: Instant) -> Self {
+        Self {
+            consecutive_successes: 0,
+            consecutive_failures: 0,
+            next_probe,
+        }
+    }
+
+    /// Records the result of a probe, returning the new health of the container if the relevant
+    /// threshold has been reached.
+    fn record(
+        &mut self,
+        result: HealthCheckResult,
+        definition: &HealthCheckDefinition,
+    ) -> Option<HealthCheckResult> {
+        match result {
+            HealthCheckResult::Success => {
+                self.consecutive_successes += 1;
+                self.consecutive_failures = 0;
+
+                (self.consecutive_successes >= definition.success_threshold)
+                    .then_some(HealthCheckResult::Success)
+            }
+            HealthCheckResult::Failure => {
+                self.consecutive_failures += 1;
+                self.consecutive_successes = 0;
+
+                (self.consecutive_failures >= definition.failure_threshold)
+                    .then_some(HealthCheckResult::Failure)
+            }
+        }
+    }
+}
+
+struct ProbeTarget {
+    service: String,
+    definition: HealthCheckDefinition,
+    details: StartedContainerDetails,
+}
+
+/// Continuously probes running containers, removing unhealthy ones from the load balancer and
+/// restoring them once they recover.
+pub struct HealthMonitor {
+    registry: Arc<RwLock<ServiceRegistry>>,
+    client: HealthCheckClient,
+    metrics: HealthMetrics,
+}
+
+impl HealthMonitor {
+    pub fn new(registry: Arc<RwLock<ServiceRegistry>>) -> Self {
+        let meter = opentelemetry::global::meter("f2");
+
+        Self {
+            registry,
+            client: build_client(),
+            metrics: HealthMetrics::new(&meter),
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let mut states: HashMap<ContainerId, ProbeState> = HashMap::new();
+
+        loop {
+            let now = Instant::now();
+            let targets = self.find_targets().await;
+
+            // Forget about any containers that have since been removed
+            states.retain(|id, _| targets.iter().any(|t| t.details.id == *id));
+
+            let due: Vec<_> = targets
+                .iter()
+                .filter(|target| {
+                    let period = Duration::from_millis(target.definition.period_ms);
+                    let state = states
+                        .entry(target.details.id.clone())
+                        .or_insert_with(|| ProbeState::new(now + period));
+
+                    let is_due = state.next_probe <= now;
+
+                    if is_due {
+                        state.next_probe = now + period;
+                    }
+
+                    is_due
+                })
+                .collect();
+
+            let probes = due.iter().map(|target| self.probe(target));
+            let results = futures::future::join_all(probes).await;
+
+            for (target, result) in due.into_iter().zip(results) {
+                let Some(state) = states.get_mut(&target.details.id) else {
+                    continue;
+                };
+
+                if let Some(health) = state.record(result, &target.definition) {
+                    self.update_health(target, health).await;
+                }
+            }
+
+            self.record_unhealthy_containers(&targets).await;
+
+            let wake_at = states
+                .values()
+                .map(|state| state.next_probe)
+                .min()
+                .unwrap_or(now + MAX_IDLE_PERIOD)
+                .min(now + MAX_IDLE_PERIOD);
+
+            tokio::time::sleep_until(wake_at).await;
+        }
+    }
+
+    async fn find_targets(&self) -> Vec<ProbeTarget> {
+        let read_lock = self.registry.read().await;
+
+        read_lock
+            .get_health_checked_containers()
+            .into_iter()
+            .flat_map(|(service, definition, containers)| {
+                containers.into_iter().map(move |details| ProbeTarget {
+                    service: service.clone(),
+                    definition: definition.clone(),
+                    details,
+                })
+            })
+            .collect()
+    }
+
+    async fn probe(&self, target: &ProbeTarget) -> HealthCheckResult {
+        let result = match HealthCheck::for_container(&target.definition, target.details.addr) {
+            Ok(health_check) => health_check.probe(&self.client).await,
+            Err(error) => {
+                tracing::warn!(service = %target.service, %error, "failed to build health check");
+                HealthCheckResult::Failure
+            }
+        };
+
+        let label = match result {
+            HealthCheckResult::Success => "success",
+            HealthCheckResult::Failure => "failure",
+        };
+
+        self.metrics.probes.add(
+            1,
+            &[
+                KeyValue::new("service", target.service.clone()),
+                KeyValue::new("result", label),
+            ],
+        );
+
+        result
+    }
+
+    async fn update_health(&self, target: &ProbeTarget, health: HealthCheckResult) {
+        let ProbeTarget {
+            service, details, ..
+        } = target;
+
+        let mut write_lock = self.registry.write().await;
+        let currently_healthy = write_lock.is_healthy(&details.id);
+
+        let status = match health {
+            HealthCheckResult::Success if !currently_healthy => {
+                tracing::info!(
+                    %service,
+                    id = %details.id,
+                    addr = %details.addr,
+                    "container recovered, adding it back to the load balancer"
+                );
+
+                write_lock.mark_healthy(&details.id);
+                "healthy"
+            }
+            HealthCheckResult::Failure if currently_healthy => {
+                tracing::warn!(
+                    %service,
+                    id = %details.id,
+                    addr = %details.addr,
+                    "container failed its health checks, removing it from the load balancer"
+                );
+
+                write_lock.mark_unhealthy(&details.id);
+                "unhealthy"
+            }
+            _ => return,
+        };
+
+        self.metrics.transitions.add(
+            1,
+            &[
+                KeyValue::new("service", service.clone()),
+                KeyValue::new("status", status),
+            ],
+        );
+    }
+
+    async fn record_unhealthy_containers(&self, targets: &[ProbeTarget]) {
+        let read_lock = self.registry.read().await;
+        let mut unhealthy: HashMap<&str, u64> = HashMap::new();
+
+        for target in targets {
+            let count = unhealthy.entry(&target.service).or_default();
+
+            if !read_lock.is_healthy(&target.details.id) {
+                *count += 1;
+            }
+        }
+
+        for (service, count) in unhealthy {
+            self.metrics
+                .unhealthy_containers
+                .record(count, &[KeyValue::new("service", service.to_owned())]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use color_eyre::eyre::{eyre, Result};
+    use hyper::StatusCode;
+    use tokio::sync::RwLock;
+
+    use crate::config::{HealthCheckDefinition, Route, Service};
+    use crate::docker::api::StartedContainerDetails;
+    use crate::docker::models::ContainerId;
+    use crate::health::tests::spawn_server;
+    use crate::health::HealthMonitor;
+    use crate::service_registry::ServiceRegistry;
+
+    static CONTAINER_HEALTHY: AtomicBool = AtomicBool::new(true);
+
+    async fn wait_for_health(
+        registry: &RwLock<ServiceRegistry>,
+        id: &ContainerId,
+        healthy: bool,
+    ) -> Result<()> {
+        for _ in 0..100 {
+            if registry.read().await.is_healthy(id) == healthy {
+                return Ok(());
+            }
+
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        Err(eyre!("container never reached the expected health"))
+    }
+
+    #[tokio::test]
+    async fn unhealthy_containers_are_evicted_and_restored() -> Result<()> {
+        let addr = spawn_server(|_| match CONTAINER_HEALTHY.load(Ordering::SeqCst) {
+            true => StatusCode::OK,
+            false => StatusCode::INTERNAL_SERVER_ERROR,
+        })
+        .await?;
+
+        let name = "backend";
+        let host = "backend.app";
+        let id = ContainerId::random();
+
+        let mut registry = ServiceRegistry::new();
+
+        registry.define(
+            name,
+            Service {
+                routes: HashSet::from([Route {
+                    host: host.to_owned(),
+                    ..Default::default()
+                }]),
+                health_check: Some(HealthCheckDefinition {
+                    path: String::from("/health"),
+                    port: addr.port(),
+                    period_ms: 2,
+                    success_threshold: 1,
+                    failure_threshold: 1,
+                }),
+                ..Default::default()
+            },
+        );
+
+        registry.add_container(
+            name,
+            StartedContainerDetails {
+                id: id.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let registry = Arc::new(RwLock::new(registry));
+        let monitor = HealthMonitor::new(Arc::clone(&registry));
+
+        tokio::spawn(monitor.run());
+
+        CONTAINER_HEALTHY.store(false, Ordering::SeqCst);
+        wait_for_health(&registry, &id, false).await?;
+
+        let downstreams = registry
+            .read()
+            .await
+            .find_downstreams(host, "/")
+            .map(|downstreams| downstreams.containers.len());
+
+        assert_eq!(downstreams, Some(0));
+
+        CONTAINER_HEALTHY.store(true, Ordering::SeqCst);
+        wait_for_health(&registry, &id, true).await?;
+
+        let downstreams = registry
+            .read()
+            .await
+            .find_downstreams(host, "/")
+            .map(|downstreams| downstreams.containers.len());
+
+        assert_eq!(downstreams, Some(1));
+
+        Ok(())
+    }
+}
@@ -4,16 +4,28 @@ use color_eyre::eyre::{eyre, Result};
 use flume::{Receiver, Sender};
 use uuid::Uuid;
 
+use crate::docker::models::ContainerId;
+
 pub struct Message<T> {
     identifier: Uuid,
-    #[allow(dead_code)]
     content: T,
 }
 
+impl<T> Message<T> {
+    pub fn into_content(self) -> T {
+        self.content
+    }
+}
+
 #[derive(Debug)]
 pub struct CertificateUpdateRequest;
 #[derive(Debug)]
 pub struct ReconciliationRequest;
+/// Asks the reconciler to replace a single container with a fresh one.
+#[derive(Debug)]
+pub struct ContainerRestartRequest {
+    pub id: ContainerId,
+}
 
 #[derive(Debug)]
 pub struct ChannelPair<T> {
@@ -33,6 +45,7 @@ impl<T> ChannelPair<T> {
 pub struct MessageBus {
     reconciliation: ChannelPair<ReconciliationRequest>,
     resolver: ChannelPair<CertificateUpdateRequest>,
+    restart: ChannelPair<ContainerRestartRequest>,
 }
 
 impl MessageBus {
@@ -40,9 +53,12 @@ impl MessageBus {
         let reconciliation_pair = ChannelPair::<ReconciliationRequest>::new();
         let resolver_pair = ChannelPair::<CertificateUpdateRequest>::new();
 
+        let restart_pair = ChannelPair::<ContainerRestartRequest>::new();
+
         let message_bus = MessageBus {
             reconciliation: reconciliation_pair,
             resolver: resolver_pair,
+            restart: restart_pair,
         };
 
         Arc::new(message_bus)
@@ -82,6 +98,23 @@ impl MessageBus {
         Ok(identifier)
     }
 
+    pub fn send_container_restart_request(&self, id: ContainerId) -> Result<Uuid> {
+        let identifier = Uuid::new_v4();
+        let message = Message {
+            identifier,
+            content: ContainerRestartRequest { id },
+        };
+
+        tracing::debug!(%identifier, "sending container restart request");
+
+        self.restart
+            .sender
+            .send(message)
+            .map_err(|_| eyre!("Failed to send container restart request"))?;
+
+        Ok(identifier)
+    }
+
     pub async fn receive_reconciliation_request(
         &self,
     ) -> Result<Message<ReconciliationRequest>, flume::RecvError> {
@@ -101,12 +134,23 @@ impl MessageBus {
 
         Ok(received)
     }
+
+    pub async fn receive_container_restart_request(
+        &self,
+    ) -> Result<Message<ContainerRestartRequest>, flume::RecvError> {
+        let received = self.restart.receiver.recv_async().await?;
+
+        tracing::debug!(%received.identifier, "received container restart request");
+
+        Ok(received)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use color_eyre::eyre::Result;
 
+    use crate::docker::models::ContainerId;
     use crate::ipc::MessageBus;
 
     #[tokio::test]
@@ -132,4 +176,18 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn can_send_and_receive_container_restart_requests() -> Result<()> {
+        let message_bus = MessageBus::new();
+        let id = ContainerId::random();
+
+        let sent = message_bus.send_container_restart_request(id.clone())?;
+        let received = message_bus.receive_container_restart_request().await?;
+
+        assert_eq!(sent, received.identifier);
+        assert_eq!(received.into_content().id, id);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,585 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, eyre, Result};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, RwLock};
+use tokio::time::MissedTickBehavior;
+
+use crate::common::Container;
+use crate::config::{Config, Job, JobSchedule};
+use crate::docker::api::{
+    remove_container_and_volumes, start_job_container, CONFIG_HASH_LABEL, JOB_LABEL,
+};
+use crate::docker::client::DockerClient;
+use crate::docker::models::ContainerId;
+
+/// How often to check for jobs that are due and runs that have finished.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of lines of output to record from each run.
+const LOG_LINES: usize = 100;
+
+/// The most recent run of a job.
+#[derive(Clone, Debug)]
+pub struct JobRun {
+    pub container: ContainerId,
+    pub started: DateTime<Utc>,
+    /// How the run ended, once the container has exited.
+    pub outcome: Option<JobOutcome>,
+}
+
+#[derive(Clone, Debug)]
+pub struct JobOutcome {
+    pub finished: DateTime<Utc>,
+    pub exit_code: i64,
+    pub logs: String,
+}
+
+/// The most recent run of each job, shared between the runner and the admin API.
+#[derive(Debug, Default)]
+pub struct JobRuns {
+    runs: HashMap<String, JobRun>,
+}
+
+impl JobRuns {
+    pub fn record_started(&mut self, job: &str, container: ContainerId) {
+        self.runs.insert(
+            job.to_owned(),
+            JobRun {
+                container,
+                started: Utc::now(),
+                outcome: None,
+            },
+        );
+    }
+
+    /// Records how a run of a job ended, including runs started before f2 was.
+    pub fn record_finished(
+        &mut self,
+        job: &str,
+        container: &ContainerId,
+        exit_code: i64,
+        logs: String,
+    ) {
+        let now = Utc::now();
+        let started = self
+            .runs
+            .get(job)
+            .filter(|run| run.container == *container)
+            .map_or(now, |run| run.started);
+
+        self.runs.insert(
+            job.to_owned(),
+            JobRun {
+                container: container.clone(),
+                started,
+                outcome: Some(JobOutcome {
+                    finished: now,
+                    exit_code,
+                    logs,
+                }),
+            },
+        );
+    }
+
+    pub fn get(&self, job: &str) -> Option<&JobRun> {
+        self.runs.get(job)
+    }
+}
+
+/// What the runner knows about previous and current runs.
+#[derive(Debug)]
+struct RunState {
+    /// When schedules were last checked, so that each cron occurrence only runs once.
+    last_checked: DateTime<Utc>,
+    /// The hash of the definition each job last ran with.
+    deployed: HashMap<String, String>,
+    /// The container of each run that has not exited yet.
+    running: HashMap<String, ContainerId>,
+}
+
+/// Starts a container for each run of the configured jobs, recording the exit code and logs once
+/// it exits. The container of the latest run is kept until the next so it can be inspected.
+///
+/// Cron jobs are started by the runner itself, while on-deploy jobs are run by the reconciler
+/// before it rolls out any services.
+#[derive(Debug)]
+pub struct JobRunner<C> {
+    runs: Arc<RwLock<JobRuns>>,
+    config: Arc<ArcSwap<Config>>,
+    docker_client: C,
+    /// The state of the runs, once those left by a previous instance have been adopted.
+    state: Mutex<Option<RunState>>,
+}
+
+impl<C: DockerClient> JobRunner<C> {
+    pub fn new(runs: Arc<RwLock<JobRuns>>, config: Arc<ArcSwap<Config>>, docker_client: C) -> Self {
+        Self {
+            runs,
+            config,
+            docker_client,
+            state: Mutex::new(None),
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            match self.lock_state().await {
+                Ok(mut state) => self.check(&mut state, Utc::now()).await,
+                Err(error) => tracing::warn!(%error, "failed to adopt job runs, retrying"),
+            }
+        }
+    }
+
+    /// Runs each on-deploy job whose definition has not run yet, one at a time, waiting for the
+    /// run to exit so that services are only rolled out once their migrations have applied. A run
+    /// that fails is returned as an error and started again by the next call.
+    pub async fn run_deploy_jobs(&self, config: &Config) -> Result<()> {
+        let mut jobs: Vec<_> = config
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.schedule == JobSchedule::OnDeploy)
+            .collect();
+
+        jobs.sort_by_key(|(name, _)| *name);
+
+        for (name, job) in jobs {
+            let container = Container::from(job);
+            let hash = container.config_hash(&job.tag);
+
+            if self.lock_state().await?.deployed.get(name) == Some(&hash) {
+                continue;
+            }
+
+            // A run left going by a previous instance finishes before the next one starts
+            self.wait_for_run(name).await?;
+
+            let id = self.start_run(name, job, &container, config).await?;
+            self.lock_state()
+                .await?
+                .running
+                .insert(name.clone(), id.clone());
+            self.wait_for_run(name).await?;
+
+            let exit_code = self
+                .runs
+                .read()
+                .await
+                .get(name)
+                .filter(|run| run.container == id)
+                .and_then(|run| run.outcome.as_ref())
+                .map(|outcome| outcome.exit_code);
+
+            match exit_code {
+                Some(0) => {}
+                Some(exit_code) => bail!("job {name} failed with exit code {exit_code}"),
+                None => bail!("job {name} was removed before it finished"),
+            }
+
+            self.lock_state().await?.deployed.insert(name.clone(), hash);
+        }
+
+        Ok(())
+    }
+
+    /// Locks the state of the runs, adopting those left by a previous instance of f2 if that has
+    /// not succeeded yet.
+    async fn lock_state(&self) -> Result<MappedMutexGuard<'_, RunState>> {
+        let mut state = self.state.lock().await;
+
+        if state.is_none() {
+            *state = Some(self.adopt_runs(Utc::now()).await?);
+        }
+
+        MutexGuard::try_map(state, Option::as_mut).map_err(|_| eyre!("job runs were not adopted"))
+    }
+
+    /// Waits until the current run of a job, if there is one, has exited and been recorded.
+    async fn wait_for_run(&self, name: &str) -> Result<()> {
+        loop {
+            {
+                let mut state = self.lock_state().await?;
+                self.collect_finished_runs(&mut state).await;
+
+                if !state.running.contains_key(name) {
+                    return Ok(());
+                }
+            }
+
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Picks up the containers left by a previous instance of f2, so that definitions which have
+    /// already run are not run again and runs that are still going are waited on.
+    async fn adopt_runs(&self, now: DateTime<Utc>) -> Result<RunState> {
+        let mut state = RunState {
+            last_checked: now,
+            deployed: HashMap::new(),
+            running: HashMap::new(),
+        };
+
+        for summary in self.docker_client.list_containers(JOB_LABEL).await? {
+            let Some(job) = summary.labels.get(JOB_LABEL) else {
+                continue;
+            };
+
+            if let Some(hash) = summary.labels.get(CONFIG_HASH_LABEL) {
+                state.deployed.insert(job.clone(), hash.clone());
+            }
+
+            if summary.state == "running" {
+                tracing::info!(%job, id = %summary.id, "adopting a run that is still going");
+                state.running.insert(job.clone(), summary.id);
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Records any runs that have finished and starts the cron jobs that are due, skipping jobs
+    /// whose previous run is still going.
+    async fn check(&self, state: &mut RunState, now: DateTime<Utc>) {
+        self.collect_finished_runs(state).await;
+
+        let config = self.config.load();
+
+        for (name, job) in &config.jobs {
+            let JobSchedule::Cron { expression } = &job.schedule else {
+                continue;
+            };
+
+            if state.running.contains_key(name) {
+                continue;
+            }
+
+            let due = expression
+                .find_next_occurrence(&state.last_checked, false)
+                .is_ok_and(|next| next <= now);
+
+            if !due {
+                continue;
+            }
+
+            let container = Container::from(job);
+
+            match self.start_run(name, job, &container, &config).await {
+                Ok(id) => {
+                    state.running.insert(name.clone(), id);
+                }
+                Err(error) => tracing::error!(%name, %error, "failed to start a run of a job"),
+            }
+        }
+
+        state.last_checked = now;
+    }
+
+    #[tracing::instrument(skip(self, job, container, config))]
+    async fn start_run(
+        &self,
+        name: &str,
+        job: &Job,
+        container: &Container,
+        config: &Config,
+    ) -> Result<ContainerId> {
+        for summary in self.docker_client.list_containers(JOB_LABEL).await? {
+            if summary.labels.get(JOB_LABEL).is_some_and(|job| job == name) {
+                remove_container_and_volumes(&self.docker_client, &summary.id).await?;
+            }
+        }
+
+        let private_key = config.get_private_key().await?;
+        let id = start_job_container(
+            &self.docker_client,
+            name,
+            container,
+            &job.tag,
+            private_key.as_ref(),
+        )
+        .await?;
+
+        self.runs.write().await.record_started(name, id.clone());
+
+        tracing::info!(%id, "started a run of the job");
+
+        Ok(id)
+    }
+
+    /// Records the exit code and logs of each run whose container has exited.
+    async fn collect_finished_runs(&self, state: &mut RunState) {
+        let mut finished = Vec::new();
+
+        for (name, id) in &state.running {
+            match self.docker_client.get_container_state(id).await {
+                Ok(Some(container_state)) if container_state.running => {}
+                Ok(Some(container_state)) => {
+                    finished.push((name.clone(), id.clone(), Some(container_state.exit_code)));
+                }
+                Ok(None) => {
+                    tracing::warn!(%name, %id, "job container was removed before it finished");
+                    finished.push((name.clone(), id.clone(), None));
+                }
+                Err(error) => {
+                    tracing::warn!(%name, %id, %error, "failed to check the state of a job container");
+                }
+            }
+        }
+
+        for (name, id, exit_code) in finished {
+            state.running.remove(&name);
+
+            let Some(exit_code) = exit_code else {
+                continue;
+            };
+
+            let logs = self
+                .docker_client
+                .get_container_logs(&id, LOG_LINES)
+                .await
+                .unwrap_or_else(|error| {
+                    tracing::warn!(%name, %id, %error, "failed to fetch the logs of a job container");
+                    String::new()
+                });
+
+            if exit_code == 0 {
+                tracing::info!(%name, %id, "job finished successfully");
+            } else {
+                tracing::warn!(%name, %id, %exit_code, %logs, "job failed");
+            }
+
+            self.runs
+                .write()
+                .await
+                .record_finished(&name, &id, exit_code, logs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use chrono::{DateTime, Utc};
+    use color_eyre::eyre::Result;
+
+    use crate::common::Container;
+    use crate::config::{AlbConfig, Config, Job, JobSchedule, Scheme};
+    use crate::docker::api::JOB_LABEL;
+    use crate::jobs::JobRunner;
+    use crate::reconciler::tests::FakeDockerClient;
+
+    fn create_runner(
+        docker_client: &FakeDockerClient,
+        jobs: HashMap<String, Job>,
+    ) -> JobRunner<FakeDockerClient> {
+        let config = Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::new(),
+                tls: None,
+                mtls: None,
+                acme: None,
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs,
+            services: HashMap::new(),
+        };
+
+        JobRunner::new(
+            Arc::default(),
+            Arc::new(ArcSwap::from_pointee(config)),
+            docker_client.clone(),
+        )
+    }
+
+    fn create_job(tag: &str, schedule: JobSchedule) -> Job {
+        Job {
+            image: String::from("myapp"),
+            tag: tag.to_owned(),
+            schedule,
+            environment: HashMap::new(),
+            volumes: HashMap::new(),
+            args: vec![String::from("migrate")],
+            container: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn deploy_jobs_run_once_for_each_definition() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let jobs = HashMap::from([(
+            String::from("migrate"),
+            create_job("v1", JobSchedule::OnDeploy),
+        )]);
+
+        let runner = create_runner(&docker_client, jobs.clone());
+        let config = runner.config.load_full();
+
+        let finished_job = async {
+            let id = docker_client.wait_for_container(JOB_LABEL).await;
+            docker_client
+                .finish_container(&id, 0, "applied 3 migrations\n")
+                .await;
+        };
+
+        let (result, _) = tokio::join!(runner.run_deploy_jobs(&config), finished_job);
+        result?;
+
+        let first = docker_client.container_ids().await;
+        assert_eq!(first.len(), 1);
+
+        {
+            let runs = runner.runs.read().await;
+            let outcome = runs.get("migrate").and_then(|run| run.outcome.as_ref());
+
+            assert_eq!(outcome.map(|outcome| outcome.exit_code), Some(0));
+            assert_eq!(
+                outcome.map(|outcome| outcome.logs.as_str()),
+                Some("applied 3 migrations\n")
+            );
+        }
+
+        // The finished run is not repeated, even by a new instance
+        runner.run_deploy_jobs(&config).await?;
+
+        let adopted = create_runner(&docker_client, jobs);
+        adopted.run_deploy_jobs(&config).await?;
+
+        assert_eq!(docker_client.container_ids().await, first);
+
+        // A new definition runs again, replacing the container of the previous run
+        let config = Config {
+            jobs: HashMap::from([(
+                String::from("migrate"),
+                create_job("v2", JobSchedule::OnDeploy),
+            )]),
+            ..Config::clone(&config)
+        };
+
+        let failed_job = async {
+            let id = docker_client.wait_for_container(JOB_LABEL).await;
+            docker_client.finish_container(&id, 1, "").await;
+        };
+
+        let (result, _) = tokio::join!(runner.run_deploy_jobs(&config), failed_job);
+        assert!(result.is_err());
+
+        let second = docker_client.container_ids().await;
+
+        assert_eq!(second.len(), 1);
+        assert_ne!(second, first);
+
+        // Failed runs are started again rather than being remembered
+        let finished_job = async {
+            let id = docker_client.wait_for_container(JOB_LABEL).await;
+            docker_client.finish_container(&id, 0, "").await;
+        };
+
+        let (result, _) = tokio::join!(runner.run_deploy_jobs(&config), finished_job);
+        result?;
+
+        let third = docker_client.container_ids().await;
+
+        assert_eq!(third.len(), 1);
+        assert_ne!(third, second);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adoption_is_retried_after_a_failure() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let jobs = HashMap::from([(
+            String::from("migrate"),
+            create_job("v1", JobSchedule::OnDeploy),
+        )]);
+
+        // A run left going by a previous instance
+        let previous = create_runner(&docker_client, jobs.clone());
+        let config = previous.config.load_full();
+        let job = &config.jobs["migrate"];
+        let id = previous
+            .start_run("migrate", job, &Container::from(job), &config)
+            .await?;
+
+        let runner = create_runner(&docker_client, jobs);
+        docker_client.fail_listings(1).await;
+
+        assert!(runner.lock_state().await.is_err());
+        assert_eq!(runner.lock_state().await?.running.get("migrate"), Some(&id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cron_jobs_run_when_their_schedule_is_due() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let schedule = JobSchedule::Cron {
+            expression: String::from("* * * * *").try_into()?,
+        };
+
+        let jobs = HashMap::from([(String::from("report"), create_job("v1", schedule))]);
+
+        let runner = create_runner(&docker_client, jobs);
+        let mut state = runner
+            .adopt_runs("2026-01-01T12:00:30Z".parse::<DateTime<Utc>>()?)
+            .await?;
+
+        runner
+            .check(&mut state, "2026-01-01T12:00:45Z".parse()?)
+            .await;
+
+        assert!(docker_client.container_ids().await.is_empty());
+
+        runner
+            .check(&mut state, "2026-01-01T12:01:00Z".parse()?)
+            .await;
+
+        let ids = docker_client.container_ids().await;
+        assert_eq!(ids.len(), 1);
+
+        // Runs do not overlap, so occurrences while the previous run is going are skipped
+        runner
+            .check(&mut state, "2026-01-01T12:02:00Z".parse()?)
+            .await;
+
+        assert_eq!(docker_client.container_ids().await, ids);
+
+        docker_client.finish_container(&ids[0], 2, "failed").await;
+
+        runner
+            .check(&mut state, "2026-01-01T12:02:30Z".parse()?)
+            .await;
+
+        let runs = runner.runs.read().await;
+        let run = runs.get("report");
+
+        assert_eq!(run.map(|run| &run.container), Some(&ids[0]));
+        assert_eq!(
+            run.and_then(|run| run.outcome.as_ref())
+                .map(|outcome| outcome.exit_code),
+            Some(2)
+        );
+
+        Ok(())
+    }
+}
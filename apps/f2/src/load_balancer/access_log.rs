@@ -0,0 +1,353 @@
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::{Request, Response};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::docker::models::ContainerId;
+use crate::load_balancer::proxy::extract_host;
+use crate::load_balancer::{ClientAddr, ClientCommonName, Upstream};
+
+/// The number of lines to queue for the writer before dropping new entries, so that a slow disk
+/// or stdout cannot hold up requests.
+const QUEUED_LINES: usize = 4096;
+
+/// A single proxied HTTP request or TLS session.
+#[derive(Debug)]
+pub struct AccessLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub protocol: &'static str,
+    pub client_ip: Option<IpAddr>,
+    pub common_name: Option<String>,
+    pub host: String,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    pub upstream: Option<ContainerId>,
+    pub latency_ms: u128,
+    /// The number of bytes sent back to the client.
+    pub bytes: u64,
+}
+
+impl AccessLogEntry {
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "timestamp": self.timestamp.to_rfc3339(),
+            "protocol": self.protocol,
+            "client_ip": self.client_ip,
+            "common_name": self.common_name,
+            "host": self.host,
+            "method": self.method,
+            "path": self.path,
+            "status": self.status,
+            "upstream": self.upstream.as_ref().map(|id| &id.0),
+            "latency_ms": self.latency_ms,
+            "bytes": self.bytes,
+        })
+        .to_string()
+    }
+
+    fn to_combined(&self) -> String {
+        fn or_dash<T: ToString>(value: Option<T>) -> String {
+            value.map_or_else(|| String::from("-"), |value| value.to_string())
+        }
+
+        let request = match (&self.method, &self.path) {
+            (Some(method), Some(path)) => format!("{method} {path}"),
+            _ => self.protocol.to_uppercase(),
+        };
+
+        format!(
+            "{} - {} [{}] \"{request}\" {} {} \"{}\" {} {}ms",
+            or_dash(self.client_ip),
+            or_dash(self.common_name.as_deref()),
+            self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+            or_dash(self.status),
+            self.bytes,
+            self.host,
+            or_dash(self.upstream.as_ref()),
+            self.latency_ms,
+        )
+    }
+}
+
+/// Writes access log entries in the configured format to stdout or a file. Entries are formatted
+/// on the request path and written by a separate task.
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    lines: Sender<String>,
+}
+
+impl AccessLogger {
+    pub fn new(config: &AccessLogConfig) -> Result<Self> {
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = match &config.path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Box::new(tokio::fs::File::from_std(file))
+            }
+            None => Box::new(tokio::io::stdout()),
+        };
+
+        let (lines, receiver) = mpsc::channel(QUEUED_LINES);
+
+        tokio::spawn(write_lines(writer, receiver));
+
+        Ok(Self {
+            format: config.format,
+            lines,
+        })
+    }
+
+    pub fn record(&self, entry: &AccessLogEntry) {
+        let line = match self.format {
+            AccessLogFormat::Json => entry.to_json(),
+            AccessLogFormat::Combined => entry.to_combined(),
+        };
+
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("access log writer is behind, dropping an entry");
+            }
+            Err(TrySendError::Closed(_)) => {
+                tracing::warn!("access log writer has stopped, dropping an entry");
+            }
+        }
+    }
+}
+
+/// Writes lines to the access log until every logger has been dropped, flushing whenever there
+/// are none waiting.
+async fn write_lines(mut writer: Box<dyn AsyncWrite + Send + Unpin>, mut lines: Receiver<String>) {
+    while let Some(mut line) = lines.recv().await {
+        line.push('\n');
+
+        if let Err(error) = writer.write_all(line.as_bytes()).await {
+            tracing::warn!(%error, "failed to write to the access log");
+        }
+
+        if lines.is_empty() {
+            if let Err(error) = writer.flush().await {
+                tracing::warn!(%error, "failed to flush the access log");
+            }
+        }
+    }
+}
+
+/// Handles a request, recording it in the access log once the response body has been sent to the
+/// client, or straight away if handling it failed.
+pub async fn log_request<B, F, Fut>(
+    logger: Option<Arc<AccessLogger>>,
+    req: Request<B>,
+    handle: F,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+where
+    F: FnOnce(Request<B>) -> Fut,
+    Fut: Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>>>,
+{
+    let Some(logger) = logger else {
+        return handle(req).await;
+    };
+
+    let started = Instant::now();
+
+    let entry = AccessLogEntry {
+        timestamp: Utc::now(),
+        protocol: "http",
+        client_ip: req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip()),
+        common_name: req
+            .extensions()
+            .get::<ClientCommonName>()
+            .map(|common_name| common_name.0.clone()),
+        host: extract_host(&req).unwrap_or_default().to_owned(),
+        method: Some(req.method().to_string()),
+        path: Some(req.uri().path().to_owned()),
+        status: None,
+        upstream: None,
+        latency_ms: 0,
+        bytes: 0,
+    };
+
+    match handle(req).await {
+        Ok(response) => {
+            let entry = AccessLogEntry {
+                status: Some(response.status().as_u16()),
+                upstream: response
+                    .extensions()
+                    .get::<Upstream>()
+                    .map(|upstream| upstream.0.clone()),
+                ..entry
+            };
+
+            Ok(response.map(|body| {
+                LoggedBody {
+                    inner: body,
+                    logger,
+                    entry,
+                    started,
+                }
+                .boxed()
+            }))
+        }
+        Err(e) => {
+            logger.record(&AccessLogEntry {
+                latency_ms: started.elapsed().as_millis(),
+                ..entry
+            });
+
+            Err(e)
+        }
+    }
+}
+
+/// A response body that counts the bytes sent through it and records the access log entry for the
+/// request once it is dropped.
+struct LoggedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    logger: Arc<AccessLogger>,
+    entry: AccessLogEntry,
+    started: Instant,
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.entry.bytes += data.len() as u64;
+            }
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.latency_ms = self.started.elapsed().as_millis();
+        self.logger.record(&self.entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use color_eyre::eyre::Result;
+
+    use crate::config::{AccessLogConfig, AccessLogFormat};
+    use crate::docker::models::ContainerId;
+    use crate::load_balancer::access_log::{AccessLogEntry, AccessLogger};
+
+    fn create_entry() -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: Utc.with_ymd_and_hms(2025, 3, 14, 9, 26, 53).unwrap(),
+            protocol: "http",
+            client_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            common_name: None,
+            host: String::from("example.com"),
+            method: Some(String::from("GET")),
+            path: Some(String::from("/health")),
+            status: Some(200),
+            upstream: Some(ContainerId(String::from("6cd915f16ab3"))),
+            latency_ms: 12,
+            bytes: 512,
+        }
+    }
+
+    #[test]
+    fn entries_can_be_formatted_as_json() -> Result<()> {
+        let json: serde_json::Value = serde_json::from_str(&create_entry().to_json())?;
+
+        assert_eq!(json["client_ip"], "10.0.0.1");
+        assert_eq!(json["common_name"], serde_json::Value::Null);
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["upstream"], "6cd915f16ab3");
+        assert_eq!(json["bytes"], 512);
+
+        Ok(())
+    }
+
+    #[test]
+    fn entries_can_be_formatted_like_the_combined_log_format() {
+        let http = create_entry();
+        let tls = AccessLogEntry {
+            protocol: "tls",
+            common_name: Some(String::from("client.example.com")),
+            method: None,
+            path: None,
+            status: None,
+            ..create_entry()
+        };
+
+        assert_eq!(
+            http.to_combined(),
+            r#"10.0.0.1 - - [14/Mar/2025:09:26:53 +0000] "GET /health" 200 512 "example.com" 6cd915f16ab3 12ms"#
+        );
+        assert_eq!(
+            tls.to_combined(),
+            r#"10.0.0.1 - client.example.com [14/Mar/2025:09:26:53 +0000] "TLS" - 512 "example.com" 6cd915f16ab3 12ms"#
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_to_files() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("access.log");
+
+        let logger = AccessLogger::new(&AccessLogConfig {
+            format: AccessLogFormat::Json,
+            path: Some(path.clone()),
+        })?;
+
+        logger.record(&create_entry());
+        logger.record(&create_entry());
+
+        // The entries are written in the background
+        let mut lines = 0;
+
+        for _ in 0..50 {
+            lines = tokio::fs::read_to_string(&path).await?.lines().count();
+
+            if lines == 2 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(lines, 2);
+
+        Ok(())
+    }
+}
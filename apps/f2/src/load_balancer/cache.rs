@@ -0,0 +1,579 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use http::header::{
+    HeaderName, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, COOKIE,
+    SET_COOKIE, VARY,
+};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::body::Bytes;
+use tokio::time::Instant;
+
+use crate::config::Route;
+use crate::load_balancer::compression::{self, Encoding};
+use crate::load_balancer::{ClientCommonName, ClientScheme};
+use crate::service_registry::strip_port;
+
+/// The total size of the bodies to keep before evicting the responses closest to expiring.
+const MAX_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The largest body to cache, so that a single response cannot take over the cache.
+const MAX_ENTRY_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Identifies a cached response by the scheme, host, path and query of the request, where the host
+/// does not include the port since it can be left out or given explicitly for the same route.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// Gets the key for a request if the route caches responses and the request can be answered
+    /// from the cache. Requests that identify the client, through credentials, cookies or a client
+    /// certificate, could get a response meant only for them, so they always go downstream.
+    pub fn for_request<B>(route: &Route, host: &str, req: &Request<B>) -> Option<Self> {
+        let identifies_client = req.headers().contains_key(AUTHORIZATION)
+            || req.headers().contains_key(COOKIE)
+            || req.extensions().get::<ClientCommonName>().is_some();
+
+        if !route.cache || req.method() != Method::GET || identifies_client {
+            return None;
+        }
+
+        let scheme = req
+            .extensions()
+            .get::<ClientScheme>()
+            .copied()
+            .unwrap_or(ClientScheme::Http);
+
+        let host = strip_port(host).to_ascii_lowercase();
+        let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+
+        Some(Self(format!(
+            "{}://{host}{path_and_query}",
+            scheme.as_str()
+        )))
+    }
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    headers: HeaderMap,
+    body: Bytes,
+    /// The body compressed with each encoding clients have asked for so far, so that it is only
+    /// compressed once for each of them.
+    encoded: HashMap<Encoding, Bytes>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl CachedResponse {
+    fn size(&self) -> usize {
+        self.body.len() + self.encoded.values().map(Bytes::len).sum::<usize>()
+    }
+}
+
+/// A response from the cache, which is either already compressed for the client or still needs
+/// compressing.
+#[derive(Debug)]
+pub enum CacheHit {
+    Identity(Response<Bytes>),
+    Encoded(Response<Bytes>),
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    responses: HashMap<CacheKey, CachedResponse>,
+    size: usize,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(response) = self.responses.remove(key) {
+            self.size -= response.size();
+        }
+    }
+
+    /// Removes expired responses, followed by those closest to expiring, until `needed` more bytes
+    /// fit within the capacity.
+    fn make_room(&mut self, needed: usize, capacity: usize, now: Instant) {
+        if self.size + needed <= capacity {
+            return;
+        }
+
+        self.responses.retain(|_, response| response.expires > now);
+        self.size = self.responses.values().map(CachedResponse::size).sum();
+
+        while self.size + needed > capacity {
+            let Some(key) = self
+                .responses
+                .iter()
+                .min_by_key(|(_, response)| response.expires)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            self.remove(&key);
+        }
+    }
+}
+
+/// Keeps responses from routes that opt into caching in memory, for as long as their
+/// `Cache-Control` header allows and within a bound on the total size of their bodies.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(MAX_CACHE_BYTES)
+    }
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Gets the cached response for a request, if there is one that is still fresh, preferring
+    /// the body already compressed with the encoding negotiated for the client.
+    pub fn get(&self, key: &CacheKey, encoding: Option<Encoding>) -> Option<CacheHit> {
+        self.get_at(key, encoding, Instant::now())
+    }
+
+    /// Stores the headers and body of a response for as long as it stays fresh.
+    pub fn insert(&self, key: CacheKey, headers: HeaderMap, body: Bytes, lifetime: Duration) {
+        self.insert_at(key, headers, body, lifetime, Instant::now());
+    }
+
+    /// Stores the body of a cached response compressed with an encoding, if the response is still
+    /// cached and there is room for it without evicting anything.
+    pub fn insert_encoded(&self, key: &CacheKey, encoding: Encoding, body: Bytes) {
+        let mut guard = self.entries.lock().unwrap();
+        let entries = &mut *guard;
+
+        if entries.size + body.len() > self.capacity {
+            return;
+        }
+
+        let Some(cached) = entries.responses.get_mut(key) else {
+            return;
+        };
+
+        if let Entry::Vacant(entry) = cached.encoded.entry(encoding) {
+            entries.size += body.len();
+            entry.insert(body);
+        }
+    }
+
+    fn get_at(&self, key: &CacheKey, encoding: Option<Encoding>, now: Instant) -> Option<CacheHit> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached = entries.responses.get(key)?;
+
+        if cached.expires <= now {
+            entries.remove(key);
+            return None;
+        }
+
+        let mut headers = cached.headers.clone();
+
+        let age = now.saturating_duration_since(cached.stored).as_secs();
+        headers.insert(AGE, HeaderValue::from(age));
+
+        let encoded =
+            encoding.and_then(|encoding| Some((encoding, cached.encoded.get(&encoding)?)));
+
+        let Some((encoding, body)) = encoded else {
+            let mut response = Response::new(cached.body.clone());
+            *response.headers_mut() = headers;
+
+            return Some(CacheHit::Identity(response));
+        };
+
+        compression::add_vary(&mut headers);
+        compression::mark_encoded(&mut headers, encoding, body.len());
+
+        let mut response = Response::new(body.clone());
+        *response.headers_mut() = headers;
+
+        Some(CacheHit::Encoded(response))
+    }
+
+    fn insert_at(
+        &self,
+        key: CacheKey,
+        headers: HeaderMap,
+        body: Bytes,
+        lifetime: Duration,
+        now: Instant,
+    ) {
+        let Some(expires) = now.checked_add(lifetime) else {
+            return;
+        };
+
+        if body.len() > self.capacity {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.remove(&key);
+        entries.make_room(body.len(), self.capacity, now);
+        entries.size += body.len();
+
+        let response = CachedResponse {
+            headers,
+            body,
+            encoded: HashMap::new(),
+            stored: now,
+            expires,
+        };
+
+        entries.responses.insert(key, response);
+    }
+}
+
+/// Gets how long a response can be served from the cache, or `None` if it must not be stored.
+///
+/// Only complete, uncompressed responses with a known size are stored, since the key does not
+/// account for the headers a response varies by and compression is applied after the cache.
+pub fn storable_lifetime(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::OK
+        || headers.contains_key(SET_COOKIE)
+        || headers.contains_key(CONTENT_ENCODING)
+    {
+        return None;
+    }
+
+    let varies_by_other_headers =
+        header_tokens(headers, VARY).any(|header| !header.eq_ignore_ascii_case("accept-encoding"));
+
+    if varies_by_other_headers {
+        return None;
+    }
+
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if length.is_none_or(|length| length > MAX_ENTRY_BYTES) {
+        return None;
+    }
+
+    let mut max_age = None;
+    let mut shared_max_age = None;
+
+    for directive in header_tokens(headers, CACHE_CONTROL) {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive, None),
+        };
+
+        let seconds = value.and_then(|value| value.parse::<u64>().ok());
+
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = seconds,
+            "s-maxage" => shared_max_age = seconds,
+            _ => {}
+        }
+    }
+
+    // The shared lifetime applies to caches like this one, so it takes precedence
+    shared_max_age
+        .or(max_age)
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+/// Splits the comma separated values of a header into trimmed tokens.
+pub fn header_tokens(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use color_eyre::eyre::{eyre, Result};
+    use http::header::{
+        AGE, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, COOKIE, SET_COOKIE, VARY,
+    };
+    use http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+    use hyper::body::Bytes;
+    use tokio::time::Instant;
+
+    use crate::config::Route;
+    use crate::load_balancer::cache::{storable_lifetime, CacheHit, CacheKey, ResponseCache};
+    use crate::load_balancer::compression::Encoding;
+    use crate::load_balancer::{ClientCommonName, ClientScheme};
+
+    fn create_headers(cache_control: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([
+            (CACHE_CONTROL, HeaderValue::from_static(cache_control)),
+            (CONTENT_LENGTH, HeaderValue::from_static("5")),
+        ])
+    }
+
+    fn create_key(path: &str) -> CacheKey {
+        CacheKey(format!("http://example.com{path}"))
+    }
+
+    #[test]
+    fn only_routes_that_opt_in_are_cached() -> Result<()> {
+        let route = Route {
+            host: String::from("example.com"),
+            cache: true,
+            ..Default::default()
+        };
+
+        let get = Request::builder()
+            .uri("http://example.com/app.css?v=2")
+            .body(())?;
+
+        assert_eq!(
+            CacheKey::for_request(&route, "example.com", &get),
+            Some(create_key("/app.css?v=2"))
+        );
+
+        let post = Request::builder()
+            .method(Method::POST)
+            .uri("http://example.com/app.css")
+            .body(())?;
+
+        assert_eq!(CacheKey::for_request(&route, "example.com", &post), None);
+
+        let opted_out = Route {
+            cache: false,
+            ..route
+        };
+
+        assert_eq!(CacheKey::for_request(&opted_out, "example.com", &get), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keys_include_the_scheme_but_not_the_port() -> Result<()> {
+        let route = Route {
+            host: String::from("example.com"),
+            cache: true,
+            ..Default::default()
+        };
+
+        let mut https = Request::builder()
+            .uri("https://example.com/app.css")
+            .body(())?;
+
+        https.extensions_mut().insert(ClientScheme::Https);
+
+        let plaintext = Request::builder()
+            .uri("http://example.com/app.css")
+            .body(())?;
+
+        let https_key = CacheKey::for_request(&route, "Example.com:443", &https);
+        let plaintext_key = CacheKey::for_request(&route, "example.com:80", &plaintext);
+
+        assert_eq!(
+            https_key,
+            Some(CacheKey(String::from("https://example.com/app.css")))
+        );
+        assert_eq!(plaintext_key, Some(create_key("/app.css")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn requests_that_identify_the_client_are_not_cached() -> Result<()> {
+        let route = Route {
+            host: String::from("example.com"),
+            cache: true,
+            ..Default::default()
+        };
+
+        let with_cookie = Request::builder()
+            .uri("http://example.com/account")
+            .header(COOKIE, "session=1")
+            .body(())?;
+
+        assert_eq!(
+            CacheKey::for_request(&route, "example.com", &with_cookie),
+            None
+        );
+
+        let mut with_certificate = Request::builder()
+            .uri("http://example.com/account")
+            .body(())?;
+
+        with_certificate
+            .extensions_mut()
+            .insert(ClientCommonName(String::from("client.example.com")));
+
+        assert_eq!(
+            CacheKey::for_request(&route, "example.com", &with_certificate),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lifetimes_come_from_cache_control() {
+        let lifetime =
+            |cache_control| storable_lifetime(StatusCode::OK, &create_headers(cache_control));
+
+        assert_eq!(lifetime("max-age=60"), Some(Duration::from_secs(60)));
+        assert_eq!(
+            lifetime("public, max-age=60, s-maxage=300"),
+            Some(Duration::from_secs(300))
+        );
+
+        assert_eq!(lifetime("max-age=0"), None);
+        assert_eq!(lifetime("no-store"), None);
+        assert_eq!(lifetime("private, max-age=60"), None);
+        assert_eq!(lifetime("public"), None);
+
+        let not_found = storable_lifetime(StatusCode::NOT_FOUND, &create_headers("max-age=60"));
+        assert_eq!(not_found, None);
+
+        let mut with_cookie = create_headers("max-age=60");
+        with_cookie.insert(SET_COOKIE, HeaderValue::from_static("session=1"));
+        assert_eq!(storable_lifetime(StatusCode::OK, &with_cookie), None);
+
+        let mut varies = create_headers("max-age=60");
+        varies.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+        assert!(storable_lifetime(StatusCode::OK, &varies).is_some());
+
+        varies.insert(VARY, HeaderValue::from_static("Accept-Encoding, Cookie"));
+        assert_eq!(storable_lifetime(StatusCode::OK, &varies), None);
+    }
+
+    #[test]
+    fn responses_expire_after_their_lifetime() -> Result<()> {
+        let cache = ResponseCache::default();
+        let key = create_key("/app.css");
+        let now = Instant::now();
+
+        cache.insert_at(
+            key.clone(),
+            create_headers("max-age=60"),
+            Bytes::from("body"),
+            Duration::from_secs(60),
+            now,
+        );
+
+        let Some(CacheHit::Identity(response)) =
+            cache.get_at(&key, None, now + Duration::from_secs(30))
+        else {
+            return Err(eyre!("expected a cached response"));
+        };
+
+        assert_eq!(response.body(), "body");
+        assert_eq!(response.headers()[AGE], "30");
+
+        assert!(cache
+            .get_at(&key, None, now + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(cache.entries.lock().unwrap().size, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn responses_closest_to_expiring_are_evicted_when_full() {
+        let cache = ResponseCache::new(10);
+        let now = Instant::now();
+
+        let insert = |path, lifetime| {
+            let body = Bytes::from("12345");
+            let lifetime = Duration::from_secs(lifetime);
+
+            cache.insert_at(create_key(path), HeaderMap::new(), body, lifetime, now);
+        };
+
+        insert("/short", 10);
+        insert("/long", 60);
+        insert("/new", 30);
+
+        assert!(cache.get_at(&create_key("/short"), None, now).is_none());
+        assert!(cache.get_at(&create_key("/long"), None, now).is_some());
+        assert!(cache.get_at(&create_key("/new"), None, now).is_some());
+
+        // Bodies larger than the whole cache are never stored
+        let large = Bytes::from("x".repeat(11));
+        cache.insert_at(
+            create_key("/large"),
+            HeaderMap::new(),
+            large,
+            Duration::from_secs(60),
+            now,
+        );
+
+        assert!(cache.get_at(&create_key("/large"), None, now).is_none());
+        assert!(cache.get_at(&create_key("/long"), None, now).is_some());
+    }
+
+    #[test]
+    fn bodies_are_kept_for_each_encoding() -> Result<()> {
+        let cache = ResponseCache::new(20);
+        let key = create_key("/app.css");
+        let now = Instant::now();
+
+        cache.insert_at(
+            key.clone(),
+            create_headers("max-age=60"),
+            Bytes::from("plain"),
+            Duration::from_secs(60),
+            now,
+        );
+
+        // Until it has been compressed, the body is given back as it is
+        let hit = cache.get_at(&key, Some(Encoding::Gzip), now);
+        assert!(matches!(hit, Some(CacheHit::Identity(_))));
+
+        cache.insert_encoded(&key, Encoding::Gzip, Bytes::from("gz"));
+
+        let Some(CacheHit::Encoded(response)) = cache.get_at(&key, Some(Encoding::Gzip), now)
+        else {
+            return Err(eyre!("expected the compressed body"));
+        };
+
+        assert_eq!(response.body(), "gz");
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "2");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+
+        // Other encodings and clients without one still get the original body
+        let hit = cache.get_at(&key, Some(Encoding::Brotli), now);
+        assert!(matches!(hit, Some(CacheHit::Identity(_))));
+
+        let hit = cache.get_at(&key, None, now);
+        assert!(matches!(hit, Some(CacheHit::Identity(_))));
+
+        assert_eq!(cache.entries.lock().unwrap().size, 7);
+
+        // Compressed bodies never evict other responses
+        cache.insert_encoded(&key, Encoding::Brotli, Bytes::from("x".repeat(14)));
+
+        let hit = cache.get_at(&key, Some(Encoding::Brotli), now);
+        assert!(matches!(hit, Some(CacheHit::Identity(_))));
+
+        cache.get_at(&key, None, now + Duration::from_secs(60));
+        assert_eq!(cache.entries.lock().unwrap().size, 0);
+
+        Ok(())
+    }
+}
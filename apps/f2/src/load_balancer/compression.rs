@@ -0,0 +1,270 @@
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, VARY,
+};
+use http::{HeaderMap, HeaderValue, StatusCode};
+
+use crate::load_balancer::cache::header_tokens;
+
+/// Bodies smaller than this gain little from compression.
+const MIN_COMPRESSED_BYTES: u64 = 1024;
+
+/// Bodies larger than this are passed through untouched rather than buffered in memory.
+const MAX_COMPRESSED_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Brotli settings that favour speed, since responses are compressed on every request.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER: usize = 4096;
+
+/// An encoding the load balancer can compress responses with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Picks an encoding from the `Accept-Encoding` header of a request, preferring brotli when
+    /// the client accepts both.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut brotli = None;
+        let mut gzip = None;
+        let mut wildcard = None;
+
+        for token in header_tokens(headers, ACCEPT_ENCODING) {
+            let mut parameters = token.split(';');
+            let coding = parameters.next().unwrap_or_default().trim();
+
+            // Codings with a weight of zero are explicitly refused
+            let accepted = parameters
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .all(|weight| weight.parse::<f32>().is_ok_and(|weight| weight > 0.0));
+
+            match coding.to_ascii_lowercase().as_str() {
+                "br" => brotli = Some(accepted),
+                "gzip" | "x-gzip" => gzip = Some(accepted),
+                "*" => wildcard = Some(accepted),
+                _ => {}
+            }
+        }
+
+        if brotli.or(wildcard) == Some(true) {
+            Some(Self::Brotli)
+        } else if gzip.or(wildcard) == Some(true) {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    pub fn encode(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(
+                    Vec::new(),
+                    BROTLI_BUFFER,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW,
+                );
+
+                writer.write_all(bytes)?;
+
+                Ok(writer.into_inner())
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes)?;
+
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Checks whether a response is worth compressing. Its size must be known up front, so that
+/// streams such as server-sent events are passed through as they arrive.
+pub fn is_compressible(status: StatusCode, headers: &HeaderMap) -> bool {
+    if status != StatusCode::OK
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(CONTENT_RANGE)
+    {
+        return false;
+    }
+
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if !length.is_some_and(|length| (MIN_COMPRESSED_BYTES..=MAX_COMPRESSED_BYTES).contains(&length))
+    {
+        return false;
+    }
+
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_compressible_type)
+}
+
+fn is_compressible_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    let Some((kind, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+
+    match kind {
+        "text" => subtype != "event-stream",
+        "application" | "image" if subtype.ends_with("+json") || subtype.ends_with("+xml") => true,
+        "application" => matches!(subtype, "json" | "javascript" | "xml" | "wasm"),
+        _ => false,
+    }
+}
+
+/// Marks a compressible response as varying by `Accept-Encoding`, so that caches in between do
+/// not give compressed bodies to clients that cannot read them.
+pub fn add_vary(headers: &mut HeaderMap) {
+    let varies = header_tokens(headers, VARY)
+        .any(|header| header == "*" || header.eq_ignore_ascii_case("accept-encoding"));
+
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+/// Updates the headers of a response to describe its compressed body.
+pub fn mark_encoded(headers: &mut HeaderMap, encoding: Encoding, length: usize) {
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+    headers.remove(ACCEPT_RANGES);
+
+    // The compressed body is a different representation, so strong validators no longer hold
+    let weakened = headers
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .filter(|etag| etag.starts_with('"'))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{etag}")).ok());
+
+    if let Some(etag) = weakened {
+        headers.insert(ETAG, etag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use color_eyre::eyre::Result;
+    use flate2::read::GzDecoder;
+    use http::header::{
+        ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY,
+    };
+    use http::{HeaderMap, HeaderValue, StatusCode};
+
+    use crate::load_balancer::compression::{add_vary, is_compressible, mark_encoded, Encoding};
+
+    fn negotiate(accept_encoding: &'static str) -> Option<Encoding> {
+        let headers =
+            HeaderMap::from_iter([(ACCEPT_ENCODING, HeaderValue::from_static(accept_encoding))]);
+
+        Encoding::negotiate(&headers)
+    }
+
+    fn create_headers(content_type: &'static str, length: usize) -> HeaderMap {
+        HeaderMap::from_iter([
+            (CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (CONTENT_LENGTH, HeaderValue::from(length)),
+        ])
+    }
+
+    #[test]
+    fn encodings_are_negotiated_from_accept_encoding() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn only_text_like_responses_of_a_known_size_are_compressible() {
+        let compressible = |content_type, length| {
+            is_compressible(StatusCode::OK, &create_headers(content_type, length))
+        };
+
+        assert!(compressible("text/css", 4096));
+        assert!(compressible("application/json; charset=utf-8", 4096));
+        assert!(compressible("image/svg+xml", 4096));
+
+        assert!(!compressible("image/png", 4096));
+        assert!(!compressible("text/event-stream", 4096));
+        assert!(!compressible("text/css", 100));
+
+        let mut encoded = create_headers("text/css", 4096);
+        encoded.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+        assert!(!is_compressible(StatusCode::OK, &encoded));
+
+        let mut streamed = create_headers("text/html", 4096);
+        streamed.remove(CONTENT_LENGTH);
+
+        assert!(!is_compressible(StatusCode::OK, &streamed));
+    }
+
+    #[test]
+    fn bodies_round_trip_through_each_encoding() -> Result<()> {
+        let body = "body { margin: 0; }\n".repeat(100);
+
+        let mut gzip = String::new();
+        GzDecoder::new(Encoding::Gzip.encode(body.as_bytes())?.as_slice())
+            .read_to_string(&mut gzip)?;
+
+        assert_eq!(gzip, body);
+
+        let mut brotli = String::new();
+        brotli::Decompressor::new(Encoding::Brotli.encode(body.as_bytes())?.as_slice(), 4096)
+            .read_to_string(&mut brotli)?;
+
+        assert_eq!(brotli, body);
+
+        Ok(())
+    }
+
+    #[test]
+    fn headers_describe_the_compressed_body() {
+        let mut headers = create_headers("text/css", 4096);
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+        add_vary(&mut headers);
+        mark_encoded(&mut headers, Encoding::Gzip, 512);
+
+        assert_eq!(headers.get_all(VARY).iter().count(), 1);
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[CONTENT_LENGTH], "512");
+        assert_eq!(headers[ETAG], "W/\"abc\"");
+    }
+}
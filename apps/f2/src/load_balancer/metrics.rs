@@ -0,0 +1,208 @@
+use std::future::Future;
+use std::time::Instant;
+
+use color_eyre::eyre::Result;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use opentelemetry::metrics::{Counter, Histogram, Meter, UpDownCounter};
+use opentelemetry::KeyValue;
+
+use crate::config::Route;
+
+/// The service and route that handled a request, recorded on the response.
+#[derive(Clone, Debug)]
+pub struct MatchedRoute {
+    pub service: String,
+    pub route: String,
+}
+
+impl MatchedRoute {
+    pub fn new(service: &str, route: &Route) -> Self {
+        Self {
+            service: service.to_owned(),
+            route: format!(
+                "{}{}",
+                route.host,
+                route.prefix.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    fn attributes(&self) -> [KeyValue; 2] {
+        [
+            KeyValue::new("service", self.service.clone()),
+            KeyValue::new("route", self.route.clone()),
+        ]
+    }
+}
+
+/// Marks a response that the load balancer generated because the upstream did not respond.
+#[derive(Copy, Clone, Debug)]
+pub struct UpstreamError;
+
+/// Instruments for the traffic passing through the load balancer.
+#[derive(Clone, Debug)]
+pub struct ProxyMetrics {
+    requests: Counter<u64>,
+    latency: Histogram<f64>,
+    upstream_errors: Counter<u64>,
+    active_connections: UpDownCounter<i64>,
+    tls_handshake_failures: Counter<u64>,
+}
+
+impl ProxyMetrics {
+    pub fn new(meter: &Meter) -> Self {
+        Self {
+            requests: meter
+                .u64_counter("f2_requests_total")
+                .with_description("Total proxied requests by service, route and status code")
+                .build(),
+            latency: meter
+                .f64_histogram("f2_request_duration_seconds")
+                .with_description("Time taken to respond to requests by service and route")
+                .with_unit("s")
+                .build(),
+            upstream_errors: meter
+                .u64_counter("f2_upstream_errors_total")
+                .with_description("Total requests that failed to reach an upstream container")
+                .build(),
+            active_connections: meter
+                .i64_up_down_counter("f2_active_connections")
+                .with_description("Number of open client connections by listener")
+                .build(),
+            tls_handshake_failures: meter
+                .u64_counter("f2_tls_handshake_failures_total")
+                .with_description("Total client connections that failed the TLS handshake")
+                .build(),
+        }
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn track_connection(&self, listener: &'static str) -> ConnectionGuard {
+        let attributes = [KeyValue::new("listener", listener)];
+        self.active_connections.add(1, &attributes);
+
+        ConnectionGuard {
+            active_connections: self.active_connections.clone(),
+            attributes,
+        }
+    }
+
+    pub fn record_tls_handshake_failure(&self, listener: &'static str) {
+        self.tls_handshake_failures
+            .add(1, &[KeyValue::new("listener", listener)]);
+    }
+
+    /// Records the outcome of a request, where requests that failed without a response are never
+    /// matched to a route and are labelled with an `error` status.
+    fn record_result<B>(&self, result: &Result<Response<B>>, elapsed: f64) {
+        let matched = result
+            .as_ref()
+            .ok()
+            .and_then(|response| response.extensions().get::<MatchedRoute>());
+
+        let [service, route] = matched.map_or_else(
+            || {
+                [
+                    KeyValue::new("service", "unmatched"),
+                    KeyValue::new("route", "unmatched"),
+                ]
+            },
+            MatchedRoute::attributes,
+        );
+
+        let status = status_attribute(result);
+
+        self.requests
+            .add(1, &[service.clone(), route.clone(), status]);
+        self.latency
+            .record(elapsed, &[service.clone(), route.clone()]);
+
+        let upstream_error = result
+            .as_ref()
+            .is_ok_and(|response| response.extensions().get::<UpstreamError>().is_some());
+
+        if upstream_error {
+            self.upstream_errors.add(1, &[service, route]);
+        }
+    }
+}
+
+/// Decrements the active connections for a listener when the connection closes.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    active_connections: UpDownCounter<i64>,
+    attributes: [KeyValue; 1],
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.active_connections.add(-1, &self.attributes);
+    }
+}
+
+/// Handles a request, recording how long it took and the response it produced, or that it failed.
+pub async fn record_request<B, F, Fut>(
+    metrics: ProxyMetrics,
+    req: Request<B>,
+    handle: F,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+where
+    F: FnOnce(Request<B>) -> Fut,
+    Fut: Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>>>,
+{
+    let started = Instant::now();
+    let result = handle(req).await;
+
+    metrics.record_result(&result, started.elapsed().as_secs_f64());
+
+    result
+}
+
+fn status_attribute<B>(result: &Result<Response<B>>) -> KeyValue {
+    match result {
+        Ok(response) => KeyValue::new("status_code", i64::from(response.status().as_u16())),
+        Err(_) => KeyValue::new("status_code", "error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::{eyre, Result};
+    use hyper::{Response, StatusCode};
+    use opentelemetry::{KeyValue, Value};
+
+    use crate::config::Route;
+    use crate::load_balancer::metrics::{status_attribute, MatchedRoute};
+
+    #[test]
+    fn routes_are_labelled_by_host_and_prefix() {
+        let route = Route {
+            host: String::from("example.com"),
+            prefix: Some(String::from("/api")),
+            ..Default::default()
+        };
+
+        let matched = MatchedRoute::new("backend", &route);
+
+        assert_eq!(matched.service, "backend");
+        assert_eq!(matched.route, "example.com/api");
+    }
+
+    #[test]
+    fn failed_requests_are_labelled_with_an_error_status() -> Result<()> {
+        let response = Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(())?;
+        let failed: Result<Response<()>> = Err(eyre!("missing host header"));
+
+        assert_eq!(
+            status_attribute(&Ok(response)),
+            KeyValue::new("status_code", 502)
+        );
+        assert_eq!(status_attribute(&failed).value, Value::from("error"));
+
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::Cursor;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use arc_swap::ArcSwap;
@@ -13,32 +14,78 @@ use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto::Builder;
-use mutual_tls::{ConnectionContext, Server, ServerConfiguration};
-use rand::prelude::SmallRng;
 use rustls::server::danger::ClientCertVerifier;
-use rustls::server::{NoClientAuth, WebPkiClientVerifier};
-use rustls::RootCertStore;
+use rustls::server::{Acceptor, NoClientAuth, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
 use tcp::TcpTlsProxy;
-use tls::DynamicAuthenticationLevelResolver;
+use tls::{AuthenticationLevel, AuthenticationLevelResolver, DynamicAuthenticationLevelResolver};
 use tokio::net::TcpListener;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 use tokio::task::JoinSet;
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
 
 use crate::config::{Config, MtlsConfig, Scheme, TlsConfig};
+use crate::docker::models::ContainerId;
 use crate::ipc::MessageBus;
-use crate::load_balancer::tls::CertificateResolver;
+use crate::load_balancer::access_log::AccessLogger;
+use crate::load_balancer::cache::ResponseCache;
+use crate::load_balancer::metrics::ProxyMetrics;
+use crate::load_balancer::rate_limit::RateLimiter;
+use crate::load_balancer::tls::{
+    client_common_name, AcmeChallenges, AcmeManager, CertificateResolver,
+};
 use crate::service_registry::ServiceRegistry;
 
+mod access_log;
+mod cache;
+mod compression;
+mod metrics;
 mod proxy;
+mod rate_limit;
+mod redirect;
 mod tcp;
 mod tls;
+mod upgrade;
+
+/// The address of the client that sent a request, where it is known.
+#[derive(Copy, Clone, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// The common name from the certificate a client presented during mutual TLS.
+#[derive(Clone, Debug)]
+pub struct ClientCommonName(pub String);
+
+/// What is known about a client connection when creating the service that handles its requests.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionContext {
+    /// The common name from the client certificate, for connections using mutual TLS.
+    pub common_name: Option<String>,
+}
+
+/// The scheme a client used to connect to the load balancer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClientScheme {
+    Http,
+    Https,
+}
+
+impl ClientScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Https => "https",
+        }
+    }
+}
+
+/// The container that handled a request, recorded on the response.
+#[derive(Clone, Debug)]
+pub struct Upstream(pub ContainerId);
 
 #[derive(Debug)]
 pub struct LoadBalancer {
     service_registry: Arc<RwLock<ServiceRegistry>>,
     client: Client<HttpConnector, Incoming>,
-    rng: Arc<Mutex<SmallRng>>,
     config: Arc<ArcSwap<Config>>,
     message_bus: Arc<MessageBus>,
 }
@@ -50,12 +97,10 @@ impl LoadBalancer {
         message_bus: Arc<MessageBus>,
     ) -> Self {
         let client = Client::builder(TokioExecutor::new()).build_http();
-        let rng = Arc::new(Mutex::new(rand::make_rng()));
 
         Self {
             service_registry,
             client,
-            rng,
             config,
             message_bus,
         }
@@ -67,47 +112,119 @@ impl LoadBalancer {
         tls: Option<TlsConfig>,
         mtls: Option<MtlsConfig>,
     ) -> Result<()> {
-        let reconciliation_path = Arc::from(self.config.load().alb.reconciliation.as_str());
         let message_bus = Arc::clone(&self.message_bus);
 
         // Pre-clone Arcs needed after `service_factory` moves `self`
         let service_registry = Arc::clone(&self.service_registry);
-        let rng = Arc::clone(&self.rng);
         let self_config = Arc::clone(&self.config);
         let self_message_bus = Arc::clone(&self.message_bus);
 
-        let service_factory = move |_| {
+        let acme = self.config.load().alb.acme.clone();
+        let challenges = Arc::new(AcmeChallenges::default());
+        let service_challenges = Arc::clone(&challenges);
+        let rate_limiter = Arc::new(RateLimiter::new(Arc::clone(&self.config)));
+        let response_cache = Arc::new(ResponseCache::default());
+
+        let access_logger = match self.config.load().alb.access_log.as_ref() {
+            Some(config) => Some(Arc::new(AccessLogger::new(config)?)),
+            None => None,
+        };
+
+        let service_access_logger = access_logger.clone();
+
+        let metrics = ProxyMetrics::new(&opentelemetry::global::meter("f2"));
+        let service_metrics = metrics.clone();
+
+        // Builds the service factory for a listener, so requests know which scheme they used
+        let service_factory = move |scheme: ClientScheme| {
             let service_registry = Arc::clone(&self.service_registry);
-            let rng = Arc::clone(&self.rng);
             let client = self.client.clone();
-            let reconciliation_path = Arc::clone(&reconciliation_path);
+            let config = Arc::clone(&self.config);
             let message_bus = Arc::clone(&message_bus);
+            let challenges = Arc::clone(&service_challenges);
+            let rate_limiter = Arc::clone(&rate_limiter);
+            let response_cache = Arc::clone(&response_cache);
+            let access_logger = service_access_logger.clone();
+            let metrics = service_metrics.clone();
 
-            service_fn(move |req| {
+            move |context: ConnectionContext| {
                 let service_registry = Arc::clone(&service_registry);
-                let rng = Arc::clone(&rng);
                 let client = client.clone();
-                let reconciliation_path = Arc::clone(&reconciliation_path);
+                let config = Arc::clone(&config);
                 let message_bus = Arc::clone(&message_bus);
+                let challenges = Arc::clone(&challenges);
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let response_cache = Arc::clone(&response_cache);
+                let access_logger = access_logger.clone();
+                let metrics = metrics.clone();
+                let common_name = context.common_name.map(ClientCommonName);
+                let connection = metrics.track_connection(scheme.as_str());
+
+                service_fn(move |mut req: Request<Incoming>| {
+                    // The connection stays open for as long as hyper holds this service
+                    let _ = &connection;
+
+                    let service_registry = Arc::clone(&service_registry);
+                    let client = client.clone();
+                    let config = Arc::clone(&config);
+                    let message_bus = Arc::clone(&message_bus);
+                    let challenges = Arc::clone(&challenges);
+                    let rate_limiter = Arc::clone(&rate_limiter);
+                    let response_cache = Arc::clone(&response_cache);
+                    let access_logger = access_logger.clone();
+                    let metrics = metrics.clone();
+
+                    req.extensions_mut().insert(scheme);
+
+                    if let Some(common_name) = common_name.clone() {
+                        req.extensions_mut().insert(common_name);
+                    }
 
-                async move {
-                    proxy::handle_request(
-                        service_registry,
-                        rng,
-                        client,
-                        reconciliation_path,
-                        message_bus,
-                        req,
-                    )
-                    .await
-                }
-            })
+                    access_log::log_request(access_logger, req, |req| {
+                        metrics::record_request(metrics, req, |req| {
+                            proxy::handle_request(
+                                service_registry,
+                                client,
+                                config,
+                                message_bus,
+                                challenges,
+                                rate_limiter,
+                                response_cache,
+                                req,
+                            )
+                        })
+                    })
+                })
+            }
+        };
+
+        // Certificates from the configuration and those issued through ACME share a resolver
+        let certificate_resolver = if tls.is_some() || acme.is_some() {
+            let config = Arc::new(tls.map(|tls| tls.domains).unwrap_or_default());
+            let resolver = CertificateResolver::new(config, Arc::clone(&self_message_bus)).await?;
+
+            Some(Arc::new(resolver))
+        } else {
+            None
         };
 
         let mut tasks = JoinSet::new();
 
+        if let (Some(acme), Some(resolver)) = (acme, certificate_resolver.as_ref()) {
+            let manager = AcmeManager::new(
+                acme,
+                Arc::clone(&self_config),
+                challenges,
+                Arc::clone(resolver),
+            );
+
+            tracing::info!("starting the ACME certificate manager");
+
+            tasks.spawn(manager.run());
+        }
+
         if let Some(listener) = listeners.remove(&Scheme::Http) {
-            let server = HttpServer::new(service_factory.clone());
+            let server = HttpServer::new(service_factory(ClientScheme::Http));
 
             tracing::info!("starting http server on {}", listener.local_addr()?);
 
@@ -115,7 +232,9 @@ impl LoadBalancer {
         }
 
         if let Some(listener) = listeners.remove(&Scheme::Https) {
-            if let Some(tls) = tls.as_ref() {
+            if let Some(resolver) = certificate_resolver.as_ref() {
+                let certificate_resolver = Arc::clone(resolver);
+
                 let client_cert_verifier: Arc<dyn ClientCertVerifier> = match &mtls {
                     Some(config) => {
                         let bytes = config.anchor.resolve().await?;
@@ -132,21 +251,15 @@ impl LoadBalancer {
                     None => Arc::new(NoClientAuth),
                 };
 
-                let config = Arc::new(tls.domains.clone());
-
-                let certificate_resolver = Arc::new(
-                    CertificateResolver::new(config, Arc::clone(&self_message_bus)).await?,
-                );
                 let authentication_level_resolver =
                     DynamicAuthenticationLevelResolver::new(Arc::clone(&self_config));
 
-                let server_configuration = ServerConfiguration::default();
-                let server = Server::new(
+                let server = HttpsServer::new(
                     authentication_level_resolver,
                     client_cert_verifier,
                     certificate_resolver,
-                    service_factory,
-                    server_configuration,
+                    metrics.clone(),
+                    service_factory(ClientScheme::Https),
                 );
 
                 tracing::info!("starting https server on {}", listener.local_addr()?);
@@ -156,19 +269,18 @@ impl LoadBalancer {
         }
 
         if let Some(listener) = listeners.remove(&Scheme::Tls) {
-            if let Some(tls) = tls.as_ref() {
-                let config = Arc::new(tls.domains.clone());
-                let certificate_resolver = Arc::new(
-                    CertificateResolver::new(config, Arc::clone(&self_message_bus)).await?,
-                );
-
+            if let Some(certificate_resolver) = certificate_resolver {
                 let server_config = rustls::ServerConfig::builder()
                     .with_no_client_auth()
                     .with_cert_resolver(certificate_resolver);
 
                 let acceptor = TlsAcceptor::from(Arc::new(server_config));
-                let proxy =
-                    TcpTlsProxy::new(Arc::clone(&service_registry), Arc::clone(&rng), acceptor);
+                let proxy = TcpTlsProxy::new(
+                    Arc::clone(&service_registry),
+                    acceptor,
+                    access_logger,
+                    metrics,
+                );
 
                 tracing::info!("starting TCP TLS proxy on {}", listener.local_addr()?);
 
@@ -220,14 +332,143 @@ where
         &self,
         listener: &mut TcpListener,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let (stream, _) = listener.accept().await?;
+        let (stream, client_addr) = listener.accept().await?;
         let io = TokioIo::new(stream);
 
         let service = (self.service_factory)(ConnectionContext { common_name: None });
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ClientAddr(client_addr));
+            service.call(req)
+        });
 
         tokio::spawn(async move {
             if let Err(e) = Builder::new(TokioExecutor::new())
-                .serve_connection(io, service)
+                .serve_connection_with_upgrades(io, service)
+                .await
+            {
+                tracing::warn!(%e, "error handling connection");
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Serves HTTPS, choosing per connection whether to ask for a client certificate based on the
+/// server name the client sent.
+pub struct HttpsServer<F> {
+    service_factory: Arc<F>,
+    authentication_level_resolver: Arc<dyn AuthenticationLevelResolver>,
+    standard: Arc<ServerConfig>,
+    mutual: Arc<ServerConfig>,
+    metrics: ProxyMetrics,
+}
+
+impl<F, S> HttpsServer<F>
+where
+    F: Fn(ConnectionContext) -> S + Send + Sync + 'static,
+    S: Service<Request<Incoming>, Response = Response<BoxBody<Bytes, hyper::Error>>>
+        + Send
+        + 'static,
+    S::Future: 'static,
+    <S as Service<Request<Incoming>>>::Future: Send,
+    <S as Service<Request<Incoming>>>::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    pub fn new(
+        authentication_level_resolver: Arc<dyn AuthenticationLevelResolver>,
+        client_cert_verifier: Arc<dyn ClientCertVerifier>,
+        certificate_resolver: Arc<dyn ResolvesServerCert>,
+        metrics: ProxyMetrics,
+        service_factory: F,
+    ) -> Self {
+        let mut standard = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&certificate_resolver));
+
+        let mut mutual = ServerConfig::builder()
+            .with_client_cert_verifier(client_cert_verifier)
+            .with_cert_resolver(certificate_resolver);
+
+        for config in [&mut standard, &mut mutual] {
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        }
+
+        Self {
+            service_factory: Arc::new(service_factory),
+            authentication_level_resolver,
+            standard: Arc::new(standard),
+            mutual: Arc::new(mutual),
+            metrics,
+        }
+    }
+
+    async fn run(self, mut listener: TcpListener) {
+        loop {
+            if let Err(e) = self.try_handle_connection(&mut listener).await {
+                tracing::warn!(%e, "failed to handle connection");
+            } else {
+                tracing::trace!("handled a connection from a client");
+            }
+        }
+    }
+
+    pub async fn try_handle_connection(
+        &self,
+        listener: &mut TcpListener,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (stream, client_addr) = listener.accept().await?;
+
+        let service_factory = Arc::clone(&self.service_factory);
+        let authentication_level_resolver = Arc::clone(&self.authentication_level_resolver);
+        let standard = Arc::clone(&self.standard);
+        let mutual = Arc::clone(&self.mutual);
+        let metrics = self.metrics.clone();
+
+        // The handshake happens on its own task so a slow client cannot hold up the listener
+        tokio::spawn(async move {
+            let handshake = LazyConfigAcceptor::new(Acceptor::default(), stream).await;
+
+            let start = match handshake {
+                Ok(start) => start,
+                Err(e) => {
+                    tracing::debug!(%client_addr, %e, "failed to read the client hello");
+                    metrics.record_tls_handshake_failure("https");
+                    return;
+                }
+            };
+
+            let client_hello = start.client_hello();
+            let server_name = client_hello.server_name().unwrap_or_default();
+
+            let config = match authentication_level_resolver.resolve(server_name) {
+                Some(AuthenticationLevel::Mutual) => mutual,
+                Some(AuthenticationLevel::Standard) | None => standard,
+            };
+
+            let stream = match start.into_stream(config).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!(%client_addr, %e, "failed to complete the TLS handshake");
+                    metrics.record_tls_handshake_failure("https");
+                    return;
+                }
+            };
+
+            let common_name = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .and_then(client_common_name);
+
+            let service = service_factory(ConnectionContext { common_name });
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ClientAddr(client_addr));
+                service.call(req)
+            });
+
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
                 .await
             {
                 tracing::warn!(%e, "error handling connection");
@@ -1,30 +1,40 @@
-use std::net::SocketAddrV4;
+use std::net::{IpAddr, SocketAddrV4};
 use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use color_eyre::eyre::{eyre, Result};
-use http::header::HOST;
-use http::{Method, Version};
+use http::header::{CONNECTION, FORWARDED, HOST, LOCATION, STRICT_TRANSPORT_SECURITY};
+use http::{HeaderName, Method, StatusCode, Version};
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty};
+use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::{Body, Bytes};
-use hyper::http::uri::PathAndQuery;
 use hyper::{Request, Response};
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
-use rand::prelude::SmallRng;
-use rand::Rng;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 
+use crate::config::{Config, HstsPolicy};
 use crate::ipc::MessageBus;
-use crate::service_registry::ServiceRegistry;
-
+use crate::load_balancer::cache::{self, CacheHit, CacheKey, ResponseCache};
+use crate::load_balancer::compression::{self, Encoding};
+use crate::load_balancer::metrics::{MatchedRoute, UpstreamError};
+use crate::load_balancer::rate_limit::RateLimiter;
+use crate::load_balancer::redirect::{hsts_policy, redirect_location};
+use crate::load_balancer::tls::{AcmeChallenges, CHALLENGE_PATH_PREFIX};
+use crate::load_balancer::upgrade::{self, is_upgrade_request};
+use crate::load_balancer::{ClientAddr, ClientCommonName, ClientScheme, Upstream};
+use crate::service_registry::{SelectionContext, ServiceRegistry};
+
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_request<B>(
     service_registry: Arc<RwLock<ServiceRegistry>>,
-    rng: Arc<Mutex<SmallRng>>,
     client: Client<HttpConnector, B>,
-    reconciliation_path: Arc<str>,
+    config: Arc<ArcSwap<Config>>,
     message_bus: Arc<MessageBus>,
-    req: Request<B>,
+    challenges: Arc<AcmeChallenges>,
+    rate_limiter: Arc<RateLimiter>,
+    response_cache: Arc<ResponseCache>,
+    mut req: Request<B>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
 where
     B: Body + Send + Unpin + 'static,
@@ -32,10 +42,14 @@ where
     <B as Body>::Error: std::error::Error + Send + Sync + 'static,
 {
     let uri = req.uri();
+    let config = config.load();
+
+    // These triggers are unauthenticated, so the admin API replaces them once it is configured
+    if req.method() == Method::PUT && config.alb.admin.is_none() {
+        let reconciliation_path = config.alb.reconciliation.as_str();
 
-    if req.method() == Method::PUT {
         match uri.path_and_query() {
-            Some(suffix) if suffix.path() == &*reconciliation_path => {
+            Some(suffix) if suffix.path() == reconciliation_path => {
                 tracing::info!(
                     %reconciliation_path,
                     "informing the reconciler that a PUT request was received",
@@ -56,42 +70,259 @@ where
         }
     }
 
+    if req.method() == Method::GET && uri.path().starts_with(CHALLENGE_PATH_PREFIX) {
+        let Some(key_authorization) = challenges.respond(uri.path()) else {
+            tracing::debug!(%uri, "received a request for an unknown ACME challenge");
+
+            return Ok(Response::builder().status(404).body(empty())?);
+        };
+
+        tracing::info!(%uri, "answering an ACME challenge");
+
+        return Ok(Response::builder()
+            .status(200)
+            .body(full(key_authorization))?);
+    }
+
     let host = extract_host(&req)?;
+    let scheme = req
+        .extensions()
+        .get::<ClientScheme>()
+        .copied()
+        .unwrap_or(ClientScheme::Http);
 
     // Filter based on the host, then do path matching for longest length
     let read_lock = service_registry.read().await;
+    let route = read_lock.find_route(host, uri.path());
+    let matched = route.map(|(service, route)| MatchedRoute::new(service, route));
+
+    // Responses are labelled with the route that handled them for metrics
+    let respond = |status: u16| -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        let mut response = Response::builder().status(status).body(empty())?;
 
-    let Some((downstreams, port)) = read_lock.find_downstreams(host, uri.path()) else {
+        if let Some(matched) = &matched {
+            response.extensions_mut().insert(matched.clone());
+        }
+
+        Ok(response)
+    };
+
+    if let Some((_, route)) = route {
+        if let Some(location) = redirect_location(&config.alb, route, scheme, host, uri) {
+            tracing::debug!(%host, %uri, %location, "redirecting request");
+
+            let mut response = respond(308)?;
+            response.headers_mut().insert(LOCATION, location.parse()?);
+
+            if let Some(hsts) = hsts_policy(&config.alb, route, scheme) {
+                response
+                    .headers_mut()
+                    .insert(STRICT_TRANSPORT_SECURITY, hsts.header_value().parse()?);
+            }
+
+            return Ok(response);
+        }
+    }
+
+    let Some(downstreams) = read_lock.find_downstreams(host, uri.path()) else {
         tracing::debug!(%host, %uri, "no downstreams found for request");
 
-        return Ok(Response::builder().status(404).body(empty())?);
+        return respond(404);
+    };
+
+    if downstreams.is_empty() {
+        tracing::warn!(%host, %uri, "no healthy downstreams available for request");
+
+        return respond(503);
+    }
+
+    if !rate_limiter.allow(downstreams.route, &req) {
+        tracing::debug!(%host, %uri, "client exceeded the rate limit for the route");
+
+        return respond(429);
+    }
+
+    let hsts = hsts_policy(&config.alb, downstreams.route, scheme).map(HstsPolicy::header_value);
+    let is_upgrade = is_upgrade_request(req.headers());
+    let cache_key = CacheKey::for_request(downstreams.route, host, &req).filter(|_| !is_upgrade);
+    let encoding = Encoding::negotiate(req.headers()).filter(|_| req.method() != Method::HEAD);
+
+    if let Some(key) = &cache_key {
+        if let Some(hit) = response_cache.get(key, encoding) {
+            tracing::debug!(%host, %uri, "serving the response from the cache");
+
+            let response = match hit {
+                CacheHit::Encoded(response) => response.map(full),
+                CacheHit::Identity(response) => {
+                    compress_cached(&response_cache, key, response.map(full), encoding).await?
+                }
+            };
+
+            return finish_response(response, matched, hsts);
+        }
+    }
+
+    let context = SelectionContext {
+        client_ip: req.extensions().get::<ClientAddr>().map(|addr| addr.0.ip()),
+        headers: Some(req.headers()),
     };
 
-    let downstream = {
-        let mut rng = rng.lock().await;
-        let next = rng.next_u32() as usize;
-        let normalised = next % downstreams.len();
+    let Some(downstream) = downstreams.select(&context) else {
+        tracing::warn!(%host, %uri, "all downstreams are at their connection limit");
 
-        downstreams
-            .get_index(normalised)
-            .ok_or_else(|| eyre!("no downstreams found for request to {uri} with host {host}"))?
-            .addr
+        return respond(503);
     };
 
+    let port = downstreams.port();
+    let path = downstreams.route.downstream_path(uri.path()).into_owned();
+
     drop(read_lock);
 
-    let addr = SocketAddrV4::new(downstream, port);
-    let path_and_query = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+    let addr = SocketAddrV4::new(downstream.addr, port);
+
+    tracing::debug!(%host, %uri, id = %downstream.id, %addr, "proxying request downstream");
+    let query = uri
+        .query()
+        .map(|query| format!("?{query}"))
+        .unwrap_or_default();
 
-    let target_uri = format!("http://{addr}{path_and_query}").parse()?;
+    let target_uri = format!("http://{addr}{path}{query}").parse()?;
 
-    let mut mapped = map_request(req)?;
+    // The client connection can only be taken over once hyper has sent the downstream's response
+    let on_client_upgrade = is_upgrade.then(|| hyper::upgrade::on(&mut req));
+
+    let mut mapped = map_request(req, config.alb.common_name_header())?;
     *mapped.uri_mut() = target_uri;
 
-    Ok(client.request(mapped).await?.map(BoxBody::new))
+    let mut response = match client.request(mapped).await {
+        Ok(response) => response.map(BoxBody::new),
+        Err(error) => {
+            tracing::warn!(id = %downstream.id, %addr, %error, "failed to proxy request downstream");
+
+            let mut response = respond(502)?;
+            response.extensions_mut().insert(UpstreamError);
+            response
+        }
+    };
+
+    response
+        .extensions_mut()
+        .insert(Upstream(downstream.id.clone()));
+
+    if let Some(on_client_upgrade) =
+        on_client_upgrade.filter(|_| response.status() == StatusCode::SWITCHING_PROTOCOLS)
+    {
+        tracing::debug!(id = %downstream.id, %addr, "splicing an upgraded connection downstream");
+
+        let on_upstream_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(upgrade::splice(
+            on_client_upgrade,
+            on_upstream_upgrade,
+            downstream,
+        ));
+
+        return finish_response(response, matched, hsts);
+    }
+
+    // The request is no longer outstanding once the downstream has responded
+    drop(downstream);
+
+    let response = match cache_key {
+        Some(key) => {
+            let response = cache_response(&response_cache, key.clone(), response).await?;
+            compress_cached(&response_cache, &key, response, encoding).await?
+        }
+        None => compress_response(response, encoding).await?.0,
+    };
+
+    finish_response(response, matched, hsts)
 }
 
-fn extract_host<B>(req: &Request<B>) -> Result<&str> {
+/// Stores a response in the cache if its headers allow it, which requires buffering the body.
+async fn cache_response(
+    response_cache: &ResponseCache,
+    key: CacheKey,
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let Some(lifetime) = cache::storable_lifetime(response.status(), response.headers()) else {
+        return Ok(response);
+    };
+
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await?.to_bytes();
+
+    response_cache.insert(key, parts.headers.clone(), body.clone(), lifetime);
+
+    Ok(Response::from_parts(parts, full(body)))
+}
+
+/// Compresses a response that is in the cache for the client, keeping the compressed body so that
+/// later requests with the same encoding do not compress it again.
+async fn compress_cached(
+    response_cache: &ResponseCache,
+    key: &CacheKey,
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+    encoding: Option<Encoding>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let (response, compressed) = compress_response(response, encoding).await?;
+
+    if let (Some(encoding), Some(compressed)) = (encoding, compressed) {
+        response_cache.insert_encoded(key, encoding, compressed);
+    }
+
+    Ok(response)
+}
+
+/// Compresses a response with the encoding negotiated for the client if it can be, also returning
+/// the compressed body when it was.
+async fn compress_response(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+    encoding: Option<Encoding>,
+) -> Result<(Response<BoxBody<Bytes, hyper::Error>>, Option<Bytes>)> {
+    if !compression::is_compressible(response.status(), response.headers()) {
+        return Ok((response, None));
+    }
+
+    let (mut parts, body) = response.into_parts();
+    compression::add_vary(&mut parts.headers);
+
+    let Some(encoding) = encoding else {
+        return Ok((Response::from_parts(parts, body), None));
+    };
+
+    let body = body.collect().await?.to_bytes();
+    let compressed = tokio::task::spawn_blocking(move || encoding.encode(&body)).await??;
+    let compressed = Bytes::from(compressed);
+
+    compression::mark_encoded(&mut parts.headers, encoding, compressed.len());
+
+    Ok((
+        Response::from_parts(parts, full(compressed.clone())),
+        Some(compressed),
+    ))
+}
+
+/// Adds the details shared by every routed response, whether it came from the downstream or the
+/// cache.
+fn finish_response(
+    mut response: Response<BoxBody<Bytes, hyper::Error>>,
+    matched: Option<MatchedRoute>,
+    hsts: Option<String>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    if let Some(matched) = matched {
+        response.extensions_mut().insert(matched);
+    }
+
+    if let Some(hsts) = hsts {
+        response
+            .headers_mut()
+            .insert(STRICT_TRANSPORT_SECURITY, hsts.parse()?);
+    }
+
+    Ok(response)
+}
+
+pub fn extract_host<B>(req: &Request<B>) -> Result<&str> {
     let uri = req.uri();
 
     let host = match req.version() {
@@ -104,7 +335,16 @@ fn extract_host<B>(req: &Request<B>) -> Result<&str> {
     Ok(host)
 }
 
-fn map_request<B>(original: Request<B>) -> Result<Request<B>> {
+/// Headers describing the client, which are always set by the load balancer so downstreams can
+/// trust them.
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// Maps a request to HTTP/1.1 for the downstream, replacing any forwarding headers the client sent
+/// with ones describing the client connection. Hop-by-hop `Connection` headers are dropped, except
+/// for the one asking the downstream to switch protocols.
+fn map_request<B>(original: Request<B>, common_name_header: &str) -> Result<Request<B>> {
     let uri = original.uri();
 
     let mut request = Request::builder()
@@ -112,82 +352,187 @@ fn map_request<B>(original: Request<B>) -> Result<Request<B>> {
         .uri(uri)
         .version(Version::HTTP_11);
 
+    let forwarding_headers = [
+        FORWARDED,
+        X_FORWARDED_FOR,
+        X_FORWARDED_HOST,
+        X_FORWARDED_PROTO,
+    ];
+
     for (name, value) in original.headers() {
-        if !name.as_str().starts_with(':') && name != "connection" {
+        let forwarded = forwarding_headers.contains(name) || name == common_name_header;
+
+        if !name.as_str().starts_with(':') && name != "connection" && !forwarded {
             request = request.header(name, value);
         }
     }
 
+    if is_upgrade_request(original.headers()) {
+        request = request.header(CONNECTION, "upgrade");
+    }
+
+    let client_ip = original
+        .extensions()
+        .get::<ClientAddr>()
+        .map(|addr| addr.0.ip());
+
+    let proto = original
+        .extensions()
+        .get::<ClientScheme>()
+        .map_or("http", |scheme| scheme.as_str());
+
+    let host = extract_host(&original).ok();
+    let mut forwarded = Vec::new();
+
+    if let Some(client_ip) = client_ip {
+        request = request.header(&X_FORWARDED_FOR, client_ip.to_string());
+        forwarded.push(format!("for={}", forwarded_node(client_ip)));
+    }
+
+    if let Some(host) = host {
+        request = request.header(&X_FORWARDED_HOST, host);
+        forwarded.push(format!("host=\"{host}\""));
+    }
+
+    request = request.header(&X_FORWARDED_PROTO, proto);
+    forwarded.push(format!("proto={proto}"));
+
+    request = request.header(FORWARDED, forwarded.join(";"));
+
+    if let Some(common_name) = original.extensions().get::<ClientCommonName>() {
+        request = request.header(common_name_header, &common_name.0);
+    }
+
     let request = request.body(original.into_body())?;
 
     Ok(request)
 }
 
+/// Formats an address for the `Forwarded` header, which requires IPv6 addresses to be quoted.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
 }
 
+fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr};
     use std::sync::Arc;
     use std::time::Duration;
 
+    use arc_swap::ArcSwap;
     use color_eyre::eyre::Result;
-    use http::header::ACCEPT;
+    use http::header::{ACCEPT, CONNECTION, HOST, UPGRADE};
     use http::{HeaderValue, Method, Request, Uri, Version};
-    use http_body_util::Empty;
+    use http_body_util::{BodyExt, Empty};
     use hyper::body::Bytes;
     use hyper_util::client::legacy::connect::HttpConnector;
     use hyper_util::client::legacy::Client;
     use hyper_util::rt::TokioExecutor;
-    use rand::rngs::SmallRng;
-    use tokio::sync::{Mutex, RwLock};
+    use tokio::sync::RwLock;
 
+    use crate::config::{AdminConfig, AlbConfig, Config, Scheme};
     use crate::ipc::MessageBus;
+    use crate::load_balancer::cache::ResponseCache;
     use crate::load_balancer::proxy::{extract_host, handle_request, map_request};
+    use crate::load_balancer::rate_limit::RateLimiter;
+    use crate::load_balancer::tls::AcmeChallenges;
+    use crate::load_balancer::{ClientAddr, ClientCommonName, ClientScheme};
     use crate::service_registry::ServiceRegistry;
 
+    fn create_config() -> Arc<ArcSwap<Config>> {
+        let config = Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::from("/reconciliation"),
+                tls: None,
+                mtls: None,
+                acme: None,
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services: HashMap::new(),
+        };
+
+        Arc::new(ArcSwap::from_pointee(config))
+    }
+
     /// Gets all the dependencies required for calling `handle_request`.
     fn get_dependencies() -> (
         Arc<RwLock<ServiceRegistry>>,
-        Arc<Mutex<SmallRng>>,
         Client<HttpConnector, Empty<Bytes>>,
-        Arc<str>,
+        Arc<ArcSwap<Config>>,
         Arc<MessageBus>,
+        Arc<AcmeChallenges>,
+        Arc<RateLimiter>,
+        Arc<ResponseCache>,
     ) {
         let service_registry = Arc::new(RwLock::new(ServiceRegistry::default()));
-        let rng = Arc::new(Mutex::new(rand::make_rng()));
         let client = Client::builder(TokioExecutor::new()).build_http();
-        let reconciliation_path = Arc::from("/reconciliation");
+        let config = create_config();
         let message_bus = MessageBus::new();
+        let rate_limiter = Arc::new(RateLimiter::new(Arc::clone(&config)));
 
         (
             service_registry,
-            rng,
             client,
-            reconciliation_path,
+            config,
             Arc::clone(&message_bus),
+            Arc::default(),
+            rate_limiter,
+            Arc::default(),
         )
     }
 
     #[tokio::test]
     async fn can_cause_reconciliation() -> Result<()> {
-        let (service_registry, rng, client, reconciliation_path, message_bus) = get_dependencies();
+        let (
+            service_registry,
+            client,
+            config,
+            message_bus,
+            challenges,
+            rate_limiter,
+            response_cache,
+        ) = get_dependencies();
 
         let req = Request::builder()
             .method("PUT")
-            .uri(format!("http://example.com{}", reconciliation_path))
+            .uri("http://example.com/reconciliation")
             .body(Empty::<Bytes>::new())
             .unwrap();
 
         let response = handle_request(
             service_registry,
-            rng,
             client,
-            reconciliation_path,
+            config,
             Arc::clone(&message_bus),
+            challenges,
+            rate_limiter,
+            response_cache,
             req,
         )
         .await?;
@@ -207,7 +552,15 @@ mod tests {
 
     #[tokio::test]
     async fn can_cause_certificate_updates() -> Result<()> {
-        let (service_registry, rng, client, reconciliation_path, message_bus) = get_dependencies();
+        let (
+            service_registry,
+            client,
+            config,
+            message_bus,
+            challenges,
+            rate_limiter,
+            response_cache,
+        ) = get_dependencies();
 
         let req = Request::builder()
             .method("PUT")
@@ -217,10 +570,12 @@ mod tests {
 
         let response = handle_request(
             service_registry,
-            rng,
             client,
-            reconciliation_path,
+            config,
             Arc::clone(&message_bus),
+            challenges,
+            rate_limiter,
+            response_cache,
             req,
         )
         .await?;
@@ -238,6 +593,115 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn put_triggers_are_disabled_by_the_admin_api() -> Result<()> {
+        let (
+            service_registry,
+            client,
+            config,
+            message_bus,
+            challenges,
+            rate_limiter,
+            response_cache,
+        ) = get_dependencies();
+
+        let mut with_admin = (**config.load()).clone();
+        with_admin.alb.admin = Some(AdminConfig {
+            addr: Ipv4Addr::LOCALHOST,
+            port: 5001,
+            token: String::from("secret"),
+        });
+        config.store(Arc::new(with_admin));
+
+        for path in ["/reconciliation", "/certificates"] {
+            let req = Request::builder()
+                .method("PUT")
+                .uri(format!("http://example.com{path}"))
+                .body(Empty::<Bytes>::new())?;
+
+            let response = handle_request(
+                Arc::clone(&service_registry),
+                client.clone(),
+                Arc::clone(&config),
+                Arc::clone(&message_bus),
+                Arc::clone(&challenges),
+                Arc::clone(&rate_limiter),
+                Arc::clone(&response_cache),
+                req,
+            )
+            .await;
+
+            assert!(response.is_err() || response.is_ok_and(|r| r.status() != 200));
+        }
+
+        let reconciliation = tokio::time::timeout(
+            Duration::from_millis(1),
+            message_bus.receive_reconciliation_request(),
+        )
+        .await;
+        let certificates = tokio::time::timeout(
+            Duration::from_millis(1),
+            message_bus.receive_certificate_update_request(),
+        )
+        .await;
+
+        assert!(
+            reconciliation.is_err(),
+            "expected no reconciliation request"
+        );
+        assert!(
+            certificates.is_err(),
+            "expected no certificate update request"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_answer_acme_challenges() -> Result<()> {
+        let (
+            service_registry,
+            client,
+            config,
+            message_bus,
+            challenges,
+            rate_limiter,
+            response_cache,
+        ) = get_dependencies();
+
+        challenges.insert("token", "token.thumbprint");
+
+        for (token, status) in [("token", 200), ("unknown", 404)] {
+            let req = Request::builder()
+                .method(Method::GET)
+                .uri(format!(
+                    "http://example.com/.well-known/acme-challenge/{token}"
+                ))
+                .body(Empty::<Bytes>::new())?;
+
+            let response = handle_request(
+                Arc::clone(&service_registry),
+                client.clone(),
+                Arc::clone(&config),
+                Arc::clone(&message_bus),
+                Arc::clone(&challenges),
+                Arc::clone(&rate_limiter),
+                Arc::clone(&response_cache),
+                req,
+            )
+            .await?;
+
+            assert_eq!(response.status(), status);
+
+            if status == 200 {
+                let body = response.into_body().collect().await?.to_bytes();
+                assert_eq!(body, "token.thumbprint");
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn can_extract_hosts_for_http_11() -> Result<()> {
         let req = Request::builder()
@@ -285,7 +749,7 @@ mod tests {
             .header(&header_name, &header_value)
             .body(Empty::<Bytes>::new())?;
 
-        let mapped = map_request(req)?;
+        let mapped = map_request(req, "x-client-common-name")?;
 
         assert_eq!(mapped.method(), method);
         assert_eq!(mapped.uri(), &uri);
@@ -294,4 +758,83 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn forwarding_headers_describe_the_client() -> Result<()> {
+        let mut req = Request::builder()
+            .uri("http://example.com/path")
+            .version(Version::HTTP_11)
+            .header(HOST, "example.com")
+            .header("x-forwarded-for", "1.2.3.4")
+            .header("forwarded", "for=1.2.3.4")
+            .header("x-client-common-name", "spoofed")
+            .body(Empty::<Bytes>::new())?;
+
+        let client_addr = SocketAddr::from(([10, 0, 0, 1], 4000));
+
+        req.extensions_mut().insert(ClientAddr(client_addr));
+        req.extensions_mut().insert(ClientScheme::Https);
+
+        let mapped = map_request(req, "x-client-common-name")?;
+        let headers = mapped.headers();
+
+        assert_eq!(headers.get_all("x-forwarded-for").iter().count(), 1);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(
+            headers["forwarded"],
+            r#"for=10.0.0.1;host="example.com";proto=https"#
+        );
+        assert!(headers.get("x-client-common-name").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_requests_keep_their_upgrade_headers() -> Result<()> {
+        let req = Request::builder()
+            .uri("http://example.com/socket")
+            .version(Version::HTTP_11)
+            .header(HOST, "example.com")
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .body(Empty::<Bytes>::new())?;
+
+        let mapped = map_request(req, "x-client-common-name")?;
+
+        assert_eq!(mapped.headers()[CONNECTION], "upgrade");
+        assert_eq!(mapped.headers()[UPGRADE], "websocket");
+
+        let req = Request::builder()
+            .uri("http://example.com/")
+            .version(Version::HTTP_11)
+            .header(HOST, "example.com")
+            .header(CONNECTION, "keep-alive")
+            .body(Empty::<Bytes>::new())?;
+
+        let mapped = map_request(req, "x-client-common-name")?;
+
+        assert!(mapped.headers().get(CONNECTION).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_common_names_are_passed_downstream() -> Result<()> {
+        let mut req = Request::builder()
+            .uri("http://example.com/path")
+            .version(Version::HTTP_11)
+            .header(HOST, "example.com")
+            .body(Empty::<Bytes>::new())?;
+
+        req.extensions_mut()
+            .insert(ClientCommonName(String::from("client.example.com")));
+
+        let mapped = map_request(req, "x-client-identity")?;
+
+        assert_eq!(mapped.headers()["x-client-identity"], "client.example.com");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+use http::Request;
+use tokio::time::Instant;
+
+use crate::config::{Config, RateLimit, RateLimitKey, Route};
+use crate::load_balancer::{ClientAddr, ClientCommonName};
+
+/// The number of buckets to keep before removing those that have refilled completely.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Identifies a bucket by the route and the client making requests to it.
+type BucketKey = (String, ClientKey);
+
+/// Identifies the client making a request, keeping values from the configured key apart from
+/// client addresses so that neither can be used to take tokens from the other.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Keyed(String),
+    /// The address of a client whose request has no value for the configured key.
+    Addr(IpAddr),
+    /// Requests that cannot be told apart at all, which share a single bucket.
+    Unknown,
+}
+
+/// The tokens remaining for a single client on a single route, along with the limit they were
+/// last taken under so that the bucket can be checked without knowing which route it is for.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    requests_per_second: f64,
+    capacity: f64,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.capacity()),
+            updated: now,
+            requests_per_second: f64::from(limit.requests_per_second),
+            capacity: f64::from(limit.capacity()),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let refilled = self.tokens + elapsed * self.requests_per_second;
+
+        self.tokens = refilled.min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token from the bucket if there is one available, applying any change to the limit
+    /// from here on.
+    fn try_acquire(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(now);
+
+        self.requests_per_second = f64::from(limit.requests_per_second);
+        self.capacity = f64::from(limit.capacity());
+        self.tokens = self.tokens.min(self.capacity);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+
+        true
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens + elapsed * self.requests_per_second >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    entries: HashMap<BucketKey, TokenBucket>,
+    /// The number of buckets at which to next remove the full ones, which grows with the number
+    /// still in use so that the sweep only runs once for every so many new buckets.
+    sweep_at: usize,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            sweep_at: MAX_BUCKETS,
+        }
+    }
+}
+
+impl Buckets {
+    fn try_acquire(&mut self, key: BucketKey, limit: &RateLimit, now: Instant) -> bool {
+        if let Some(bucket) = self.entries.get_mut(&key) {
+            return bucket.try_acquire(limit, now);
+        }
+
+        if self.entries.len() >= self.sweep_at {
+            self.entries.retain(|_, bucket| !bucket.is_full(now));
+            self.sweep_at = MAX_BUCKETS.max(self.entries.len() * 2);
+        }
+
+        self.entries
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .try_acquire(limit, now)
+    }
+}
+
+/// Applies the rate limit for each route, falling back to the default from the configuration.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: Arc<ArcSwap<Config>>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: Arc<ArcSwap<Config>>) -> Self {
+        Self {
+            config,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Checks whether a request to a route is allowed, taking a token from the client's bucket if
+    /// the route is rate limited. Requests without the value the limit is keyed by are limited by
+    /// the address of the client instead, so leaving it out does not avoid the limit.
+    pub fn allow<B>(&self, route: &Route, req: &Request<B>) -> bool {
+        let config = self.config.load();
+
+        let Some(limit) = route.rate_limit.as_ref().or(config.alb.rate_limit.as_ref()) else {
+            return true;
+        };
+
+        let client_key = client_key(&limit.key, req);
+
+        let route_key = format!(
+            "{}{}",
+            route.host,
+            route.prefix.as_deref().unwrap_or_default()
+        );
+
+        self.buckets
+            .lock()
+            .unwrap()
+            .try_acquire((route_key, client_key), limit, Instant::now())
+    }
+}
+
+/// Gets the value that identifies the client making a request, falling back to its address.
+fn client_key<B>(key: &RateLimitKey, req: &Request<B>) -> ClientKey {
+    let value = match key {
+        RateLimitKey::ClientIp => None,
+        RateLimitKey::CommonName => req
+            .extensions()
+            .get::<ClientCommonName>()
+            .map(|common_name| common_name.0.clone()),
+        RateLimitKey::Header { name } => req
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned),
+    };
+
+    if let Some(value) = value {
+        return ClientKey::Keyed(value);
+    }
+
+    req.extensions()
+        .get::<ClientAddr>()
+        .map_or(ClientKey::Unknown, |addr| ClientKey::Addr(addr.0.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use arc_swap::ArcSwap;
+    use color_eyre::eyre::Result;
+    use http::Request;
+    use tokio::time::Instant;
+
+    use crate::config::{AlbConfig, Config, RateLimit, RateLimitKey, Route, Scheme};
+    use crate::load_balancer::rate_limit::{
+        Buckets, ClientKey, RateLimiter, TokenBucket, MAX_BUCKETS,
+    };
+    use crate::load_balancer::ClientAddr;
+
+    fn create_limit(requests_per_second: u32, burst: Option<u32>) -> RateLimit {
+        RateLimit {
+            requests_per_second,
+            burst,
+            key: RateLimitKey::ClientIp,
+        }
+    }
+
+    fn create_limiter(default: Option<RateLimit>) -> RateLimiter {
+        let config = Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::new(),
+                tls: None,
+                mtls: None,
+                acme: None,
+                rate_limit: default,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services: HashMap::new(),
+        };
+
+        RateLimiter::new(Arc::new(ArcSwap::from_pointee(config)))
+    }
+
+    fn request_from(ip: [u8; 4]) -> Result<Request<()>> {
+        let mut req = Request::builder().uri("http://example.com/").body(())?;
+
+        let addr = SocketAddr::from((ip, 4000));
+        req.extensions_mut().insert(ClientAddr(addr));
+
+        Ok(req)
+    }
+
+    #[test]
+    fn buckets_refill_at_the_configured_rate() {
+        let limit = create_limit(2, Some(4));
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&limit, now);
+
+        for _ in 0..4 {
+            assert!(bucket.try_acquire(&limit, now));
+        }
+
+        assert!(!bucket.try_acquire(&limit, now));
+
+        // Half a second is enough for one more request at 2 per second
+        let later = now + Duration::from_millis(500);
+
+        assert!(bucket.try_acquire(&limit, later));
+        assert!(!bucket.try_acquire(&limit, later));
+        assert!(!bucket.is_full(later));
+        assert!(bucket.is_full(later + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn clients_are_limited_independently() -> Result<()> {
+        let limiter = create_limiter(None);
+        let route = Route {
+            host: String::from("example.com"),
+            rate_limit: Some(create_limit(1, Some(2))),
+            ..Default::default()
+        };
+
+        let first = request_from([10, 0, 0, 1])?;
+        let second = request_from([10, 0, 0, 2])?;
+
+        assert!(limiter.allow(&route, &first));
+        assert!(limiter.allow(&route, &first));
+        assert!(!limiter.allow(&route, &first));
+
+        assert!(limiter.allow(&route, &second));
+
+        Ok(())
+    }
+
+    #[test]
+    fn routes_fall_back_to_the_default_limit() -> Result<()> {
+        let route = Route {
+            host: String::from("example.com"),
+            ..Default::default()
+        };
+
+        let req = request_from([10, 0, 0, 1])?;
+
+        let unlimited = create_limiter(None);
+        assert!((0..100).all(|_| unlimited.allow(&route, &req)));
+
+        let limited = create_limiter(Some(create_limit(1, None)));
+        assert!(limited.allow(&route, &req));
+        assert!(!limited.allow(&route, &req));
+
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_keyed_by_header() -> Result<()> {
+        let limiter = create_limiter(None);
+        let route = Route {
+            host: String::from("example.com"),
+            rate_limit: Some(RateLimit {
+                key: RateLimitKey::Header {
+                    name: String::from("x-api-key"),
+                },
+                ..create_limit(1, None)
+            }),
+            ..Default::default()
+        };
+
+        let with_key = |key: &str| {
+            Request::builder()
+                .uri("http://example.com/")
+                .header("x-api-key", key)
+                .body(())
+        };
+
+        assert!(limiter.allow(&route, &with_key("first")?));
+        assert!(!limiter.allow(&route, &with_key("first")?));
+        assert!(limiter.allow(&route, &with_key("second")?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn requests_without_a_key_are_limited_by_address() -> Result<()> {
+        let limiter = create_limiter(None);
+        let route = Route {
+            host: String::from("example.com"),
+            rate_limit: Some(RateLimit {
+                key: RateLimitKey::Header {
+                    name: String::from("x-api-key"),
+                },
+                ..create_limit(1, None)
+            }),
+            ..Default::default()
+        };
+
+        let first = request_from([10, 0, 0, 1])?;
+        let second = request_from([10, 0, 0, 2])?;
+
+        assert!(limiter.allow(&route, &first));
+        assert!(!limiter.allow(&route, &first));
+        assert!(limiter.allow(&route, &second));
+
+        // A key that looks like an address has its own bucket
+        let mut keyed = request_from([10, 0, 0, 1])?;
+        keyed.headers_mut().insert("x-api-key", "10.0.0.1".parse()?);
+
+        assert!(limiter.allow(&route, &keyed));
+
+        // Requests that cannot be told apart share a bucket
+        let unknown = Request::builder().uri("http://example.com/").body(())?;
+
+        assert!(limiter.allow(&route, &unknown));
+        assert!(!limiter.allow(&route, &unknown));
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_buckets_are_swept_by_their_own_limit() {
+        let mut buckets = Buckets::default();
+        let now = Instant::now();
+
+        let slow = create_limit(1, Some(10));
+        let fast = create_limit(100, None);
+
+        let key =
+            |route: &str, client: usize| (route.to_owned(), ClientKey::Keyed(client.to_string()));
+
+        // A slow bucket takes seconds to refill, so it is still in use after one second
+        for _ in 0..3 {
+            buckets.try_acquire(key("slow", 0), &slow, now);
+        }
+
+        for client in 1..MAX_BUCKETS {
+            buckets.try_acquire(key("fast", client), &fast, now);
+        }
+
+        let later = now + Duration::from_secs(1);
+
+        // Existing buckets never trigger a sweep, and the one used here is no longer full
+        buckets.try_acquire(key("fast", 1), &fast, later);
+        assert_eq!(buckets.entries.len(), MAX_BUCKETS);
+
+        buckets.try_acquire(key("fast", MAX_BUCKETS), &fast, later);
+
+        let mut remaining: Vec<_> = buckets.entries.keys().map(|(route, _)| route).collect();
+        remaining.sort();
+
+        assert_eq!(remaining, vec!["fast", "fast", "slow"]);
+        assert_eq!(buckets.sweep_at, MAX_BUCKETS);
+    }
+}
@@ -0,0 +1,212 @@
+use hyper::http::uri::PathAndQuery;
+use hyper::Uri;
+
+use crate::config::{AlbConfig, HstsPolicy, Route, Scheme};
+use crate::load_balancer::ClientScheme;
+use crate::service_registry::{host_match_specificity, strip_port};
+
+/// Gets where to redirect a request to, if it was made over plaintext to a route that requires
+/// HTTPS or to one of the route's aliases rather than its canonical host.
+pub fn redirect_location(
+    alb: &AlbConfig,
+    route: &Route,
+    scheme: ClientScheme,
+    host: &str,
+    uri: &Uri,
+) -> Option<String> {
+    let upgrade =
+        scheme == ClientScheme::Http && route.https_redirect.unwrap_or(alb.https_redirect);
+
+    // Requests for an alias go to the canonical host, which wildcard routes do not have
+    let hostname = strip_port(host);
+    let canonicalise =
+        !route.host.starts_with("*.") && host_match_specificity(&route.host, hostname).is_none();
+
+    if !upgrade && !canonicalise {
+        return None;
+    }
+
+    let hostname = if canonicalise { &route.host } else { hostname };
+    let path_and_query = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+
+    if !upgrade {
+        let port = &host[strip_port(host).len()..];
+
+        return Some(format!(
+            "{}://{hostname}{port}{path_and_query}",
+            scheme.as_str()
+        ));
+    }
+
+    // Any port in the host belongs to the plaintext listener, so swap it for the HTTPS one
+    let authority = match alb.ports.get(&Scheme::Https) {
+        Some(443) | None => hostname.to_owned(),
+        Some(port) => format!("{hostname}:{port}"),
+    };
+
+    Some(format!("https://{authority}{path_and_query}"))
+}
+
+/// Gets the HSTS policy for responses from a route, which only applies to requests over HTTPS.
+pub fn hsts_policy<'a>(
+    alb: &'a AlbConfig,
+    route: &'a Route,
+    scheme: ClientScheme,
+) -> Option<&'a HstsPolicy> {
+    if scheme != ClientScheme::Https {
+        return None;
+    }
+
+    route.hsts.as_ref().or(alb.hsts.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    use color_eyre::eyre::Result;
+    use hyper::Uri;
+
+    use crate::config::{AlbConfig, HstsPolicy, Route, Scheme};
+    use crate::load_balancer::redirect::{hsts_policy, redirect_location};
+    use crate::load_balancer::ClientScheme;
+
+    fn create_alb(https_port: u16, https_redirect: bool) -> AlbConfig {
+        AlbConfig {
+            addr: Ipv4Addr::LOCALHOST,
+            ports: HashMap::from([(Scheme::Http, 80), (Scheme::Https, https_port)]),
+            reconciliation: String::new(),
+            tls: None,
+            mtls: None,
+            acme: None,
+            rate_limit: None,
+            access_log: None,
+            https_redirect,
+            hsts: None,
+            admin: None,
+        }
+    }
+
+    fn create_route() -> Route {
+        Route {
+            host: String::from("example.com"),
+            aliases: vec![String::from("www.example.com")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plaintext_requests_are_upgraded_when_configured() -> Result<()> {
+        let uri: Uri = "/path?query=1".parse()?;
+        let route = create_route();
+
+        let location = |alb: &AlbConfig, route: &Route, scheme| {
+            redirect_location(alb, route, scheme, "example.com", &uri)
+        };
+
+        assert_eq!(
+            location(&create_alb(443, false), &route, ClientScheme::Http),
+            None
+        );
+        assert_eq!(
+            location(&create_alb(443, true), &route, ClientScheme::Http),
+            Some(String::from("https://example.com/path?query=1"))
+        );
+        assert_eq!(
+            location(&create_alb(8443, true), &route, ClientScheme::Http),
+            Some(String::from("https://example.com:8443/path?query=1"))
+        );
+        assert_eq!(
+            location(&create_alb(443, true), &route, ClientScheme::Https),
+            None
+        );
+
+        // Routes can opt out of the default
+        let opted_out = Route {
+            https_redirect: Some(false),
+            ..create_route()
+        };
+
+        assert_eq!(
+            location(&create_alb(443, true), &opted_out, ClientScheme::Http),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn aliases_are_redirected_to_the_canonical_host() -> Result<()> {
+        let uri: Uri = "/path".parse()?;
+        let route = create_route();
+
+        assert_eq!(
+            redirect_location(
+                &create_alb(443, false),
+                &route,
+                ClientScheme::Https,
+                "www.example.com",
+                &uri
+            ),
+            Some(String::from("https://example.com/path"))
+        );
+        assert_eq!(
+            redirect_location(
+                &create_alb(443, true),
+                &route,
+                ClientScheme::Http,
+                "www.example.com",
+                &uri
+            ),
+            Some(String::from("https://example.com/path"))
+        );
+
+        // Ports and differences in case do not make the host an alias
+        assert_eq!(
+            redirect_location(
+                &create_alb(443, false),
+                &route,
+                ClientScheme::Http,
+                "Example.com:8080",
+                &uri
+            ),
+            None
+        );
+        assert_eq!(
+            redirect_location(
+                &create_alb(443, false),
+                &route,
+                ClientScheme::Http,
+                "www.example.com:8080",
+                &uri
+            ),
+            Some(String::from("http://example.com:8080/path"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn hsts_only_applies_over_https() {
+        let policy = HstsPolicy {
+            max_age: 600,
+            include_subdomains: true,
+            preload: false,
+        };
+
+        let route = Route {
+            hsts: Some(policy.clone()),
+            ..create_route()
+        };
+
+        let alb = create_alb(443, false);
+
+        assert_eq!(hsts_policy(&alb, &route, ClientScheme::Http), None);
+        assert_eq!(
+            hsts_policy(&alb, &route, ClientScheme::Https),
+            Some(&policy)
+        );
+        assert_eq!(policy.header_value(), "max-age=600; includeSubDomains");
+    }
+}
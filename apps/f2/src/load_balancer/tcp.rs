@@ -1,32 +1,37 @@
-use std::net::SocketAddrV4;
+use std::net::{SocketAddr, SocketAddrV4};
 use std::sync::Arc;
+use std::time::Instant;
 
+use chrono::Utc;
 use color_eyre::eyre::{eyre, Result};
-use rand::prelude::SmallRng;
-use rand::Rng;
 use tokio::io::copy_bidirectional;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 use tokio_rustls::TlsAcceptor;
 
-use crate::service_registry::ServiceRegistry;
+use crate::load_balancer::access_log::{AccessLogEntry, AccessLogger};
+use crate::load_balancer::metrics::ProxyMetrics;
+use crate::service_registry::{SelectionContext, ServiceRegistry};
 
 pub struct TcpTlsProxy {
     service_registry: Arc<RwLock<ServiceRegistry>>,
-    rng: Arc<Mutex<SmallRng>>,
     acceptor: TlsAcceptor,
+    access_logger: Option<Arc<AccessLogger>>,
+    metrics: ProxyMetrics,
 }
 
 impl TcpTlsProxy {
     pub fn new(
         service_registry: Arc<RwLock<ServiceRegistry>>,
-        rng: Arc<Mutex<SmallRng>>,
         acceptor: TlsAcceptor,
+        access_logger: Option<Arc<AccessLogger>>,
+        metrics: ProxyMetrics,
     ) -> Self {
         Self {
             service_registry,
-            rng,
             acceptor,
+            access_logger,
+            metrics,
         }
     }
 
@@ -43,10 +48,20 @@ impl TcpTlsProxy {
 
         let acceptor = self.acceptor.clone();
         let service_registry = Arc::clone(&self.service_registry);
-        let rng = Arc::clone(&self.rng);
+        let access_logger = self.access_logger.clone();
+        let metrics = self.metrics.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(acceptor, service_registry, rng, stream).await {
+            if let Err(e) = handle_connection(
+                acceptor,
+                service_registry,
+                access_logger,
+                metrics,
+                stream,
+                peer_addr,
+            )
+            .await
+            {
                 tracing::warn!(%peer_addr, %e, "error handling TCP TLS connection");
             }
         });
@@ -58,10 +73,18 @@ impl TcpTlsProxy {
 async fn handle_connection(
     acceptor: TlsAcceptor,
     service_registry: Arc<RwLock<ServiceRegistry>>,
-    rng: Arc<Mutex<SmallRng>>,
+    access_logger: Option<Arc<AccessLogger>>,
+    metrics: ProxyMetrics,
     stream: TcpStream,
+    peer_addr: SocketAddr,
 ) -> Result<()> {
-    let mut tls_stream = acceptor.accept(stream).await?;
+    let timestamp = Utc::now();
+    let started = Instant::now();
+    let _connection = metrics.track_connection("tls");
+
+    let mut tls_stream = acceptor.accept(stream).await.inspect_err(|_| {
+        metrics.record_tls_handshake_failure("tls");
+    })?;
 
     let sni = tls_stream
         .get_ref()
@@ -72,25 +95,50 @@ async fn handle_connection(
 
     let read_lock = service_registry.read().await;
 
-    let Some((downstreams, port)) = read_lock.find_downstreams(&sni, "") else {
+    let Some(downstreams) = read_lock.find_downstreams(&sni, "") else {
         return Err(eyre!("no downstream found for SNI hostname {sni}"));
     };
 
-    let downstream_addr = {
-        let mut rng = rng.lock().await;
-        let idx = rng.next_u32() as usize % downstreams.len();
-        downstreams
-            .get_index(idx)
-            .ok_or_else(|| eyre!("no downstream available for {sni}"))?
-            .addr
+    if downstreams.is_empty() {
+        return Err(eyre!("no healthy downstreams for SNI hostname {sni}"));
+    }
+
+    let context = SelectionContext {
+        client_ip: Some(peer_addr.ip()),
+        headers: None,
     };
 
+    // Held for the lifetime of the connection so it counts as outstanding
+    let downstream = downstreams
+        .select(&context)
+        .ok_or_else(|| eyre!("no downstream available for {sni}"))?;
+
+    let port = downstreams.port();
+
     drop(read_lock);
 
-    let addr = SocketAddrV4::new(downstream_addr, port);
+    let addr = SocketAddrV4::new(downstream.addr, port);
+
+    tracing::debug!(%sni, %peer_addr, id = %downstream.id, %addr, "proxying connection downstream");
     let mut backend = TcpStream::connect(addr).await?;
 
-    copy_bidirectional(&mut tls_stream, &mut backend).await?;
+    let (_, bytes) = copy_bidirectional(&mut tls_stream, &mut backend).await?;
+
+    if let Some(access_logger) = access_logger {
+        access_logger.record(&AccessLogEntry {
+            timestamp,
+            protocol: "tls",
+            client_ip: Some(peer_addr.ip()),
+            common_name: None,
+            host: sni,
+            method: None,
+            path: None,
+            status: None,
+            upstream: Some(downstream.id.clone()),
+            latency_ms: started.elapsed().as_millis(),
+            bytes,
+        });
+    }
 
     Ok(())
 }
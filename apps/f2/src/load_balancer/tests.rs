@@ -1,28 +1,107 @@
 use std::collections::{HashMap, HashSet};
+use std::io::Read;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use arc_swap::ArcSwap;
 use color_eyre::eyre::Result;
+use flate2::read::GzDecoder;
 use http_body_util::{BodyExt, Full};
 use hyper::body::{Bytes, Incoming};
-use hyper::header::HOST;
+use hyper::header::{
+    ACCEPT_ENCODING, CACHE_CONTROL, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE, HOST, UPGRADE,
+};
 use hyper::service::service_fn;
 use hyper::{Request, Response, StatusCode};
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto::Builder;
-use tokio::net::TcpListener;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
 
-use crate::config::{AlbConfig, Config, Route, Scheme, Service};
+use crate::config::{
+    AlbConfig, Config, ExternalBytes, RateLimit, RateLimitKey, Route, Scheme, Service, TlsConfig,
+    TlsSecrets,
+};
 use crate::docker::api::StartedContainerDetails;
 use crate::docker::models::ContainerId;
 use crate::ipc::MessageBus;
 use crate::load_balancer::LoadBalancer;
 use crate::service_registry::ServiceRegistry;
 
+/// The domain of the certificate the HTTPS load balancer serves in tests.
+const TLS_DOMAIN: &str = "new.example.com";
+
+/// Accepts any certificate from the load balancer, since the test certificates are self-signed.
+#[derive(Debug)]
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &supported_algorithms())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &supported_algorithms())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        supported_algorithms().supported_schemes()
+    }
+}
+
+fn supported_algorithms() -> WebPkiSupportedAlgorithms {
+    rustls::crypto::ring::default_provider().signature_verification_algorithms
+}
+
+/// Opens a TLS connection to the load balancer for the test domain.
+async fn connect_tls(addr: SocketAddr) -> Result<TlsStream<TcpStream>> {
+    let mut config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_no_client_auth();
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    let stream = TcpStream::connect(addr).await?;
+    let connector = TlsConnector::from(Arc::new(config));
+
+    Ok(connector
+        .connect(ServerName::try_from(TLS_DOMAIN)?, stream)
+        .await?)
+}
+
 fn create_service<T: Into<Option<&'static str>>>(
     host: &'static str,
     port: u16,
@@ -33,6 +112,7 @@ fn create_service<T: Into<Option<&'static str>>>(
             host: String::from(host),
             prefix: path_prefix.into().map(ToOwned::to_owned),
             port,
+            ..Default::default()
         }]),
         ..Default::default()
     }
@@ -82,6 +162,35 @@ async fn spawn_fixed_response_server(response: &'static str) -> Result<SocketAdd
 }
 
 async fn spawn_load_balancer(service_registry: ServiceRegistry) -> Result<SocketAddr> {
+    spawn_load_balancer_on(service_registry, Scheme::Http, None).await
+}
+
+/// Spawns a load balancer serving HTTPS with the test certificate for `new.example.com`.
+async fn spawn_https_load_balancer(service_registry: ServiceRegistry) -> Result<SocketAddr> {
+    // Installed by `main` outside of tests
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let secrets = TlsSecrets::new(
+        ExternalBytes::Filesystem {
+            path: PathBuf::from("resources/certificates/new.crt"),
+        },
+        ExternalBytes::Filesystem {
+            path: PathBuf::from("resources/certificates/new.key"),
+        },
+    );
+
+    let tls = TlsConfig {
+        domains: HashMap::from([(String::from(TLS_DOMAIN), secrets)]),
+    };
+
+    spawn_load_balancer_on(service_registry, Scheme::Https, Some(tls)).await
+}
+
+async fn spawn_load_balancer_on(
+    service_registry: ServiceRegistry,
+    scheme: Scheme,
+    tls: Option<TlsConfig>,
+) -> Result<SocketAddr> {
     let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
     let listener = TcpListener::bind(&addr).await?;
 
@@ -91,12 +200,21 @@ async fn spawn_load_balancer(service_registry: ServiceRegistry) -> Result<Socket
     let config = Config {
         alb: AlbConfig {
             addr: Ipv4Addr::LOCALHOST,
-            ports: HashMap::from([(Scheme::Http, resolved_addr.port())]),
+            ports: HashMap::from([(scheme.clone(), resolved_addr.port())]),
             reconciliation: String::from("/reconciliation"),
             tls: None,
             mtls: None,
+            acme: None,
+            rate_limit: None,
+            access_log: None,
+            https_redirect: false,
+            hsts: None,
+            admin: None,
         },
         secrets: None,
+        metrics: None,
+        polling: None,
+        jobs: HashMap::new(),
         services: HashMap::new(),
     };
 
@@ -107,10 +225,10 @@ async fn spawn_load_balancer(service_registry: ServiceRegistry) -> Result<Socket
         let message_bus = Arc::clone(&message_bus);
         let load_balancer = LoadBalancer::new(service_registry, config, message_bus);
 
-        let listeners = HashMap::from([(Scheme::Http, listener)]);
+        let listeners = HashMap::from([(scheme, listener)]);
 
         load_balancer
-            .run(listeners, None, None)
+            .run(listeners, tls, None)
             .await
             .expect("Failed to run load balancer");
     });
@@ -154,9 +272,56 @@ async fn can_proxy_requests_based_on_host_header() -> Result<()> {
 }
 
 #[tokio::test]
-async fn request_paths_are_proxied_downstream() -> Result<()> {
+async fn requests_over_the_rate_limit_are_rejected() -> Result<()> {
     let host = "opentracker.app";
+    let downstream_addr = spawn_fixed_response_server("Hello from OpenTracker").await?;
+
+    let mut service = create_service(host, downstream_addr.port(), None);
+    service.routes = service
+        .routes
+        .into_iter()
+        .map(|route| Route {
+            rate_limit: Some(RateLimit {
+                requests_per_second: 1,
+                burst: Some(2),
+                key: RateLimitKey::ClientIp,
+            }),
+            ..route
+        })
+        .collect();
+
+    let mut service_registry = ServiceRegistry::new();
+
+    service_registry.define("opentracker", service);
+    add_container(&mut service_registry, "opentracker");
+
+    let addr = spawn_load_balancer(service_registry).await?;
+    let client = Client::builder(TokioExecutor::new()).build_http();
+
+    let mut statuses = Vec::new();
+
+    for _ in 0..3 {
+        let request = Request::builder()
+            .uri(format!("http://{}", addr))
+            .header(HOST, host)
+            .body(Full::<Bytes>::default())?;
+
+        statuses.push(client.request(request).await?.status());
+    }
+
+    assert_eq!(
+        statuses,
+        vec![
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS
+        ]
+    );
 
+    Ok(())
+}
+
+async fn spawn_health_check_server() -> Result<SocketAddr> {
     let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
     let listener = TcpListener::bind(&addr).await?;
 
@@ -174,6 +339,14 @@ async fn request_paths_are_proxied_downstream() -> Result<()> {
         }
     });
 
+    Ok(resolved_addr)
+}
+
+#[tokio::test]
+async fn request_paths_are_proxied_downstream() -> Result<()> {
+    let host = "opentracker.app";
+    let resolved_addr = spawn_health_check_server().await?;
+
     let mut service_registry = ServiceRegistry::new();
 
     service_registry.define("service", create_service(host, resolved_addr.port(), None));
@@ -203,6 +376,321 @@ async fn request_paths_are_proxied_downstream() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn route_prefixes_can_be_stripped_before_proxying() -> Result<()> {
+    let host = "opentracker.app";
+    let resolved_addr = spawn_health_check_server().await?;
+
+    let mut service = create_service(host, resolved_addr.port(), "/app");
+    service.routes = service
+        .routes
+        .into_iter()
+        .map(|route| Route {
+            strip_prefix: true,
+            ..route
+        })
+        .collect();
+
+    let mut service_registry = ServiceRegistry::new();
+
+    service_registry.define("service", service);
+    add_container(&mut service_registry, "service");
+
+    let addr = spawn_load_balancer(service_registry).await?;
+    let client = Client::builder(TokioExecutor::new()).build_http();
+
+    let request = Request::builder()
+        .uri(format!("http://{}/app/health", addr))
+        .header(HOST, host)
+        .body(Full::<Bytes>::default())?;
+
+    let response = client.request(request).await?;
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+async fn spawn_static_asset_server(body: String, hits: Arc<AtomicUsize>) -> Result<SocketAddr> {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let listener = TcpListener::bind(&addr).await?;
+
+    let resolved_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let io = TokioIo::new(stream);
+            let body = body.clone();
+            let hits = Arc::clone(&hits);
+
+            let service = service_fn(move |_| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let body = body.clone();
+
+                async move {
+                    Response::builder()
+                        .header(CONTENT_TYPE, "text/css")
+                        .header(CACHE_CONTROL, "public, max-age=60")
+                        .body(Full::<Bytes>::from(body))
+                }
+            });
+
+            Builder::new(TokioExecutor::new())
+                .serve_connection(io, service)
+                .await
+                .unwrap();
+        }
+    });
+
+    Ok(resolved_addr)
+}
+
+#[tokio::test]
+async fn cached_responses_are_compressed_for_each_client() -> Result<()> {
+    let host = "uptime.app";
+    let body = "body { margin: 0; }\n".repeat(100);
+    let hits = Arc::new(AtomicUsize::new(0));
+    let downstream_addr = spawn_static_asset_server(body.clone(), Arc::clone(&hits)).await?;
+
+    let mut service = create_service(host, downstream_addr.port(), None);
+    service.routes = service
+        .routes
+        .into_iter()
+        .map(|route| Route {
+            cache: true,
+            ..route
+        })
+        .collect();
+
+    let mut service_registry = ServiceRegistry::new();
+
+    service_registry.define("uptime", service);
+    add_container(&mut service_registry, "uptime");
+
+    let addr = spawn_load_balancer(service_registry).await?;
+    let client = Client::builder(TokioExecutor::new()).build_http();
+
+    for accept_encoding in ["gzip", "gzip", "identity"] {
+        let request = Request::builder()
+            .uri(format!("http://{}/assets/app.css", addr))
+            .header(HOST, host)
+            .header(ACCEPT_ENCODING, accept_encoding)
+            .body(Full::<Bytes>::default())?;
+
+        let response = client.request(request).await?;
+        let encoding = response.headers().get(CONTENT_ENCODING).cloned();
+        let bytes = response.into_body().collect().await?.to_bytes();
+
+        let mut text = String::new();
+
+        if encoding.is_some_and(|encoding| encoding == "gzip") {
+            GzDecoder::new(bytes.as_ref()).read_to_string(&mut text)?;
+        } else {
+            assert_eq!(accept_encoding, "identity");
+            text = String::from_utf8(bytes.to_vec())?;
+        }
+
+        assert_eq!(text, body);
+    }
+
+    // Only the first request reached the downstream
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+async fn spawn_echo_server() -> Result<SocketAddr> {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let listener = TcpListener::bind(&addr).await?;
+
+    let resolved_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let io = TokioIo::new(stream);
+
+            // Switches to a protocol that echoes back whatever the client sends
+            let service = service_fn(|mut req: Request<Incoming>| async move {
+                let on_upgrade = hyper::upgrade::on(&mut req);
+
+                tokio::spawn(async move {
+                    let mut upgraded = TokioIo::new(on_upgrade.await.unwrap());
+                    let mut buffer = [0; 64];
+
+                    while let Ok(read @ 1..) = upgraded.read(&mut buffer).await {
+                        upgraded.write_all(&buffer[..read]).await.unwrap();
+                    }
+                });
+
+                Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(CONNECTION, "upgrade")
+                    .header(UPGRADE, "echo")
+                    .body(Full::<Bytes>::default())
+            });
+
+            Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(io, service)
+                .await
+                .unwrap();
+        }
+    });
+
+    Ok(resolved_addr)
+}
+
+#[tokio::test]
+async fn upgraded_connections_are_spliced_downstream() -> Result<()> {
+    let host = "today.app";
+    let downstream_addr = spawn_echo_server().await?;
+
+    let mut service_registry = ServiceRegistry::new();
+
+    service_registry.define("today", create_service(host, downstream_addr.port(), None));
+    add_container(&mut service_registry, "today");
+
+    let addr = spawn_load_balancer(service_registry).await?;
+    let stream = TcpStream::connect(addr).await?;
+
+    assert_upgrade_is_echoed(stream, host).await
+}
+
+#[tokio::test]
+async fn upgraded_tls_connections_are_spliced_downstream() -> Result<()> {
+    let downstream_addr = spawn_echo_server().await?;
+
+    let mut service_registry = ServiceRegistry::new();
+
+    service_registry.define(
+        "today",
+        create_service(TLS_DOMAIN, downstream_addr.port(), None),
+    );
+    add_container(&mut service_registry, "today");
+
+    let addr = spawn_https_load_balancer(service_registry).await?;
+    let stream = connect_tls(addr).await?;
+
+    assert_upgrade_is_echoed(stream, TLS_DOMAIN).await
+}
+
+/// Upgrades a connection to the echo server and checks that bytes written to it come back.
+async fn assert_upgrade_is_echoed<S>(mut stream: S, host: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = format!(
+        "GET /socket HTTP/1.1\r\nHost: {host}\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n"
+    );
+
+    stream.write_all(handshake.as_bytes()).await?;
+
+    let mut response = Vec::new();
+
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).await?;
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8(response)?;
+
+    assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+    assert!(response.to_ascii_lowercase().contains("upgrade: echo"));
+
+    stream.write_all(b"ping").await?;
+
+    let mut echoed = [0; 4];
+    stream.read_exact(&mut echoed).await?;
+
+    assert_eq!(&echoed, b"ping");
+
+    Ok(())
+}
+
+/// Responds with the value of a request header, so tests can check what reached the downstream.
+async fn spawn_header_echo_server(header: &'static str) -> Result<SocketAddr> {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let listener = TcpListener::bind(&addr).await?;
+
+    let resolved_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let io = TokioIo::new(stream);
+
+            let service = service_fn(move |req: Request<Incoming>| async move {
+                let value = req
+                    .headers()
+                    .get(header)
+                    .map(|value| Bytes::copy_from_slice(value.as_bytes()))
+                    .unwrap_or_default();
+
+                Ok::<_, hyper::Error>(Response::new(Full::new(value)))
+            });
+
+            Builder::new(TokioExecutor::new())
+                .serve_connection(io, service)
+                .await
+                .unwrap();
+        }
+    });
+
+    Ok(resolved_addr)
+}
+
+/// Sends a request over HTTPS, with a spoofed `X-Forwarded-For`, and gets the value of a header
+/// as it was received downstream.
+async fn get_header_over_https(header: &'static str) -> Result<Bytes> {
+    let downstream_addr = spawn_header_echo_server(header).await?;
+
+    let mut service_registry = ServiceRegistry::new();
+
+    service_registry.define(
+        "today",
+        create_service(TLS_DOMAIN, downstream_addr.port(), None),
+    );
+    add_container(&mut service_registry, "today");
+
+    let addr = spawn_https_load_balancer(service_registry).await?;
+    let stream = connect_tls(addr).await?;
+
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    let request = Request::builder()
+        .uri("/")
+        .header(HOST, TLS_DOMAIN)
+        .header("x-forwarded-for", "1.2.3.4")
+        .body(Full::<Bytes>::default())?;
+
+    let response = sender.send_request(request).await?;
+    let body = response.into_body().collect().await?.to_bytes();
+
+    Ok(body)
+}
+
+#[tokio::test]
+async fn https_requests_carry_the_client_address() -> Result<()> {
+    assert_eq!(get_header_over_https("x-forwarded-for").await?, "127.0.0.1");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn https_requests_carry_forwarding_headers() -> Result<()> {
+    assert_eq!(get_header_over_https("x-forwarded-proto").await?, "https");
+    assert_eq!(
+        get_header_over_https("forwarded").await?,
+        format!("for=127.0.0.1;host=\"{TLS_DOMAIN}\";proto=https")
+    );
+
+    Ok(())
+}
+
 async fn get_response_body(
     client: &Client<HttpConnector, Full<Bytes>>,
     request: Request<Full<Bytes>>,
@@ -303,11 +791,13 @@ async fn can_proxy_to_different_ports_based_on_route_configuration() -> Result<(
                 host: String::from(internal_host),
                 prefix: None,
                 port: internal_addr.port(),
+                ..Default::default()
             },
             Route {
                 host: String::from(external_host),
                 prefix: None,
                 port: external_addr.port(),
+                ..Default::default()
             },
         ]),
         ..Default::default()
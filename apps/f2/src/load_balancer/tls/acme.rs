@@ -0,0 +1,555 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use color_eyre::eyre::{self, eyre, Result};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+use x509_parser::pem::Pem;
+
+use crate::config::{AcmeConfig, AcmeEnvironment, Config};
+use crate::load_balancer::tls::{parse_certified_key, CertificateResolver};
+use crate::service_registry::host_match_specificity;
+
+/// The path prefix that ACME servers request HTTP-01 challenge responses from.
+pub const CHALLENGE_PATH_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// How often to check whether any certificates need to be issued or renewed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long to wait before trying to issue a certificate for a domain again after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+impl From<AcmeEnvironment> for LetsEncrypt {
+    fn from(environment: AcmeEnvironment) -> Self {
+        match environment {
+            AcmeEnvironment::Staging => LetsEncrypt::Staging,
+            AcmeEnvironment::Production => LetsEncrypt::Production,
+        }
+    }
+}
+
+/// The key authorisations for pending HTTP-01 challenges, keyed by their token.
+#[derive(Debug, Default)]
+pub struct AcmeChallenges {
+    tokens: ArcSwap<HashMap<String, String>>,
+}
+
+impl AcmeChallenges {
+    /// Gets the response for a request to the challenge path, if it is for a pending challenge.
+    pub fn respond(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(CHALLENGE_PATH_PREFIX)?;
+
+        self.tokens.load().get(token).cloned()
+    }
+
+    pub fn insert(&self, token: &str, key_authorization: &str) {
+        self.tokens.rcu(|tokens| {
+            let mut tokens = HashMap::clone(tokens);
+            tokens.insert(token.to_owned(), key_authorization.to_owned());
+            tokens
+        });
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.tokens.rcu(|tokens| {
+            let mut tokens = HashMap::clone(tokens);
+            tokens.remove(token);
+            tokens
+        });
+    }
+}
+
+/// Obtains and renews certificates for every route host that does not have one configured,
+/// installing them into the certificate resolver as they are issued.
+pub struct AcmeManager {
+    acme: AcmeConfig,
+    config: Arc<ArcSwap<Config>>,
+    challenges: Arc<AcmeChallenges>,
+    resolver: Arc<CertificateResolver>,
+    expiries: HashMap<String, i64>,
+    retry_after: HashMap<String, Instant>,
+}
+
+impl AcmeManager {
+    pub fn new(
+        acme: AcmeConfig,
+        config: Arc<ArcSwap<Config>>,
+        challenges: Arc<AcmeChallenges>,
+        resolver: Arc<CertificateResolver>,
+    ) -> Self {
+        Self {
+            acme,
+            config,
+            challenges,
+            resolver,
+            expiries: HashMap::new(),
+            retry_after: HashMap::new(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut account = None;
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if account.is_none() {
+                match self.create_account().await {
+                    Ok(created) => account = Some(created),
+                    Err(error) => {
+                        tracing::error!(%error, "failed to create an ACME account");
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(account) = account.as_ref() {
+                self.ensure_certificates(account).await;
+            }
+        }
+    }
+
+    /// Restores the account stored by a previous run, only registering a new one with the ACME
+    /// server if there is none for the environment.
+    async fn create_account(&self) -> Result<Account> {
+        let credentials_path = self.credentials_path();
+
+        match tokio::fs::read(&credentials_path).await {
+            Ok(stored) => {
+                let credentials: AccountCredentials = serde_json::from_slice(&stored)?;
+
+                tracing::info!(?credentials_path, "restoring the stored ACME account");
+
+                return Ok(Account::builder()?.from_credentials(credentials).await?);
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        let contact = format!("mailto:{}", self.acme.contact);
+
+        let new_account = NewAccount {
+            contact: &[&contact],
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        };
+
+        tracing::info!(environment = ?self.acme.environment, "creating an ACME account");
+
+        let url = LetsEncrypt::from(self.acme.environment).url();
+        let (account, credentials) = Account::builder()?
+            .create(&new_account, url.to_owned(), None)
+            .await?;
+
+        tokio::fs::create_dir_all(&self.acme.storage).await?;
+        write_private_file(&credentials_path, &serde_json::to_vec(&credentials)?).await?;
+
+        tracing::info!(?credentials_path, "stored the ACME account credentials");
+
+        Ok(account)
+    }
+
+    /// Gets where the account credentials are stored, which differs between environments since an
+    /// account only exists on the server it was created with.
+    fn credentials_path(&self) -> PathBuf {
+        let environment = match self.acme.environment {
+            AcmeEnvironment::Staging => "staging",
+            AcmeEnvironment::Production => "production",
+        };
+
+        self.acme
+            .storage
+            .join(format!("account-{environment}.json"))
+    }
+
+    /// Gets the route hosts and aliases that need a certificate from ACME, which excludes any that
+    /// already have one in the configuration. Wildcard hosts are skipped as HTTP-01 challenges
+    /// cannot be used to issue wildcard certificates.
+    fn wanted_domains(&self) -> BTreeSet<String> {
+        let config = self.config.load();
+
+        config
+            .services
+            .values()
+            .flat_map(|service| &service.routes)
+            .flat_map(|route| std::iter::once(&route.host).chain(&route.aliases))
+            .filter(|host| !host.starts_with("*."))
+            .filter(|host| {
+                config.alb.tls.as_ref().is_none_or(|tls| {
+                    !tls.domains
+                        .keys()
+                        .any(|domain| host_match_specificity(domain, host).is_some())
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    async fn ensure_certificates(&mut self, account: &Account) {
+        let now = chrono::Utc::now().timestamp();
+        let renew_before = i64::from(self.acme.renew_before_days) * 24 * 60 * 60;
+
+        for domain in self.wanted_domains() {
+            if !self.expiries.contains_key(&domain) {
+                if let Err(error) = self.load_stored_certificate(&domain).await {
+                    tracing::debug!(%domain, %error, "no usable stored certificate for domain");
+                }
+            }
+
+            let due = self
+                .expiries
+                .get(&domain)
+                .is_none_or(|expiry| expiry - now < renew_before);
+
+            let waiting = self
+                .retry_after
+                .get(&domain)
+                .is_some_and(|retry_after| Instant::now() < *retry_after);
+
+            if !due || waiting {
+                continue;
+            }
+
+            tracing::info!(%domain, "requesting a certificate from ACME");
+
+            match self.issue(account, &domain).await {
+                Ok(()) => {
+                    self.retry_after.remove(&domain);
+                }
+                Err(error) => {
+                    tracing::error!(%domain, %error, "failed to obtain a certificate");
+                    self.retry_after
+                        .insert(domain, Instant::now() + RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    fn certificate_paths(&self, domain: &str) -> (PathBuf, PathBuf) {
+        let storage = &self.acme.storage;
+
+        (
+            storage.join(format!("{domain}.crt")),
+            storage.join(format!("{domain}.key")),
+        )
+    }
+
+    async fn load_stored_certificate(&mut self, domain: &str) -> Result<()> {
+        let (cert_path, key_path) = self.certificate_paths(domain);
+
+        let cert = tokio::fs::read(&cert_path).await?;
+        let key = tokio::fs::read(&key_path).await?;
+
+        self.install(domain, &cert, &key)?;
+
+        tracing::info!(%domain, "loaded a stored certificate");
+
+        Ok(())
+    }
+
+    fn install(&mut self, domain: &str, cert: &[u8], key: &[u8]) -> Result<()> {
+        let expiry = certificate_expiry(cert)?;
+        let certified_key = parse_certified_key(cert, key)?;
+
+        self.resolver.install(domain, certified_key);
+        self.expiries.insert(domain.to_owned(), expiry);
+
+        Ok(())
+    }
+
+    async fn issue(&mut self, account: &Account, domain: &str) -> Result<()> {
+        let identifiers = [Identifier::Dns(domain.to_owned())];
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+        let mut tokens = Vec::new();
+        let result = self.complete_order(&mut order, &mut tokens).await;
+
+        // Challenges are only needed while the order is being validated
+        for token in &tokens {
+            self.challenges.remove(token);
+        }
+
+        let (key, cert) = result?;
+
+        let (cert_path, key_path) = self.certificate_paths(domain);
+
+        tokio::fs::create_dir_all(&self.acme.storage).await?;
+        tokio::fs::write(&cert_path, &cert).await?;
+        write_private_file(&key_path, key.as_bytes()).await?;
+
+        self.install(domain, cert.as_bytes(), key.as_bytes())?;
+
+        tracing::info!(%domain, ?cert_path, "obtained and installed a new certificate");
+
+        Ok(())
+    }
+
+    /// Answers the HTTP-01 challenges for an order and waits for the certificate, returning the
+    /// private key and certificate chain.
+    async fn complete_order(
+        &self,
+        order: &mut Order,
+        tokens: &mut Vec<String>,
+    ) -> Result<(String, String)> {
+        let mut authorizations = order.authorizations();
+
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+
+            match &authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => eyre::bail!("authorization is {status:?} rather than pending"),
+            }
+
+            let mut challenge = authorization
+                .challenge(ChallengeType::Http01)
+                .ok_or_else(|| eyre!("no HTTP-01 challenge found for authorization"))?;
+
+            let token = challenge.token.clone();
+
+            self.challenges
+                .insert(&token, challenge.key_authorization().as_str());
+            tokens.push(token);
+
+            challenge.set_ready().await?;
+        }
+
+        let status = order.poll_ready(&RetryPolicy::default()).await?;
+
+        eyre::ensure!(
+            matches!(status, OrderStatus::Ready),
+            "order is {status:?} rather than ready"
+        );
+
+        let private_key = order.finalize().await?;
+        let cert_chain = order.poll_certificate(&RetryPolicy::default()).await?;
+
+        Ok((private_key, cert_chain))
+    }
+}
+
+/// Gets the earliest expiry of the certificates in a chain, as a Unix timestamp.
+fn certificate_expiry(chain: &[u8]) -> Result<i64> {
+    Pem::iter_from_buffer(chain)
+        .filter_map(Result::ok)
+        .filter_map(|pem| {
+            let x509 = pem.parse_x509().ok()?;
+            Some(x509.validity().not_after.timestamp())
+        })
+        .min()
+        .ok_or_else(|| eyre!("no certificates found in chain"))
+}
+
+/// Writes a file that contains a private key, such that only the owner can read it. Files from
+/// before this was enforced are tightened as well.
+async fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+
+    file.set_permissions(Permissions::from_mode(0o600)).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::fs::Permissions;
+    use std::net::Ipv4Addr;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use color_eyre::eyre::Result;
+
+    use crate::config::{
+        AcmeConfig, AcmeEnvironment, AlbConfig, Config, ExternalBytes, Route, Scheme, Service,
+        TlsConfig, TlsSecrets,
+    };
+    use crate::load_balancer::tls::acme::{
+        certificate_expiry, write_private_file, AcmeChallenges, AcmeManager,
+    };
+    use crate::load_balancer::tls::CertificateResolver;
+
+    fn create_manager(services: &[(&str, &str)], configured: &[&str]) -> AcmeManager {
+        let acme = AcmeConfig {
+            contact: String::from("admin@example.com"),
+            environment: AcmeEnvironment::Staging,
+            storage: PathBuf::from("/tmp/f2/acme"),
+            renew_before_days: 30,
+        };
+
+        let tls = TlsConfig {
+            domains: configured
+                .iter()
+                .map(|domain| {
+                    let path = ExternalBytes::Filesystem {
+                        path: PathBuf::new(),
+                    };
+
+                    (domain.to_string(), TlsSecrets::new(path.clone(), path))
+                })
+                .collect(),
+        };
+
+        let services = services
+            .iter()
+            .map(|(name, host)| {
+                let service = Service {
+                    routes: HashSet::from([Route {
+                        host: host.to_string(),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                };
+
+                (name.to_string(), service)
+            })
+            .collect();
+
+        let config = Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::new(),
+                tls: Some(tls),
+                mtls: None,
+                acme: Some(acme.clone()),
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services,
+        };
+
+        AcmeManager::new(
+            acme,
+            Arc::new(ArcSwap::from_pointee(config)),
+            Arc::default(),
+            Arc::new(CertificateResolver::default()),
+        )
+    }
+
+    #[test]
+    fn challenges_are_only_answered_while_pending() {
+        let challenges = AcmeChallenges::default();
+        let path = "/.well-known/acme-challenge/token";
+
+        assert_eq!(challenges.respond(path), None);
+
+        challenges.insert("token", "token.thumbprint");
+
+        assert_eq!(
+            challenges.respond(path),
+            Some(String::from("token.thumbprint"))
+        );
+        assert_eq!(challenges.respond("/token"), None);
+
+        challenges.remove("token");
+
+        assert_eq!(challenges.respond(path), None);
+    }
+
+    #[test]
+    fn certificates_are_wanted_for_hosts_without_configured_ones() {
+        let manager = create_manager(
+            &[
+                ("frontend", "example.com"),
+                ("backend", "api.example.com"),
+                ("internal", "internal.example.com"),
+            ],
+            &["internal.example.com"],
+        );
+
+        let wanted: Vec<_> = manager.wanted_domains().into_iter().collect();
+
+        assert_eq!(wanted, vec!["api.example.com", "example.com"]);
+    }
+
+    #[test]
+    fn certificates_are_not_wanted_for_wildcards() {
+        let manager = create_manager(
+            &[
+                ("tenants", "*.example.com"),
+                ("backend", "api.example.org"),
+                ("frontend", "example.net"),
+            ],
+            &["*.example.org"],
+        );
+
+        let wanted: Vec<_> = manager.wanted_domains().into_iter().collect();
+
+        assert_eq!(wanted, vec!["example.net"]);
+    }
+
+    #[tokio::test]
+    async fn stored_certificates_are_installed() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let domain = "new.example.com";
+
+        let mut manager = create_manager(&[("frontend", domain)], &[]);
+        manager.acme.storage = temp_dir.path().to_owned();
+
+        let (cert_path, key_path) = manager.certificate_paths(domain);
+
+        tokio::fs::copy("resources/certificates/new.crt", cert_path).await?;
+        tokio::fs::copy("resources/certificates/new.key", key_path).await?;
+
+        manager.load_stored_certificate(domain).await?;
+
+        let cert = tokio::fs::read("resources/certificates/new.crt").await?;
+
+        assert!(manager.resolver.issued.load().contains_key(domain));
+        assert_eq!(
+            manager.expiries.get(domain),
+            Some(&certificate_expiry(&cert)?)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_keys_are_only_readable_by_the_owner() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("example.com.key");
+
+        // Keys written before permissions were enforced should be tightened
+        std::fs::write(&path, "old")?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+
+        write_private_file(&path, b"key").await?;
+
+        let metadata = std::fs::metadata(&path)?;
+
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read(&path)?, b"key");
+
+        Ok(())
+    }
+}
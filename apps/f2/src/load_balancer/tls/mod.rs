@@ -5,14 +5,18 @@ use std::sync::Arc;
 use arc_swap::ArcSwap;
 use color_eyre::eyre::{eyre, Result};
 use itertools::Itertools;
-use mutual_tls::{AuthenticationLevel, AuthenticationLevelResolver};
 use rustls::crypto::ring::sign::any_supported_type;
-use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use rustls::server::{ClientHello, ResolvesServerCert};
 use rustls::sign::CertifiedKey;
 
 use crate::config::{Config, TlsSecrets};
 use crate::ipc::MessageBus;
+use crate::service_registry::host_match_specificity;
+
+pub use acme::{AcmeChallenges, AcmeManager, CHALLENGE_PATH_PREFIX};
+
+mod acme;
 
 type Configuration = HashMap<String, TlsSecrets>;
 type Domains = HashMap<String, Arc<CertifiedKey>>;
@@ -20,6 +24,7 @@ type Domains = HashMap<String, Arc<CertifiedKey>>;
 #[derive(Debug, Default)]
 pub struct CertificateResolver {
     domains: Arc<ArcSwap<Domains>>,
+    issued: ArcSwap<Domains>,
 }
 
 async fn resolve_and_parse_certificates(config: &Configuration) -> Result<Domains> {
@@ -74,6 +79,7 @@ impl CertificateResolver {
 
         let resolver = Self {
             domains: Arc::clone(&domains),
+            issued: ArcSwap::default(),
         };
 
         tokio::spawn({
@@ -88,9 +94,21 @@ impl CertificateResolver {
 
         Ok(resolver)
     }
+
+    /// Installs a certificate obtained at runtime, replacing any previously issued one for the
+    /// domain. Certificates from the configuration take precedence over issued ones.
+    pub fn install(&self, domain: &str, certified_key: CertifiedKey) {
+        let certified_key = Arc::new(certified_key);
+
+        self.issued.rcu(|issued| {
+            let mut issued = HashMap::clone(issued);
+            issued.insert(domain.to_owned(), Arc::clone(&certified_key));
+            issued
+        });
+    }
 }
 
-fn parse_certified_key(cert: &[u8], key: &[u8]) -> Result<CertifiedKey> {
+pub fn parse_certified_key(cert: &[u8], key: &[u8]) -> Result<CertifiedKey> {
     let mut cert = Cursor::new(cert);
     let mut key = Cursor::new(key);
 
@@ -112,15 +130,52 @@ fn parse_certified_key(cert: &[u8], key: &[u8]) -> Result<CertifiedKey> {
     Ok(certified_key)
 }
 
+/// Gets the common name from the subject of a certificate a client presented.
+pub fn client_common_name(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?;
+
+    common_name.as_str().ok().map(ToOwned::to_owned)
+}
+
 impl ResolvesServerCert for CertificateResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
         let server_name = client_hello.server_name()?;
-        let domains = self.domains.load();
 
-        domains.get(server_name).cloned()
+        find_certificate(&self.domains.load(), server_name)
+            .or_else(|| find_certificate(&self.issued.load(), server_name))
     }
 }
 
+/// Finds the certificate for a server name, preferring an exact match over the most specific
+/// wildcard certificate.
+fn find_certificate(domains: &Domains, server_name: &str) -> Option<Arc<CertifiedKey>> {
+    if let Some(certified_key) = domains.get(server_name) {
+        return Some(Arc::clone(certified_key));
+    }
+
+    domains
+        .iter()
+        .filter_map(|(domain, certified_key)| {
+            host_match_specificity(domain, server_name)
+                .map(|specificity| (specificity, certified_key))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, certified_key)| Arc::clone(certified_key))
+}
+
+/// Whether a client has to present a certificate to connect.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AuthenticationLevel {
+    Standard,
+    Mutual,
+}
+
+/// Decides whether a connection uses mutual TLS from the server name in its client hello.
+pub trait AuthenticationLevelResolver: Send + Sync + 'static {
+    fn resolve(&self, client_hello: &str) -> Option<AuthenticationLevel>;
+}
+
 #[derive(Debug)]
 pub struct DynamicAuthenticationLevelResolver {
     config: Arc<ArcSwap<Config>>,
@@ -140,7 +195,28 @@ impl AuthenticationLevelResolver for DynamicAuthenticationLevelResolver {
             return Some(AuthenticationLevel::Standard);
         };
 
-        if mtls.domains.contains(client_hello) {
+        // A route can be reached through any of its hosts, so protecting one protects them all
+        let route_hosts = config
+            .services
+            .values()
+            .flat_map(|service| &service.routes)
+            .map(|route| std::iter::once(&route.host).chain(&route.aliases))
+            .filter(|hosts| {
+                hosts
+                    .clone()
+                    .any(|host| host_match_specificity(host, client_hello).is_some())
+            })
+            .flatten();
+
+        let requires_mutual = std::iter::once(client_hello)
+            .chain(route_hosts.map(String::as_str))
+            .any(|host| {
+                mtls.domains
+                    .iter()
+                    .any(|domain| host_match_specificity(domain, host).is_some())
+            });
+
+        if requires_mutual {
             Some(AuthenticationLevel::Mutual)
         } else {
             Some(AuthenticationLevel::Standard)
@@ -158,13 +234,17 @@ mod tests {
 
     use arc_swap::ArcSwap;
     use color_eyre::eyre::{eyre, Result};
-    use mutual_tls::{AuthenticationLevel, AuthenticationLevelResolver};
     use rustls::pki_types::pem::PemObject;
     use rustls::pki_types::CertificateDer;
 
-    use crate::config::{AlbConfig, Config, ExternalBytes, MtlsConfig, Scheme, TlsSecrets};
+    use crate::config::{
+        AlbConfig, Config, ExternalBytes, MtlsConfig, Route, Scheme, Service, TlsSecrets,
+    };
     use crate::ipc::MessageBus;
-    use crate::load_balancer::tls::{CertificateResolver, DynamicAuthenticationLevelResolver};
+    use crate::load_balancer::tls::{
+        find_certificate, parse_certified_key, AuthenticationLevel, AuthenticationLevelResolver,
+        CertificateResolver, DynamicAuthenticationLevelResolver,
+    };
 
     const PRIMARY_DOMAIN: &str = "primary.example.com";
     const SECONDARY_DOMAIN: &str = "secondary.example.com";
@@ -184,12 +264,22 @@ mod tests {
                     path: PathBuf::new(),
                 },
                 domains: HashSet::from([domain1.to_string()]),
+                common_name_header: String::from("x-client-common-name"),
             }),
+            acme: None,
+            rate_limit: None,
+            access_log: None,
+            https_redirect: false,
+            hsts: None,
+            admin: None,
         };
 
         let mut original_config = Config {
             alb,
             secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
             services: HashMap::new(),
         };
 
@@ -225,6 +315,71 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn wildcard_and_alias_hosts_require_client_certificates() {
+        let alb = AlbConfig {
+            addr: Ipv4Addr::LOCALHOST,
+            ports: HashMap::from([(Scheme::Http, 5000)]),
+            reconciliation: String::new(),
+            tls: None,
+            mtls: Some(MtlsConfig {
+                anchor: ExternalBytes::Filesystem {
+                    path: PathBuf::new(),
+                },
+                domains: HashSet::from([
+                    String::from("*.internal.example.com"),
+                    String::from("admin.example.com"),
+                ]),
+                common_name_header: String::from("x-client-common-name"),
+            }),
+            acme: None,
+            rate_limit: None,
+            access_log: None,
+            https_redirect: false,
+            hsts: None,
+            admin: None,
+        };
+
+        let service = Service {
+            routes: HashSet::from([Route {
+                host: String::from("admin.example.com"),
+                aliases: vec![String::from("*.admin.example.com")],
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        let config = Config {
+            alb,
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services: HashMap::from([(String::from("admin"), service)]),
+        };
+
+        let resolver =
+            DynamicAuthenticationLevelResolver::new(Arc::new(ArcSwap::from_pointee(config)));
+
+        for host in [
+            "metrics.internal.example.com",
+            "admin.example.com",
+            "eu.admin.example.com",
+        ] {
+            assert!(
+                matches!(resolver.resolve(host), Some(AuthenticationLevel::Mutual)),
+                "{host} should require a client certificate"
+            );
+        }
+
+        for host in ["internal.example.com", "www.example.com"] {
+            assert!(
+                matches!(resolver.resolve(host), Some(AuthenticationLevel::Standard)),
+                "{host} should not require a client certificate"
+            );
+        }
+    }
+
     /// Builds a `TlsSecrets` instance from the given certificate and key paths.
     fn build_tls_secrets(cert_path: &Path, key_path: &Path) -> TlsSecrets {
         let cert_file = ExternalBytes::Filesystem {
@@ -378,4 +533,29 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn the_most_specific_certificate_is_chosen() -> Result<()> {
+        let old = Arc::new(parse_certified_key(
+            &std::fs::read("resources/certificates/old.crt")?,
+            &std::fs::read("resources/certificates/old.key")?,
+        )?);
+        let new = Arc::new(parse_certified_key(
+            &std::fs::read("resources/certificates/new.crt")?,
+            &std::fs::read("resources/certificates/new.key")?,
+        )?);
+
+        let domains = HashMap::from([
+            (String::from("*.example.com"), Arc::clone(&old)),
+            (String::from(PRIMARY_DOMAIN), Arc::clone(&new)),
+        ]);
+
+        let find = |server_name| find_certificate(&domains, server_name);
+
+        assert!(find(PRIMARY_DOMAIN).is_some_and(|key| Arc::ptr_eq(&key, &new)));
+        assert!(find(SECONDARY_DOMAIN).is_some_and(|key| Arc::ptr_eq(&key, &old)));
+        assert!(find("example.com").is_none());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,38 @@
+use http::header::{CONNECTION, UPGRADE};
+use http::HeaderMap;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use tokio::io::copy_bidirectional;
+
+use crate::load_balancer::cache::header_tokens;
+use crate::service_registry::SelectedDownstream;
+
+/// Checks whether a request asks to switch protocols, such as a WebSocket handshake.
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE)
+        && header_tokens(headers, CONNECTION).any(|token| token.eq_ignore_ascii_case("upgrade"))
+}
+
+/// Copies bytes in both directions between the client and the downstream once both connections
+/// have switched protocols. The downstream counts as outstanding until either side closes.
+pub async fn splice(client: OnUpgrade, upstream: OnUpgrade, downstream: SelectedDownstream) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(error) => {
+            tracing::warn!(id = %downstream.id, %error, "failed to upgrade the connection");
+            return;
+        }
+    };
+
+    let mut client = TokioIo::new(client);
+    let mut upstream = TokioIo::new(upstream);
+
+    match copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => {
+            tracing::debug!(id = %downstream.id, %sent, %received, "upgraded connection closed");
+        }
+        Err(error) => {
+            tracing::debug!(id = %downstream.id, %error, "upgraded connection closed with an error");
+        }
+    }
+}
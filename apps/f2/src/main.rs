@@ -4,8 +4,7 @@ use std::sync::Arc;
 
 use arc_swap::ArcSwap;
 use color_eyre::eyre::{eyre, Result};
-use docker::client::{Client, DockerClient};
-use rsa::RsaPrivateKey;
+use docker::client::Client;
 use service_registry::ServiceRegistry;
 use tokio::net::TcpListener;
 use tokio::signal::unix::SignalKind;
@@ -15,23 +14,29 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
-use crate::args::Args;
-use crate::common::Container;
-use crate::config::{Config, Service};
-use crate::docker::api::create_and_start_container;
+use crate::admin::AdminServer;
+use crate::args::{Args, Command};
+use crate::config::Config;
+use crate::health::HealthMonitor;
 use crate::ipc::MessageBus;
+use crate::jobs::{JobRunner, JobRuns};
 use crate::load_balancer::LoadBalancer;
 use crate::reconciler::Reconciler;
 
+mod admin;
 mod args;
+mod check;
 mod common;
 mod config;
 mod crypto;
 mod docker;
+mod encrypt;
 mod health;
 mod ipc;
+mod jobs;
 mod load_balancer;
 mod reconciler;
+mod secrets;
 mod service_registry;
 
 fn setup() -> Result<()> {
@@ -57,41 +62,55 @@ async fn main() -> Result<()> {
     setup()?;
 
     let args = Args::parse()?;
+
+    match &args.command {
+        Command::Run => {}
+        Command::Check { compare_with } => {
+            return check::run(&args.config_location, compare_with.as_ref()).await;
+        }
+        Command::Encrypt { public_key } => {
+            return encrypt::run(&args.config_location, public_key.as_ref()).await;
+        }
+    }
+
     let config = Arc::new(ArcSwap::from_pointee(
         Config::from_location(&args.config_location).await?,
     ));
 
+    if let Some(metrics) = &config.load().metrics {
+        foundation_metrics::init("f2", metrics)?;
+    }
+
     let alb_config = &config.load().alb;
 
     let addr = alb_config.addr;
     let tls = alb_config.tls.clone();
     let mtls = alb_config.mtls.clone();
 
-    let mut service_registry = ServiceRegistry::new();
-    let private_key = config.load().get_private_key().await?;
-
+    let service_registry = Arc::new(RwLock::new(ServiceRegistry::new()));
     let docker_client = Client::default();
-    let services = &config.load().services;
-
-    start_services(
-        &docker_client,
-        services,
-        &mut service_registry,
-        private_key.as_ref(),
-    )
-    .await?;
-
-    let service_registry = Arc::new(RwLock::new(service_registry));
     let message_bus = MessageBus::new();
 
+    let job_runs = Arc::new(RwLock::new(JobRuns::default()));
+    let job_runner = Arc::new(JobRunner::new(
+        Arc::clone(&job_runs),
+        Arc::clone(&config),
+        Client::default(),
+    ));
+
     let reconciler = Reconciler::new(
         Arc::clone(&service_registry),
         args.config_location.clone(),
         Arc::clone(&config),
         docker_client,
         Arc::clone(&message_bus),
+        Arc::clone(&job_runner),
     );
 
+    // Migrations have to apply before any service that relies on them starts
+    job_runner.run_deploy_jobs(&config.load()).await?;
+    reconciler.start_services().await?;
+
     let mut listeners = HashMap::new();
 
     for (protocol, port) in alb_config.ports.iter() {
@@ -99,12 +118,38 @@ async fn main() -> Result<()> {
         listeners.insert(protocol.clone(), listener);
     }
 
+    let admin_server = match &alb_config.admin {
+        Some(admin) => {
+            let listener = TcpListener::bind(SocketAddrV4::new(admin.addr, admin.port)).await?;
+            let server = AdminServer::new(
+                Arc::clone(&service_registry),
+                Arc::clone(&job_runs),
+                Arc::clone(&config),
+                Arc::clone(&message_bus),
+            );
+
+            Some((server, listener))
+        }
+        None => None,
+    };
+
+    let admin = async {
+        match admin_server {
+            Some((server, listener)) => server.run(listener).await,
+            None => Ok(()),
+        }
+    };
+
+    let health_monitor = HealthMonitor::new(Arc::clone(&service_registry));
     let load_balancer = LoadBalancer::new(service_registry, config, message_bus);
     let shutdown_signal = handle_shutdown_signal();
 
     tokio::try_join!(
         load_balancer.run(listeners, tls, mtls),
         reconciler.run(),
+        job_runner.run(),
+        health_monitor.run(),
+        admin,
         shutdown_signal
     )?;
 
@@ -134,26 +179,3 @@ async fn handle_shutdown_signal() -> Result<()> {
 
     Err(eyre!("shutdown signal received, exiting..."))
 }
-
-async fn start_services<C: DockerClient>(
-    client: &C,
-    services: &HashMap<String, Service>,
-    service_registry: &mut ServiceRegistry,
-    private_key: Option<&RsaPrivateKey>,
-) -> Result<()> {
-    for (name, service) in services {
-        service_registry.define(name, service.clone());
-
-        let tag = &service.tag;
-        let container = Container::from(service);
-
-        tracing::info!(%name, %tag, "starting service");
-
-        for _ in 0..service.replicas.get() {
-            let details = create_and_start_container(client, &container, tag, private_key).await?;
-            service_registry.add_container(name, details);
-        }
-    }
-
-    Ok(())
-}
@@ -0,0 +1,296 @@
+use std::collections::{BTreeSet, HashMap};
+
+use color_eyre::eyre::Result;
+
+use crate::common::Container;
+use crate::docker::api::{
+    create_and_start_container, remove_container_and_volumes, replica_slot, take_free_slot,
+    StartedContainerDetails, CONFIG_HASH_LABEL, SERVICE_LABEL,
+};
+use crate::docker::client::DockerClient;
+use crate::docker::models::{ContainerId, ContainerSummary};
+use crate::reconciler::{Reconciler, Track};
+
+impl<C: DockerClient> Reconciler<C> {
+    /// Starts the containers for every configured service, adopting any left running by a previous
+    /// instance of f2 with the same configuration and removing any that are stale.
+    #[tracing::instrument(skip(self))]
+    pub async fn start_services(&self) -> Result<()> {
+        let config = self.config.load();
+        let private_key = config.get_private_key().await?;
+
+        let mut existing: HashMap<String, Vec<ContainerSummary>> = HashMap::new();
+
+        for summary in self.docker_client.list_containers(SERVICE_LABEL).await? {
+            let service = summary
+                .labels
+                .get(SERVICE_LABEL)
+                .cloned()
+                .unwrap_or_default();
+
+            existing.entry(service).or_default().push(summary);
+        }
+
+        let mut stale: Vec<ContainerId> = Vec::new();
+
+        for (name, service) in &config.services {
+            let mut candidates = existing.remove(name).unwrap_or_default();
+            let mut used_slots = BTreeSet::new();
+
+            self.registry.write().await.define(name, service.clone());
+
+            let tracks = [
+                (Track::Stable, Some(service.clone())),
+                (Track::Canary, service.canary_definition()),
+            ];
+
+            for (track, definition) in tracks {
+                let Some(definition) = definition else {
+                    continue;
+                };
+
+                let tag = &definition.tag;
+                let container = Container::from(&definition);
+                let config_hash = container.config_hash(tag);
+                let replicas = usize::from(definition.replicas.get());
+
+                let mut adopted = Vec::new();
+                let mut remaining = Vec::new();
+
+                for summary in candidates {
+                    let matches = summary.state == "running"
+                        && summary.labels.get(CONFIG_HASH_LABEL) == Some(&config_hash);
+
+                    if !matches || adopted.len() >= replicas {
+                        remaining.push(summary);
+                        continue;
+                    }
+
+                    match self.docker_client.get_container_ip(&summary.id).await {
+                        Ok(addr) => {
+                            tracing::info!(%name, ?track, id = %summary.id, %addr, "adopting a running container");
+                            used_slots.extend(replica_slot(&summary));
+                            adopted.push(StartedContainerDetails {
+                                id: summary.id,
+                                addr,
+                            });
+                        }
+                        Err(error) => {
+                            tracing::warn!(%name, id = %summary.id, %error, "failed to adopt a running container");
+                            stale.push(summary.id);
+                        }
+                    }
+                }
+
+                candidates = remaining;
+
+                tracing::info!(%name, ?track, %tag, adopted = %adopted.len(), "starting service");
+
+                let mut containers = adopted;
+
+                for _ in containers.len()..replicas {
+                    let replica = take_free_slot(&mut used_slots);
+                    let details = create_and_start_container(
+                        &self.docker_client,
+                        name,
+                        &container,
+                        tag,
+                        replica,
+                        private_key.as_ref(),
+                    )
+                    .await?;
+
+                    containers.push(details);
+                }
+
+                self.register(name, track, containers).await;
+            }
+
+            stale.extend(candidates.into_iter().map(|summary| summary.id));
+        }
+
+        // Anything left over belongs to a service that is no longer configured
+        stale.extend(existing.into_values().flatten().map(|summary| summary.id));
+
+        for id in &stale {
+            tracing::info!(%id, "removing a stale container");
+
+            // A container that cannot be removed should not stop the others from starting
+            if let Err(error) = remove_container_and_volumes(&self.docker_client, id).await {
+                tracing::warn!(%id, %error, "failed to remove a stale container");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use color_eyre::eyre::Result;
+
+    use crate::common::Container;
+    use crate::config::{ContainerOptions, ReplicaCount, Service};
+    use crate::docker::api::{CONFIG_HASH_LABEL, SERVICE_LABEL};
+    use crate::docker::client::DockerClient;
+    use crate::docker::models::ContainerId;
+    use crate::reconciler::tests::{create_reconciler, FakeDockerClient};
+    use crate::reconciler::Reconciler;
+    use crate::service_registry::ServiceRegistry;
+
+    fn create_reconciler_for(
+        docker_client: &FakeDockerClient,
+        services: HashMap<String, Service>,
+    ) -> Reconciler<FakeDockerClient> {
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+
+        let mut config = (**reconciler.config.load()).clone();
+        config.services = services;
+        reconciler.config.store(Arc::new(config));
+
+        reconciler
+    }
+
+    async fn create_labelled_container(
+        docker_client: &FakeDockerClient,
+        service: &str,
+        config_hash: &str,
+    ) -> Result<ContainerId> {
+        let labels = HashMap::from([
+            (SERVICE_LABEL.to_owned(), service.to_owned()),
+            (CONFIG_HASH_LABEL.to_owned(), config_hash.to_owned()),
+        ]);
+
+        docker_client
+            .create_container(
+                "myapp:v1",
+                &None,
+                &HashMap::new(),
+                None,
+                &[],
+                &labels,
+                &ContainerOptions::default(),
+            )
+            .await
+    }
+
+    async fn registered_ids(
+        reconciler: &Reconciler<FakeDockerClient>,
+        name: &str,
+    ) -> Vec<ContainerId> {
+        let registry = reconciler.registry.read().await;
+
+        registry
+            .get_running_containers(name)
+            .map(|containers| {
+                containers
+                    .iter()
+                    .map(|details| details.id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn matching_containers_are_adopted() -> Result<()> {
+        let name = "backend";
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            replicas: ReplicaCount::try_from(2)?,
+            ..Default::default()
+        };
+
+        let config_hash = Container::from(&definition).config_hash(&definition.tag);
+
+        let docker_client = FakeDockerClient::default();
+        let first = create_labelled_container(&docker_client, name, &config_hash).await?;
+        let second = create_labelled_container(&docker_client, name, &config_hash).await?;
+
+        let reconciler = create_reconciler_for(
+            &docker_client,
+            HashMap::from([(name.to_owned(), definition)]),
+        );
+
+        reconciler.start_services().await?;
+
+        // No new containers should have been created
+        assert_eq!(
+            docker_client.container_ids().await,
+            vec![first.clone(), second.clone()]
+        );
+        assert_eq!(registered_ids(&reconciler, name).await, vec![first, second]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_containers_are_removed() -> Result<()> {
+        let name = "backend";
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let config_hash = Container::from(&definition).config_hash(&definition.tag);
+
+        let docker_client = FakeDockerClient::default();
+        let outdated = create_labelled_container(&docker_client, name, "outdated").await?;
+        let removed = create_labelled_container(&docker_client, "removed", &config_hash).await?;
+        let exited = create_labelled_container(&docker_client, name, &config_hash).await?;
+
+        docker_client.exit_container(&exited).await;
+
+        let reconciler = create_reconciler_for(
+            &docker_client,
+            HashMap::from([(name.to_owned(), definition)]),
+        );
+
+        reconciler.start_services().await?;
+
+        let running = docker_client.container_ids().await;
+
+        assert_eq!(running.len(), 1);
+        assert!(![outdated, removed, exited].contains(&running[0]));
+        assert_eq!(registered_ids(&reconciler, name).await, running);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_to_remove_a_stale_container_does_not_stop_startup() -> Result<()> {
+        let name = "backend";
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let docker_client = FakeDockerClient::default();
+        let stuck = create_labelled_container(&docker_client, name, "outdated").await?;
+        let outdated = create_labelled_container(&docker_client, name, "outdated").await?;
+
+        docker_client.fail_container(&stuck).await;
+
+        let reconciler = create_reconciler_for(
+            &docker_client,
+            HashMap::from([(name.to_owned(), definition)]),
+        );
+
+        reconciler.start_services().await?;
+
+        let running = docker_client.container_ids().await;
+        let registered = registered_ids(&reconciler, name).await;
+
+        assert_eq!(registered.len(), 1);
+        assert!(running.contains(&stuck));
+        assert!(!running.contains(&outdated));
+        assert!(running.contains(&registered[0]));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use color_eyre::eyre::{self, Result};
+
+use crate::config::{ReplicaCount, Service};
+use crate::docker::api::StartedContainerDetails;
+use crate::docker::client::DockerClient;
+use crate::reconciler::Reconciler;
+
+impl<C: DockerClient> Reconciler<C> {
+    /// Starts a full set of new containers and only removes the old ones once they are all healthy.
+    #[tracing::instrument(skip_all, fields(%name))]
+    pub(super) async fn blue_green_deployment(
+        &self,
+        name: &str,
+        old_definition: &Service,
+        new_definition: Service,
+        old_containers: Vec<StartedContainerDetails>,
+    ) -> Result<()> {
+        let replicas = new_definition.replicas;
+
+        tracing::info!(new = %replicas.get(), old = %old_containers.len(), "starting the green containers");
+
+        self.start_multiple_containers(name, new_definition, replicas)
+            .await?;
+
+        tracing::info!("switched traffic to the green containers, removing the blue ones");
+
+        self.remove_containers(name, old_definition.shutdown_mode, &old_containers)
+            .await?;
+
+        tracing::info!("completed the blue/green deployment");
+
+        Ok(())
+    }
+
+    /// Replaces the old containers in batches, never running more than `max_surge` containers above
+    /// the desired replica count or having more than `max_unavailable` below it.
+    #[tracing::instrument(skip_all, fields(%name, %max_surge, %max_unavailable))]
+    pub(super) async fn rolling_deployment(
+        &self,
+        name: &str,
+        old_definition: &Service,
+        new_definition: Service,
+        mut old_containers: Vec<StartedContainerDetails>,
+        max_surge: u8,
+        max_unavailable: u8,
+    ) -> Result<()> {
+        eyre::ensure!(
+            max_surge > 0 || max_unavailable > 0,
+            "rolling deployment for {name} needs a non-zero max_surge or max_unavailable"
+        );
+
+        let desired = usize::from(new_definition.replicas.get());
+        let max_total = desired + usize::from(max_surge);
+        let min_available = desired.saturating_sub(usize::from(max_unavailable));
+
+        let mut started = 0;
+        let mut step = 1;
+
+        while started < desired || !old_containers.is_empty() {
+            let running = started + old_containers.len();
+            let batch = (desired - started).min(max_total.saturating_sub(running));
+
+            if batch > 0 {
+                tracing::info!(%step, count = %batch, "starting new containers");
+
+                let replicas = ReplicaCount::try_from(u8::try_from(batch)?)?;
+
+                if let Err(e) = self
+                    .start_multiple_containers(name, new_definition.clone(), replicas)
+                    .await
+                {
+                    tracing::warn!(%step, new = %started, old = %old_containers.len(), "rolling deployment failed part way through");
+
+                    return Err(e);
+                }
+
+                started += batch;
+            }
+
+            let removable = (started + old_containers.len())
+                .saturating_sub(min_available)
+                .min(old_containers.len());
+
+            if removable > 0 {
+                tracing::info!(%step, count = %removable, "removing old containers");
+
+                let removed: Vec<_> = old_containers.drain(..removable).collect();
+
+                self.remove_containers(name, old_definition.shutdown_mode, &removed)
+                    .await?;
+            }
+
+            tracing::info!(%step, new = %started, old = %old_containers.len(), "completed a rolling deployment step");
+
+            step += 1;
+        }
+
+        tracing::info!("completed the rolling deployment");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    use color_eyre::eyre::Result;
+
+    use crate::config::{ContainerOptions, DeploymentStrategy, Diff, ReplicaCount, Service};
+    use crate::docker::api::StartedContainerDetails;
+    use crate::docker::client::DockerClient;
+    use crate::docker::models::ContainerId;
+    use crate::reconciler::tests::{create_reconciler, FakeDockerClient};
+    use crate::service_registry::ServiceRegistry;
+
+    const SERVICE: &str = "foobar";
+
+    /// Sets up a registry with `replicas` running containers for the old definition.
+    async fn setup_replicas(
+        docker_client: &FakeDockerClient,
+        definition: &Service,
+        replicas: u8,
+    ) -> Result<(ServiceRegistry, Vec<ContainerId>)> {
+        let mut registry = ServiceRegistry::new();
+        let mut ids = Vec::new();
+
+        registry.define(SERVICE, definition.clone());
+
+        for _ in 0..replicas {
+            let id = docker_client
+                .create_container(
+                    &format!("{}:{}", definition.image, definition.tag),
+                    &None,
+                    &HashMap::new(),
+                    None,
+                    &[],
+                    &HashMap::new(),
+                    &ContainerOptions::default(),
+                )
+                .await?;
+
+            registry.add_container(
+                SERVICE,
+                StartedContainerDetails {
+                    id: id.clone(),
+                    addr: Ipv4Addr::LOCALHOST,
+                },
+            );
+
+            ids.push(id);
+        }
+
+        Ok((registry, ids))
+    }
+
+    fn definitions(replicas: u8, deployment: DeploymentStrategy) -> Result<(Service, Service)> {
+        let old_definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            replicas: ReplicaCount::try_from(replicas)?,
+            ..Default::default()
+        };
+
+        let new_definition = Service {
+            tag: "v2".to_owned(),
+            deployment,
+            ..old_definition.clone()
+        };
+
+        Ok((old_definition, new_definition))
+    }
+
+    async fn deploy(
+        docker_client: &FakeDockerClient,
+        deployment: DeploymentStrategy,
+    ) -> Result<Vec<ContainerId>> {
+        let (old_definition, new_definition) = definitions(3, deployment)?;
+        let (registry, old_ids) = setup_replicas(docker_client, &old_definition, 3).await?;
+
+        docker_client.reset_peak_containers().await;
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: SERVICE.to_owned(),
+                old_definition,
+                new_definition,
+            })
+            .await?;
+
+        Ok(old_ids)
+    }
+
+    async fn assert_fully_replaced(docker_client: &FakeDockerClient, old_ids: &[ContainerId]) {
+        let running = docker_client.container_ids().await;
+
+        assert_eq!(running.len(), 3);
+        assert!(running.iter().all(|id| !old_ids.contains(id)));
+    }
+
+    #[tokio::test]
+    async fn blue_green_deployments_start_every_replica_first() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let old_ids = deploy(&docker_client, DeploymentStrategy::BlueGreen).await?;
+
+        assert_fully_replaced(&docker_client, &old_ids).await;
+        assert_eq!(docker_client.peak_containers().await, 6);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rolling_deployments_respect_max_surge() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let strategy = DeploymentStrategy::Rolling {
+            max_surge: 1,
+            max_unavailable: 0,
+        };
+
+        let old_ids = deploy(&docker_client, strategy).await?;
+
+        assert_fully_replaced(&docker_client, &old_ids).await;
+        assert_eq!(docker_client.peak_containers().await, 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rolling_deployments_can_replace_in_place() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let strategy = DeploymentStrategy::Rolling {
+            max_surge: 0,
+            max_unavailable: 1,
+        };
+
+        let old_ids = deploy(&docker_client, strategy).await?;
+
+        assert_fully_replaced(&docker_client, &old_ids).await;
+        assert_eq!(docker_client.peak_containers().await, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rolling_deployments_need_some_leeway() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let strategy = DeploymentStrategy::Rolling {
+            max_surge: 0,
+            max_unavailable: 0,
+        };
+
+        let result = deploy(&docker_client, strategy).await;
+
+        assert!(result.is_err());
+        assert_eq!(docker_client.container_ids().await.len(), 3);
+
+        Ok(())
+    }
+}
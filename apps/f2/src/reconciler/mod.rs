@@ -0,0 +1,1710 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use color_eyre::eyre::{ensure, eyre, Report, Result};
+use indexmap::IndexSet;
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::KeyValue;
+use tokio::sync::RwLock;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::common::Container;
+use crate::config::{
+    Config, DeploymentStrategy, Diff, ExternalBytes, PollingConfig, ReplicaCount, Service,
+    ShutdownMode,
+};
+use crate::docker::api::{
+    create_and_start_container, remove_container_and_volumes, replica_slot, take_free_slot,
+    StartedContainerDetails, SERVICE_LABEL,
+};
+use crate::docker::client::DockerClient;
+use crate::health::{HealthCheck, HealthCheckResult};
+use crate::ipc::MessageBus;
+use crate::jobs::JobRunner;
+use crate::reconciler::polling::ChangeDetector;
+use crate::service_registry::ServiceRegistry;
+
+mod adoption;
+mod deployment;
+mod polling;
+mod rollback;
+mod supervision;
+
+/// How often to check that each service still has its configured number of running containers.
+const SUPERVISION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which set of containers for a service a container belongs to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+enum Track {
+    Stable,
+    Canary,
+}
+
+#[derive(Debug)]
+struct ReconciliationMetrics {
+    reconciliations: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl ReconciliationMetrics {
+    fn new(meter: &Meter) -> Self {
+        Self {
+            reconciliations: meter
+                .u64_counter("f2_reconciliations_total")
+                .with_description("Total reconciliations of the configuration by result")
+                .build(),
+            duration: meter
+                .f64_histogram("f2_reconciliation_duration_seconds")
+                .with_description("Time taken to reconcile the configuration by result")
+                .with_unit("s")
+                .build(),
+        }
+    }
+
+    fn record(&self, succeeded: bool, elapsed: Duration) {
+        let result = if succeeded { "success" } else { "failure" };
+        let attributes = [KeyValue::new("result", result)];
+
+        self.reconciliations.add(1, &attributes);
+        self.duration.record(elapsed.as_secs_f64(), &attributes);
+    }
+}
+
+#[derive(Debug)]
+pub struct Reconciler<C: DockerClient> {
+    registry: Arc<RwLock<ServiceRegistry>>,
+    config_location: Arc<ExternalBytes>,
+    config: Arc<ArcSwap<Config>>,
+    docker_client: C,
+    message_bus: Arc<MessageBus>,
+    job_runner: Arc<JobRunner<C>>,
+    metrics: ReconciliationMetrics,
+}
+
+impl<C: DockerClient> Reconciler<C> {
+    pub fn new(
+        registry: Arc<RwLock<ServiceRegistry>>,
+        config_location: ExternalBytes,
+        config: Arc<ArcSwap<Config>>,
+        docker_client: C,
+        message_bus: Arc<MessageBus>,
+        job_runner: Arc<JobRunner<C>>,
+    ) -> Self {
+        Self {
+            registry,
+            config_location: Arc::new(config_location),
+            config,
+            docker_client,
+            message_bus,
+            job_runner,
+            metrics: ReconciliationMetrics::new(&opentelemetry::global::meter("f2")),
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut backoffs = HashMap::new();
+        let mut supervision = tokio::time::interval(SUPERVISION_INTERVAL);
+        supervision.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // Polling is read once at startup, defaulting to the supervision interval when disabled so
+        // the timer can still be created
+        let polling = self.config.load().polling.clone();
+        let mut change_detector = ChangeDetector::default();
+        let mut poll = tokio::time::interval(
+            polling
+                .as_ref()
+                .map_or(SUPERVISION_INTERVAL, PollingConfig::interval),
+        );
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                request = self.message_bus.receive_reconciliation_request() => {
+                    if request.is_err() {
+                        break;
+                    }
+
+                    tracing::info!("received signal to reconcile");
+
+                    self.reconcile_and_record().await;
+                }
+                _ = poll.tick(), if polling.is_some() => {
+                    self.poll_for_changes(&mut change_detector).await;
+                }
+                request = self.message_bus.receive_container_restart_request() => {
+                    let Ok(request) = request else {
+                        break;
+                    };
+
+                    let id = request.into_content().id;
+
+                    tracing::info!(%id, "received signal to restart a container");
+
+                    if let Err(error) = self.restart_container(&id).await {
+                        tracing::error!(%id, %error, "failed to restart container");
+                    }
+                }
+                _ = supervision.tick() => {
+                    if let Err(error) = self.supervise(&mut backoffs).await {
+                        tracing::error!(%error, "failed to supervise containers");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reconciles if the configuration has changed, only remembering the change once it has been
+    /// applied so that failed services are tried again on the next poll.
+    async fn poll_for_changes(&self, change_detector: &mut ChangeDetector) {
+        match change_detector.has_changed(&self.config_location).await {
+            Ok(true) => {
+                tracing::info!("configuration changed, reconciling");
+
+                if self.reconcile_and_record().await {
+                    change_detector.commit();
+                }
+            }
+            Ok(false) => {}
+            Err(error) => tracing::warn!(%error, "failed to check configuration for changes"),
+        }
+    }
+
+    /// Reconciles and records the result in the metrics, returning whether it succeeded.
+    async fn reconcile_and_record(&self) -> bool {
+        let started = Instant::now();
+        let result = self.reconcile().await;
+
+        self.metrics.record(result.is_ok(), started.elapsed());
+
+        if let Err(error) = &result {
+            tracing::error!(%error, "failed to reconcile");
+        }
+
+        result.is_ok()
+    }
+
+    /// Applies the changes to each service separately, so that a failure only affects that
+    /// service. Failed services are rolled back and keep their old definition in the stored
+    /// configuration, which means the next reconciliation tries them again.
+    async fn reconcile(&self) -> Result<()> {
+        let new_config = Config::from_location(&self.config_location).await?;
+        let old_config = self.config.load_full();
+
+        let diff = old_config.diff(&new_config).unwrap_or_default();
+
+        if diff.is_empty() && old_config.jobs == new_config.jobs {
+            return Ok(());
+        }
+
+        // Jobs such as migrations have to succeed before the services relying on them roll out
+        self.job_runner.run_deploy_jobs(&new_config).await?;
+
+        // Everything but the services applies straight away, which are updated as they succeed
+        let mut applied = Config {
+            services: old_config.services.clone(),
+            ..new_config
+        };
+
+        self.config.store(Arc::new(applied.clone()));
+
+        let mut failures = HashMap::new();
+
+        for event in diff.iter().cloned() {
+            let name = event.name().to_owned();
+            let new_definition = event.new_definition().cloned();
+            let snapshot = self.snapshot(&name).await;
+
+            if let Err(error) = self.handle_diff(event).await {
+                tracing::error!(%name, %error, "failed to reconcile service, rolling back");
+
+                self.roll_back(&name, snapshot).await;
+                failures.insert(name, format!("{error:#}"));
+
+                continue;
+            }
+
+            match new_definition {
+                Some(definition) => applied.services.insert(name, definition),
+                None => applied.services.remove(&name),
+            };
+
+            self.config.store(Arc::new(applied.clone()));
+        }
+
+        let mut failed: Vec<_> = failures.keys().cloned().collect();
+        failed.sort();
+
+        self.registry
+            .write()
+            .await
+            .record_reconciliation(diff, failures);
+
+        // Pruning only frees space, so it should not fail a reconciliation that was applied
+        if let Err(error) = self.docker_client.prune_images().await {
+            tracing::warn!(%error, "failed to prune unused images");
+        }
+
+        ensure!(
+            failed.is_empty(),
+            "failed to reconcile {}",
+            failed.join(", ")
+        );
+
+        Ok(())
+    }
+
+    async fn get_running_containers(
+        &self,
+        name: &str,
+    ) -> Option<IndexSet<StartedContainerDetails>> {
+        let read_lock = self.registry.read().await;
+
+        read_lock.get_running_containers(name).cloned()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn start_multiple_containers(
+        &self,
+        name: &str,
+        new_definition: Service,
+        replicas: ReplicaCount,
+    ) -> Result<()> {
+        let started_containers = self
+            .start_containers(name, &new_definition, replicas)
+            .await?;
+
+        self.registry.write().await.define(name, new_definition);
+        self.register(name, Track::Stable, started_containers).await;
+
+        Ok(())
+    }
+
+    /// Creates and starts containers for a definition, removing them again if they do not pass
+    /// their health checks.
+    async fn start_containers(
+        &self,
+        name: &str,
+        definition: &Service,
+        replicas: ReplicaCount,
+    ) -> Result<Vec<StartedContainerDetails>> {
+        // Keep the locks short, create everything then add to the LB
+        let mut started_containers = Vec::new();
+
+        let private_key = self.config.load().get_private_key().await?;
+        let container = Container::from(definition);
+        let mut used_slots = self.used_replica_slots(name).await?;
+
+        for _ in 0..replicas.get() {
+            let replica = take_free_slot(&mut used_slots);
+            let result = create_and_start_container(
+                &self.docker_client,
+                name,
+                &container,
+                &definition.tag,
+                replica,
+                private_key.as_ref(),
+            )
+            .await;
+
+            match result {
+                Ok(details) => started_containers.push(details),
+                Err(e) => {
+                    tracing::warn!(%name, "removing the containers started before one failed");
+
+                    self.discard_containers(name, &started_containers).await;
+
+                    return Err(e);
+                }
+            }
+        }
+
+        // Only let the new containers take traffic once they are healthy
+        if let Err(e) = self
+            .wait_for_health_checks(name, definition, &started_containers)
+            .await
+        {
+            tracing::warn!(%name, "rolling back containers that failed their health checks");
+
+            self.discard_containers(name, &started_containers).await;
+
+            return Err(e);
+        }
+
+        Ok(started_containers)
+    }
+
+    /// Gets the replica slots taken by the running containers of a service, across both tracks.
+    async fn used_replica_slots(&self, name: &str) -> Result<BTreeSet<usize>> {
+        let containers = self.docker_client.list_containers(SERVICE_LABEL).await?;
+
+        let slots = containers
+            .iter()
+            .filter(|summary| summary.state == "running")
+            .filter(|summary| summary.labels.get(SERVICE_LABEL).is_some_and(|s| s == name))
+            .filter_map(replica_slot)
+            .collect();
+
+        Ok(slots)
+    }
+
+    /// Removes containers that never took any traffic, carrying on if any of them cannot be.
+    async fn discard_containers(&self, name: &str, containers: &[StartedContainerDetails]) {
+        for details in containers {
+            if let Err(error) = remove_container_and_volumes(&self.docker_client, &details.id).await
+            {
+                tracing::warn!(%name, id = %details.id, %error, "failed to remove container");
+            }
+        }
+    }
+
+    /// Adds started containers to the load balancer.
+    async fn register(&self, name: &str, track: Track, containers: Vec<StartedContainerDetails>) {
+        let mut write_lock = self.registry.write().await;
+
+        for details in containers {
+            match track {
+                Track::Stable => write_lock.add_container(name, details),
+                Track::Canary => write_lock.add_canary_container(name, details),
+            }
+        }
+    }
+
+    /// Starts the canary containers for a service, if it defines a canary.
+    async fn start_canary(&self, name: &str, definition: &Service) -> Result<()> {
+        let Some(canary) = definition.canary_definition() else {
+            return Ok(());
+        };
+
+        tracing::info!(%name, tag = %canary.tag, replicas = %canary.replicas.get(), "starting canary containers");
+
+        let started = self
+            .start_containers(name, &canary, canary.replicas)
+            .await?;
+
+        self.register(name, Track::Canary, started).await;
+
+        Ok(())
+    }
+
+    /// Replaces the canary containers for a service, starting the new ones before removing the
+    /// old ones so that promoting or aborting a canary is just a change to the configuration.
+    #[tracing::instrument(skip(self, old_definition, new_definition))]
+    async fn replace_canary(
+        &self,
+        name: &str,
+        old_definition: &Service,
+        new_definition: &Service,
+    ) -> Result<()> {
+        let old_canaries: Vec<_> = self
+            .registry
+            .read()
+            .await
+            .get_canary_containers(name)
+            .map(|containers| containers.iter().cloned().collect())
+            .unwrap_or_default();
+
+        self.start_canary(name, new_definition).await?;
+        self.registry
+            .write()
+            .await
+            .define(name, new_definition.clone());
+
+        self.remove_containers(name, old_definition.shutdown_mode, &old_canaries)
+            .await?;
+
+        tracing::info!(%name, removed = %old_canaries.len(), "replaced canary containers");
+
+        Ok(())
+    }
+
+    /// Waits for each of the containers to pass the health check for the service, if it has one,
+    /// returning an error if any of them fail.
+    async fn wait_for_health_checks(
+        &self,
+        name: &str,
+        definition: &Service,
+        containers: &[StartedContainerDetails],
+    ) -> Result<()> {
+        let Some(health_check) = definition.health_check.as_ref() else {
+            return Ok(());
+        };
+
+        tracing::info!(%name, count = %containers.len(), "waiting for containers to become healthy");
+
+        let checks = containers.iter().map(|details| async move {
+            let result = HealthCheck::for_container(health_check, details.addr)?
+                .run()
+                .await?;
+
+            Ok::<_, Report>((details, result))
+        });
+
+        for (details, result) in futures::future::try_join_all(checks).await? {
+            if result == HealthCheckResult::Failure {
+                return Err(eyre!(
+                    "container {} for {name} failed its health check",
+                    details.id
+                ));
+            }
+        }
+
+        tracing::info!(%name, "all containers passed their health checks");
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn handle_alteration(
+        &self,
+        name: &str,
+        old_definition: Service,
+        new_definition: Service,
+    ) -> Result<()> {
+        let running_containers = self
+            .get_running_containers(name)
+            .await
+            .ok_or_else(|| eyre!("Failed to get running containers for {name}"))?;
+
+        if old_definition.differs_only_in_canary(&new_definition) {
+            let same_containers = match (&old_definition.canary, &new_definition.canary) {
+                (Some(old), Some(new)) => old.tag == new.tag && old.replicas == new.replicas,
+                _ => false,
+            };
+
+            // Only the share of traffic changed, so the running canaries can be kept
+            if same_containers {
+                self.registry.write().await.define(name, new_definition);
+                return Ok(());
+            }
+
+            return self
+                .replace_canary(name, &old_definition, &new_definition)
+                .await;
+        }
+
+        let old_containers: Vec<_> = running_containers.into_iter().collect();
+        let canary_changed = old_definition.canary.is_some() || new_definition.canary.is_some();
+        let definition = new_definition.clone();
+
+        match new_definition.deployment {
+            DeploymentStrategy::Rolling {
+                max_surge,
+                max_unavailable,
+            } => {
+                self.rolling_deployment(
+                    name,
+                    &old_definition,
+                    new_definition,
+                    old_containers,
+                    max_surge,
+                    max_unavailable,
+                )
+                .await?
+            }
+            DeploymentStrategy::BlueGreen => {
+                self.blue_green_deployment(name, &old_definition, new_definition, old_containers)
+                    .await?
+            }
+        }
+
+        // Canary containers share the rest of the definition, so they need replacing too
+        if canary_changed {
+            self.replace_canary(name, &old_definition, &definition)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes containers from the load balancer before shutting them down in Docker.
+    async fn remove_containers(
+        &self,
+        name: &str,
+        shutdown_mode: ShutdownMode,
+        containers: &[StartedContainerDetails],
+    ) -> Result<()> {
+        let mut write_lock = self.registry.write().await;
+
+        for details in containers {
+            write_lock.remove_container_by_id(name, &details.id);
+        }
+
+        drop(write_lock);
+
+        for details in containers {
+            match shutdown_mode {
+                ShutdownMode::Graceful => {
+                    self.docker_client.stop_container(&details.id).await?;
+                    remove_container_and_volumes(&self.docker_client, &details.id).await?;
+                }
+                ShutdownMode::Forceful => {
+                    remove_container_and_volumes(&self.docker_client, &details.id).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn handle_addition(&self, name: String, definition: Service) -> Result<()> {
+        let replicas = definition.replicas;
+
+        self.start_multiple_containers(&name, definition.clone(), replicas)
+            .await?;
+
+        self.start_canary(&name, &definition).await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn handle_removal(&self, name: String) -> Result<()> {
+        let running_containers = self.get_running_containers(&name).await;
+
+        // Remove them from the LB
+        if let Some(containers) = running_containers {
+            let mut write_lock = self.registry.write().await;
+
+            let canaries = write_lock
+                .get_canary_containers(&name)
+                .cloned()
+                .unwrap_or_default();
+
+            write_lock.undefine(&name);
+            write_lock.remove_all_containers(&name);
+            write_lock.remove_all_canary_containers(&name);
+
+            drop(write_lock);
+
+            for details in containers.iter().chain(&canaries) {
+                remove_container_and_volumes(&self.docker_client, &details.id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn handle_diff(&self, diff: Diff) -> Result<()> {
+        match diff {
+            Diff::Alteration {
+                name,
+                old_definition,
+                new_definition,
+            } => {
+                self.handle_alteration(&name, old_definition, new_definition)
+                    .await?
+            }
+            Diff::Addition { name, definition } => self.handle_addition(name, definition).await?,
+            Diff::Removal { name } => self.handle_removal(name).await?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use arc_swap::ArcSwap;
+    use color_eyre::eyre::{bail, ensure, Result};
+    use hyper::StatusCode;
+    use tempfile::NamedTempFile;
+    use tokio::sync::RwLock;
+
+    use crate::common::Environment;
+    use crate::config::{
+        AlbConfig, CanaryDefinition, CanaryWeight, Config, ContainerHealthCheck, ContainerOptions,
+        Diff, ExternalBytes, HealthCheckDefinition, ReplicaCount, RestartPolicy, Scheme, Service,
+        ShutdownMode, VolumeDefinition,
+    };
+    use crate::docker::api::{StartedContainerDetails, JOB_LABEL, REPLICA_LABEL, SERVICE_LABEL};
+    use crate::docker::client::DockerClient;
+    use crate::docker::models::{
+        ContainerId, ContainerState, ContainerSummary, ImageSummary, NetworkId,
+    };
+    use crate::health::tests::spawn_server;
+    use crate::ipc::MessageBus;
+    use crate::jobs::JobRunner;
+    use crate::reconciler::Reconciler;
+    use crate::service_registry::ServiceRegistry;
+
+    #[derive(Clone, Debug, Default)]
+    struct DockerState {
+        images: Vec<ImageSummary>,
+        containers: Vec<(ContainerId, String)>,
+        labels: HashMap<ContainerId, HashMap<String, String>>,
+        options: HashMap<ContainerId, ContainerOptions>,
+        /// The exit code of each container that has exited.
+        exited: HashMap<ContainerId, i64>,
+        logs: HashMap<ContainerId, String>,
+        peak_containers: usize,
+        /// The number of containers that can be created for an image before creation fails.
+        failing_images: HashMap<String, usize>,
+        /// Containers that Docker returns errors for when they are inspected or removed.
+        failing_containers: HashSet<ContainerId>,
+        /// The number of times listing containers fails before it succeeds again.
+        failing_listings: usize,
+        failing_prunes: bool,
+    }
+
+    #[derive(Clone, Default)]
+    pub struct FakeDockerClient {
+        state: Arc<RwLock<DockerState>>,
+    }
+
+    impl FakeDockerClient {
+        pub async fn container_ids(&self) -> Vec<ContainerId> {
+            let lock = self.state.read().await;
+
+            lock.containers.iter().map(|(id, _)| id.clone()).collect()
+        }
+
+        /// Gets the largest number of containers that have existed at once.
+        pub async fn peak_containers(&self) -> usize {
+            self.state.read().await.peak_containers
+        }
+
+        pub async fn reset_peak_containers(&self) {
+            let mut lock = self.state.write().await;
+            lock.peak_containers = lock.containers.len();
+        }
+
+        /// Makes creating containers for an image fail once `successes` have been created.
+        pub async fn fail_image_after(&self, image: &str, successes: usize) {
+            let mut lock = self.state.write().await;
+            lock.failing_images.insert(image.to_owned(), successes);
+        }
+
+        /// Makes inspecting or removing a container fail, as if the Docker daemon returned an error.
+        pub async fn fail_container(&self, id: &ContainerId) {
+            let mut lock = self.state.write().await;
+            lock.failing_containers.insert(id.clone());
+        }
+
+        /// Simulates a container exiting without being removed.
+        pub async fn exit_container(&self, id: &ContainerId) {
+            self.finish_container(id, 1, "").await;
+        }
+
+        /// Simulates a container exiting with the given code after writing some logs.
+        pub async fn finish_container(&self, id: &ContainerId, exit_code: i64, logs: &str) {
+            let mut lock = self.state.write().await;
+            lock.exited.insert(id.clone(), exit_code);
+            lock.logs.insert(id.clone(), logs.to_owned());
+        }
+
+        /// Waits for a container with the label to be running.
+        pub async fn wait_for_container(&self, label: &str) -> ContainerId {
+            loop {
+                let running = self
+                    .list_containers(label)
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|summary| summary.state == "running");
+
+                if let Some(summary) = running {
+                    return summary.id;
+                }
+
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        /// Makes the next `count` attempts to list containers fail.
+        pub async fn fail_listings(&self, count: usize) {
+            self.state.write().await.failing_listings = count;
+        }
+
+        /// Makes pruning images fail, as if another prune was already running.
+        pub async fn fail_prunes(&self) {
+            self.state.write().await.failing_prunes = true;
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DockerClient for FakeDockerClient {
+        async fn fetch_images(&self) -> Result<Vec<ImageSummary>> {
+            let lock = self.state.read().await;
+
+            Ok(lock.images.clone())
+        }
+
+        async fn pull_image(&self, _image: &str, _tag: &str) -> Result<()> {
+            Ok(())
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        async fn create_container(
+            &self,
+            image: &str,
+            _environment: &Option<Environment>,
+            _docker_volumes: &HashMap<String, String>,
+            _network: Option<(&NetworkId, &str)>,
+            _args: &[String],
+            labels: &HashMap<String, String>,
+            options: &ContainerOptions,
+        ) -> Result<ContainerId> {
+            let container_id = ContainerId::random();
+
+            let mut lock = self.state.write().await;
+
+            if let Some(remaining) = lock.failing_images.get_mut(image) {
+                ensure!(*remaining > 0, "failed to create container for {image}");
+                *remaining -= 1;
+            }
+
+            lock.containers
+                .push((container_id.clone(), image.to_owned()));
+            lock.labels.insert(container_id.clone(), labels.clone());
+            lock.options.insert(container_id.clone(), options.clone());
+            lock.peak_containers = lock.peak_containers.max(lock.containers.len());
+
+            Ok(container_id)
+        }
+
+        async fn list_containers(&self, label: &str) -> Result<Vec<ContainerSummary>> {
+            let mut lock = self.state.write().await;
+
+            if lock.failing_listings > 0 {
+                lock.failing_listings -= 1;
+                bail!("failed to list containers");
+            }
+
+            let summaries = lock
+                .containers
+                .iter()
+                .filter_map(|(id, _)| {
+                    let labels = lock.labels.get(id).filter(|l| l.contains_key(label))?;
+                    let state = match lock.exited.contains_key(id) {
+                        true => "exited",
+                        false => "running",
+                    };
+
+                    Some(ContainerSummary {
+                        id: id.clone(),
+                        labels: labels.clone(),
+                        state: state.to_owned(),
+                    })
+                })
+                .collect();
+
+            Ok(summaries)
+        }
+
+        async fn start_container(&self, _id: &ContainerId) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get_container_ip(&self, _id: &ContainerId) -> Result<Ipv4Addr> {
+            Ok(Ipv4Addr::LOCALHOST)
+        }
+
+        async fn get_container_state(&self, id: &ContainerId) -> Result<Option<ContainerState>> {
+            let lock = self.state.read().await;
+
+            ensure!(
+                !lock.failing_containers.contains(id),
+                "failed to inspect container {id}"
+            );
+
+            if !lock.containers.iter().any(|(c, _)| c == id) {
+                return Ok(None);
+            }
+
+            let exit_code = lock.exited.get(id).copied();
+
+            Ok(Some(ContainerState {
+                status: String::from(if exit_code.is_none() {
+                    "running"
+                } else {
+                    "exited"
+                }),
+                running: exit_code.is_none(),
+                oom_killed: false,
+                exit_code: exit_code.unwrap_or_default(),
+            }))
+        }
+
+        async fn get_container_logs(&self, id: &ContainerId, _tail: usize) -> Result<String> {
+            let lock = self.state.read().await;
+
+            Ok(lock.logs.get(id).cloned().unwrap_or_default())
+        }
+
+        async fn stop_container(&self, id: &ContainerId) -> Result<()> {
+            let mut lock = self.state.write().await;
+            lock.containers.retain(|c| c.0 != *id);
+
+            Ok(())
+        }
+
+        async fn remove_container(&self, id: &ContainerId) -> Result<()> {
+            let mut lock = self.state.write().await;
+
+            ensure!(
+                !lock.failing_containers.contains(id),
+                "failed to remove container {id}"
+            );
+            lock.containers.retain(|c| c.0 != *id);
+
+            Ok(())
+        }
+
+        async fn get_network_by_name(&self, _name: &str) -> Result<Option<NetworkId>> {
+            Ok(Some(NetworkId("mesh".to_owned())))
+        }
+
+        async fn prune_images(&self) -> Result<()> {
+            let mut lock = self.state.write().await;
+            ensure!(!lock.failing_prunes, "a prune operation is already running");
+
+            let in_use: HashSet<String> =
+                lock.containers.iter().map(|(_, img)| img.clone()).collect();
+            lock.images
+                .retain(|img| img.repo_tags.iter().any(|t| in_use.contains(t)));
+            Ok(())
+        }
+    }
+
+    pub fn create_reconciler<C: DockerClient + Clone>(
+        registry: ServiceRegistry,
+        docker_client: C,
+    ) -> Reconciler<C> {
+        let config = Config {
+            alb: AlbConfig {
+                addr: Ipv4Addr::LOCALHOST,
+                ports: HashMap::from([(Scheme::Http, 5000)]),
+                reconciliation: String::new(),
+                tls: None,
+                mtls: None,
+                acme: None,
+                rate_limit: None,
+                access_log: None,
+                https_redirect: false,
+                hsts: None,
+                admin: None,
+            },
+            secrets: None,
+            metrics: None,
+            polling: None,
+            jobs: HashMap::new(),
+            services: HashMap::new(),
+        };
+
+        let config = Arc::new(ArcSwap::from_pointee(config));
+        let job_runner = JobRunner::new(Arc::default(), Arc::clone(&config), docker_client.clone());
+
+        Reconciler::new(
+            Arc::new(RwLock::new(registry)),
+            ExternalBytes::Filesystem {
+                path: PathBuf::new(),
+            },
+            config,
+            docker_client,
+            MessageBus::new(),
+            Arc::new(job_runner),
+        )
+    }
+
+    #[tokio::test]
+    async fn can_handle_addition_of_services() -> Result<()> {
+        let registry = ServiceRegistry::new();
+
+        let image = "alexanderjackson/f2";
+        let tag = "latest";
+
+        let service = Service {
+            image: image.to_owned(),
+            tag: tag.to_owned(),
+            ..Default::default()
+        };
+
+        let docker_client = FakeDockerClient::default();
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler
+            .handle_diff(Diff::Addition {
+                name: "foobar".to_owned(),
+                definition: service,
+            })
+            .await?;
+
+        // Check we now have some containers in the Docker state
+        let lock = docker_client.state.read().await;
+        let containers = &lock.containers;
+
+        let container_id = containers.iter().find_map(|(id, image_and_tag)| {
+            image_and_tag.eq(&format!("{image}:{tag}")).then_some(id)
+        });
+
+        assert!(container_id.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn containers_are_created_with_the_service_options() -> Result<()> {
+        let options = ContainerOptions {
+            memory_mb: Some(128),
+            user: Some(String::from("nobody")),
+            read_only: true,
+            restart_policy: Some(RestartPolicy::UnlessStopped),
+            healthcheck: Some(ContainerHealthCheck {
+                command: vec![String::from("/bin/healthcheck")],
+                interval_ms: Some(5000),
+                timeout_ms: None,
+                retries: None,
+                start_period_ms: None,
+            }),
+            labels: HashMap::from([
+                (String::from("team"), String::from("platform")),
+                (String::from(SERVICE_LABEL), String::from("overridden")),
+            ]),
+            ..Default::default()
+        };
+
+        let service = Service {
+            image: String::from("myapp"),
+            tag: String::from("v1"),
+            container: options.clone(),
+            ..Default::default()
+        };
+
+        let docker_client = FakeDockerClient::default();
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+
+        reconciler
+            .handle_diff(Diff::Addition {
+                name: String::from("foobar"),
+                definition: service,
+            })
+            .await?;
+
+        let lock = docker_client.state.read().await;
+        let (id, _) = &lock.containers[0];
+        let labels = &lock.labels[id];
+
+        assert_eq!(lock.options.get(id), Some(&options));
+        assert_eq!(labels.get("team").map(String::as_str), Some("platform"));
+        assert_eq!(
+            labels.get(SERVICE_LABEL).map(String::as_str),
+            Some("foobar")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_handle_removal_of_service() -> Result<()> {
+        let mut registry = ServiceRegistry::new();
+
+        let service = "foobar";
+        let image = "alexanderjackson/f2";
+        let tag = "latest";
+
+        let docker_client = FakeDockerClient::default();
+
+        let id = docker_client
+            .create_container(
+                &format!("{image}:{tag}"),
+                &None,
+                &HashMap::new(),
+                Some((&NetworkId("mesh".to_owned()), "foobar.local")),
+                &[],
+                &HashMap::new(),
+                &ContainerOptions::default(),
+            )
+            .await?;
+
+        registry.add_container(
+            service,
+            StartedContainerDetails {
+                id,
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        let diff = Diff::Removal {
+            name: service.to_owned(),
+        };
+
+        reconciler.handle_diff(diff).await?;
+
+        // Check the state is now empty
+        let lock = docker_client.state.read().await;
+        let containers = &lock.containers;
+
+        assert!(containers.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_handle_scale_up_of_service() -> Result<()> {
+        let mut registry = ServiceRegistry::new();
+
+        let service = "foobar";
+        let image = "alexanderjackson/f2";
+        let tag = "latest";
+
+        let docker_client = FakeDockerClient::default();
+
+        let service_definition = Service {
+            image: image.to_owned(),
+            tag: tag.to_owned(),
+            ..Default::default()
+        };
+
+        let mut altered_definition = service_definition.clone();
+        altered_definition.replicas = ReplicaCount::try_from(2)?;
+
+        let image_and_tag = format!("{image}:{tag}");
+        let id = docker_client
+            .create_container(
+                &image_and_tag,
+                &None,
+                &HashMap::new(),
+                Some((&NetworkId("mesh".to_owned()), "foobar.local")),
+                &[],
+                &HashMap::new(),
+                &ContainerOptions::default(),
+            )
+            .await?;
+
+        registry.define(service, service_definition.clone());
+        registry.add_container(
+            service,
+            StartedContainerDetails {
+                id: id.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        let diff = Diff::Alteration {
+            name: service.to_owned(),
+            old_definition: service_definition,
+            new_definition: altered_definition,
+        };
+
+        reconciler.handle_diff(diff).await?;
+
+        // Check we now have 2 containers for this image and tag
+        let lock = docker_client.state.read().await;
+        let containers = &lock.containers;
+        let matching_containers = containers.iter().filter(|c| c.1 == image_and_tag).count();
+
+        assert_eq!(matching_containers, 2);
+
+        // Neither of these containers are our original one
+        let original_container = containers.iter().find(|c| c.0 == id);
+        assert!(original_container.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prunes_unused_images_after_tag_change() -> Result<()> {
+        let mut registry = ServiceRegistry::new();
+
+        let service = "foobar";
+        let image = "myapp";
+        let old_tag = "v1";
+        let new_tag = "v2";
+
+        let docker_client = FakeDockerClient::default();
+
+        // Pre-seed the old image on the host
+        {
+            let mut lock = docker_client.state.write().await;
+            lock.images.push(ImageSummary {
+                repo_tags: vec![format!("{image}:{old_tag}")],
+            });
+        }
+
+        // Create a running container for v1
+        let id = docker_client
+            .create_container(
+                &format!("{image}:{old_tag}"),
+                &None,
+                &HashMap::new(),
+                Some((&NetworkId("mesh".to_owned()), "foobar.local")),
+                &[],
+                &HashMap::new(),
+                &ContainerOptions::default(),
+            )
+            .await?;
+
+        let old_definition = Service {
+            image: image.to_owned(),
+            tag: old_tag.to_owned(),
+            ..Default::default()
+        };
+        let new_definition = Service {
+            image: image.to_owned(),
+            tag: new_tag.to_owned(),
+            ..Default::default()
+        };
+
+        registry.define(service, old_definition.clone());
+        registry.add_container(
+            service,
+            StartedContainerDetails {
+                id,
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition,
+                new_definition,
+            })
+            .await?;
+
+        docker_client.prune_images().await?;
+
+        let lock = docker_client.state.read().await;
+
+        // Old image should be gone
+        let old_image_present = lock
+            .images
+            .iter()
+            .any(|img| img.repo_tags.contains(&format!("{image}:{old_tag}")));
+        assert!(!old_image_present, "old image should have been pruned");
+
+        // New container should exist
+        let new_container_present = lock
+            .containers
+            .iter()
+            .any(|(_, img)| img == &format!("{image}:{new_tag}"));
+        assert!(new_container_present, "new container should be running");
+
+        Ok(())
+    }
+
+    /// Sets up a registry with a single running container for `service`, returning the registry
+    /// and the identifier of the container.
+    pub async fn setup_running_service(
+        docker_client: &FakeDockerClient,
+        service: &str,
+        definition: &Service,
+    ) -> Result<(ServiceRegistry, ContainerId)> {
+        let mut registry = ServiceRegistry::new();
+
+        let id = docker_client
+            .create_container(
+                &format!("{}:{}", definition.image, definition.tag),
+                &None,
+                &HashMap::new(),
+                Some((&NetworkId("mesh".to_owned()), "foobar.local")),
+                &[],
+                &HashMap::new(),
+                &ContainerOptions::default(),
+            )
+            .await?;
+
+        registry.define(service, definition.clone());
+        registry.add_container(
+            service,
+            StartedContainerDetails {
+                id: id.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        Ok((registry, id))
+    }
+
+    fn health_check_on_port(port: u16) -> HealthCheckDefinition {
+        HealthCheckDefinition {
+            path: String::from("/health"),
+            port,
+            period_ms: 2,
+            success_threshold: 1,
+            failure_threshold: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn alterations_complete_once_health_checks_pass() -> Result<()> {
+        let service = "foobar";
+        let image = "myapp";
+
+        let docker_client = FakeDockerClient::default();
+        let addr = spawn_server(|_| StatusCode::OK).await?;
+
+        let old_definition = Service {
+            image: image.to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+        let new_definition = Service {
+            image: image.to_owned(),
+            tag: "v2".to_owned(),
+            health_check: Some(health_check_on_port(addr.port())),
+            ..Default::default()
+        };
+
+        let (registry, id) =
+            setup_running_service(&docker_client, service, &old_definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition,
+                new_definition,
+            })
+            .await?;
+
+        let lock = docker_client.state.read().await;
+
+        assert_eq!(lock.containers.len(), 1);
+        assert_ne!(lock.containers[0].0, id, "old container should be removed");
+        assert_eq!(lock.containers[0].1, format!("{image}:v2"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn alterations_are_rolled_back_if_health_checks_fail() -> Result<()> {
+        let service = "foobar";
+        let image = "myapp";
+
+        let docker_client = FakeDockerClient::default();
+        let addr = spawn_server(|_| StatusCode::INTERNAL_SERVER_ERROR).await?;
+
+        let old_definition = Service {
+            image: image.to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+        let new_definition = Service {
+            image: image.to_owned(),
+            tag: "v2".to_owned(),
+            health_check: Some(health_check_on_port(addr.port())),
+            ..Default::default()
+        };
+
+        let (registry, id) =
+            setup_running_service(&docker_client, service, &old_definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        let result = reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition,
+                new_definition,
+            })
+            .await;
+
+        assert!(
+            result.is_err(),
+            "failed health checks should fail the alteration"
+        );
+
+        // Only the original container should still exist
+        let lock = docker_client.state.read().await;
+        assert_eq!(lock.containers, vec![(id.clone(), format!("{image}:v1"))]);
+
+        // And it should still be the one taking traffic
+        let registry = reconciler.registry.read().await;
+        let running = registry
+            .get_running_containers(service)
+            .expect("service should still have containers");
+
+        assert_eq!(running.len(), 1);
+        assert!(running.iter().all(|details| details.id == id));
+
+        Ok(())
+    }
+
+    fn canary_on_tag(tag: &str, weight: u8) -> CanaryDefinition {
+        CanaryDefinition {
+            tag: tag.to_owned(),
+            replicas: ReplicaCount::default(),
+            weight: CanaryWeight::try_from(weight).expect("weight should be a percentage"),
+        }
+    }
+
+    #[tokio::test]
+    async fn canaries_can_be_added_without_replacing_stable_containers() -> Result<()> {
+        let service = "foobar";
+        let image = "myapp";
+
+        let docker_client = FakeDockerClient::default();
+
+        let old_definition = Service {
+            image: image.to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+        let new_definition = Service {
+            canary: Some(canary_on_tag("v2", 10)),
+            ..old_definition.clone()
+        };
+
+        let (registry, id) =
+            setup_running_service(&docker_client, service, &old_definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition: old_definition.clone(),
+                new_definition: new_definition.clone(),
+            })
+            .await?;
+
+        {
+            let lock = docker_client.state.read().await;
+            let images: Vec<_> = lock.containers.iter().map(|(_, image)| image).collect();
+
+            assert_eq!(lock.containers[0].0, id, "stable container should remain");
+            assert_eq!(images, vec!["myapp:v1", "myapp:v2"]);
+
+            let registry = reconciler.registry.read().await;
+            let canaries = registry.get_canary_containers(service).map(|c| c.len());
+
+            assert_eq!(canaries, Some(1));
+        }
+
+        // Aborting the canary removes its containers and leaves the stable ones alone
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition: new_definition,
+                new_definition: old_definition,
+            })
+            .await?;
+
+        assert_eq!(docker_client.container_ids().await, vec![id]);
+
+        let registry = reconciler.registry.read().await;
+        let canaries = registry.get_canary_containers(service).map(|c| c.len());
+
+        assert_eq!(canaries, Some(0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicas_take_the_lowest_free_slot() -> Result<()> {
+        let service = "foobar";
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let docker_client = FakeDockerClient::default();
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+
+        let slot_of = |details: &StartedContainerDetails| {
+            let docker_client = docker_client.clone();
+            let id = details.id.clone();
+
+            async move {
+                let lock = docker_client.state.read().await;
+                lock.labels[&id][REPLICA_LABEL].clone()
+            }
+        };
+
+        let started = reconciler
+            .start_containers(service, &definition, ReplicaCount::try_from(2)?)
+            .await?;
+
+        assert_eq!(slot_of(&started[0]).await, "0");
+        assert_eq!(slot_of(&started[1]).await, "1");
+
+        // A replacement takes the slot of the container that went away
+        docker_client.remove_container(&started[0].id).await?;
+
+        let replacement = reconciler
+            .start_containers(service, &definition, ReplicaCount::default())
+            .await?;
+
+        assert_eq!(slot_of(&replacement[0]).await, "0");
+
+        // While the others are running, new containers get a slot of their own
+        let extra = reconciler
+            .start_containers(service, &definition, ReplicaCount::default())
+            .await?;
+
+        assert_eq!(slot_of(&extra[0]).await, "2");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rendered_volumes_are_removed_with_their_container() -> Result<()> {
+        let service = "rendered-volumes";
+
+        let source = tempfile::NamedTempFile::new()?;
+        std::fs::write(source.path(), "node = {{ f2.replica }}\n")?;
+
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            volumes: HashMap::from([(
+                String::from("config"),
+                VolumeDefinition {
+                    source: ExternalBytes::Filesystem {
+                        path: source.path().to_owned(),
+                    },
+                    target: String::from("/etc/app/config.toml"),
+                    template: true,
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let docker_client = FakeDockerClient::default();
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+
+        let started = reconciler
+            .start_containers(service, &definition, ReplicaCount::try_from(2)?)
+            .await?;
+
+        let rendered = |replica: usize| {
+            PathBuf::from(format!(
+                "/tmp/f2/rendered/{SERVICE_LABEL}/{service}/{replica}/config/config.toml"
+            ))
+        };
+
+        assert!(rendered(0).exists());
+        assert!(rendered(1).exists());
+
+        reconciler
+            .remove_containers(service, ShutdownMode::Forceful, &started[..1])
+            .await?;
+
+        assert!(!rendered(0).exists());
+        assert!(rendered(1).exists());
+
+        reconciler
+            .remove_containers(service, ShutdownMode::Forceful, &started[1..])
+            .await?;
+
+        assert!(!rendered(1).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn changing_the_canary_weight_keeps_the_canary_containers() -> Result<()> {
+        let service = "foobar";
+
+        let docker_client = FakeDockerClient::default();
+
+        let old_definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            canary: Some(canary_on_tag("v2", 10)),
+            ..Default::default()
+        };
+        let new_definition = Service {
+            canary: Some(canary_on_tag("v2", 50)),
+            ..old_definition.clone()
+        };
+
+        let (registry, _) = setup_running_service(&docker_client, service, &old_definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler.start_canary(service, &old_definition).await?;
+
+        let before = docker_client.container_ids().await;
+
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition,
+                new_definition,
+            })
+            .await?;
+
+        assert_eq!(docker_client.container_ids().await, before);
+
+        let registry = reconciler.registry.read().await;
+        let weight = registry.get_definitions()[service]
+            .canary
+            .as_ref()
+            .map(|canary| *canary.weight);
+
+        assert_eq!(weight, Some(50));
+        assert_eq!(
+            registry.get_canary_containers(service).map(|c| c.len()),
+            Some(1)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn promoting_a_canary_replaces_both_sets_of_containers() -> Result<()> {
+        let service = "foobar";
+        let image = "myapp";
+
+        let docker_client = FakeDockerClient::default();
+
+        let old_definition = Service {
+            image: image.to_owned(),
+            tag: "v1".to_owned(),
+            canary: Some(canary_on_tag("v2", 10)),
+            ..Default::default()
+        };
+        let new_definition = Service {
+            tag: "v2".to_owned(),
+            canary: None,
+            ..old_definition.clone()
+        };
+
+        let (registry, _) = setup_running_service(&docker_client, service, &old_definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler.start_canary(service, &old_definition).await?;
+
+        reconciler
+            .handle_diff(Diff::Alteration {
+                name: service.to_owned(),
+                old_definition,
+                new_definition,
+            })
+            .await?;
+
+        let lock = docker_client.state.read().await;
+        let images: Vec<_> = lock.containers.iter().map(|(_, image)| image).collect();
+
+        assert_eq!(images, vec!["myapp:v2"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn services_roll_out_once_deploy_jobs_succeed() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+
+        let mut file = NamedTempFile::new()?;
+        write!(
+            file,
+            r#"
+            alb:
+              addr: 127.0.0.1
+              ports:
+                http: 5000
+              reconciliation: /reconcile
+            jobs:
+              migrate:
+                image: myapp
+                tag: v1
+                schedule:
+                  trigger: on_deploy
+            services:
+              myapp:
+                image: myapp
+                tag: v1
+                replicas: 1
+            "#
+        )?;
+
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+        let reconciler = Reconciler {
+            config_location: Arc::new(ExternalBytes::Filesystem {
+                path: file.path().to_owned(),
+            }),
+            ..reconciler
+        };
+
+        // A failed migration leaves the services alone and is run again by the next attempt
+        let failed_job = async {
+            let id = docker_client.wait_for_container(JOB_LABEL).await;
+            docker_client
+                .finish_container(&id, 1, "migration failed")
+                .await;
+        };
+
+        let (result, _) = tokio::join!(reconciler.reconcile(), failed_job);
+
+        assert!(result.is_err());
+        assert!(docker_client
+            .list_containers(SERVICE_LABEL)
+            .await?
+            .is_empty());
+        assert!(reconciler.config.load().jobs.is_empty());
+
+        let services_during_job = async {
+            let id = docker_client.wait_for_container(JOB_LABEL).await;
+            let services = docker_client.list_containers(SERVICE_LABEL).await;
+            docker_client.finish_container(&id, 0, "").await;
+
+            services
+        };
+
+        let (result, services) = tokio::join!(reconciler.reconcile(), services_during_job);
+        result?;
+
+        assert!(services?.is_empty());
+        assert_eq!(docker_client.list_containers(SERVICE_LABEL).await?.len(), 1);
+        assert!(reconciler.config.load().jobs.contains_key("migrate"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_to_prune_images_does_not_fail_reconciliation() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        docker_client.fail_prunes().await;
+
+        let mut file = NamedTempFile::new()?;
+        write!(
+            file,
+            r#"
+            alb:
+              addr: 127.0.0.1
+              ports:
+                http: 5000
+              reconciliation: /reconcile
+            services:
+              myapp:
+                image: myapp
+                tag: v1
+                replicas: 1
+            "#
+        )?;
+
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+        let reconciler = Reconciler {
+            config_location: Arc::new(ExternalBytes::Filesystem {
+                path: file.path().to_owned(),
+            }),
+            ..reconciler
+        };
+
+        reconciler.reconcile().await?;
+
+        assert!(reconciler.config.load().services.contains_key("myapp"));
+        assert_eq!(docker_client.list_containers(SERVICE_LABEL).await?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use color_eyre::eyre::Result;
+use sha2::{Digest, Sha256};
+
+use crate::config::{ExternalBytes, Revision};
+
+/// Notices changes to the configuration by polling its location, only fetching the content when
+/// the revision changes and only reporting a change when the content differs.
+///
+/// A change is only remembered once it has been committed, so that a reconciliation which fails is
+/// tried again on the next poll.
+#[derive(Debug, Default)]
+pub struct ChangeDetector {
+    revision: Option<Revision>,
+    digest: Option<Vec<u8>>,
+    pending: Option<(Revision, Vec<u8>)>,
+}
+
+impl ChangeDetector {
+    /// Checks whether the content has changed since the last committed check, which is always
+    /// the case for the first one.
+    pub async fn has_changed(&mut self, location: &ExternalBytes) -> Result<bool> {
+        let revision = location.revision().await?;
+
+        if self.revision.as_ref() == Some(&revision) {
+            return Ok(false);
+        }
+
+        // Files can be touched or rewritten with the same content, so compare that too
+        let digest = Sha256::digest(location.resolve().await?).to_vec();
+        let changed = self.digest.as_ref() != Some(&digest);
+
+        tracing::debug!(?revision, %changed, "configuration revision changed");
+
+        self.pending = Some((revision, digest));
+
+        // There is nothing to apply for the same content, so it can be remembered straight away
+        if !changed {
+            self.commit();
+        }
+
+        Ok(changed)
+    }
+
+    /// Remembers the content from the last check, once it has been applied successfully.
+    pub fn commit(&mut self) {
+        if let Some((revision, digest)) = self.pending.take() {
+            self.revision = Some(revision);
+            self.digest = Some(digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use color_eyre::eyre::Result;
+
+    use crate::config::ExternalBytes;
+    use crate::reconciler::polling::ChangeDetector;
+    use crate::reconciler::tests::{create_reconciler, FakeDockerClient};
+    use crate::reconciler::Reconciler;
+    use crate::service_registry::ServiceRegistry;
+
+    #[tokio::test]
+    async fn changes_are_detected_by_content() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("f2.yaml");
+        let location = ExternalBytes::Filesystem { path: path.clone() };
+
+        let write = |content: &str, modified: SystemTime| -> Result<()> {
+            let mut file = File::create(&path)?;
+            file.write_all(content.as_bytes())?;
+            file.set_modified(modified)?;
+
+            Ok(())
+        };
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut detector = ChangeDetector::default();
+
+        write("services: {}", start)?;
+        assert!(detector.has_changed(&location).await?);
+
+        detector.commit();
+        assert!(!detector.has_changed(&location).await?);
+
+        // Rewriting the same content only changes the modification time
+        write("services: {}", start + Duration::from_secs(1))?;
+        assert!(!detector.has_changed(&location).await?);
+
+        write("services: { backend: {} }", start + Duration::from_secs(2))?;
+        assert!(detector.has_changed(&location).await?);
+
+        // Changes are reported again until they have been applied
+        assert!(detector.has_changed(&location).await?);
+
+        detector.commit();
+        assert!(!detector.has_changed(&location).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_reconciliations_are_retried_on_the_next_poll() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("f2.yaml");
+
+        std::fs::write(
+            &path,
+            r#"
+            alb:
+              addr: 127.0.0.1
+              ports:
+                http: 5000
+              reconciliation: /reconcile
+            services:
+              backend:
+                image: backend
+                tag: v1
+                replicas: 1
+            "#,
+        )?;
+
+        let docker_client = FakeDockerClient::default();
+        docker_client.fail_image_after("backend:v1", 0).await;
+
+        let reconciler = create_reconciler(ServiceRegistry::new(), docker_client.clone());
+        let reconciler = Reconciler {
+            config_location: Arc::new(ExternalBytes::Filesystem { path }),
+            ..reconciler
+        };
+
+        let mut detector = ChangeDetector::default();
+
+        reconciler.poll_for_changes(&mut detector).await;
+        assert!(docker_client.container_ids().await.is_empty());
+
+        // The file has not changed since, but the failed service is tried again
+        docker_client.fail_image_after("backend:v1", 1).await;
+        reconciler.poll_for_changes(&mut detector).await;
+
+        assert_eq!(docker_client.container_ids().await.len(), 1);
+        assert!(reconciler.config.load().services.contains_key("backend"));
+
+        // Once applied, the change is not reported again
+        assert!(!detector.has_changed(&reconciler.config_location).await?);
+
+        Ok(())
+    }
+}
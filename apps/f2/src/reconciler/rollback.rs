@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+
+use crate::config::Service;
+use crate::docker::api::StartedContainerDetails;
+use crate::docker::client::DockerClient;
+use crate::reconciler::{Reconciler, Track};
+
+/// The definition and containers a service had before a change was applied to it.
+#[derive(Debug)]
+pub struct ServiceSnapshot {
+    definition: Option<Service>,
+    stable: Vec<StartedContainerDetails>,
+    canary: Vec<StartedContainerDetails>,
+}
+
+impl<C: DockerClient> Reconciler<C> {
+    pub(super) async fn snapshot(&self, name: &str) -> ServiceSnapshot {
+        let read_lock = self.registry.read().await;
+
+        ServiceSnapshot {
+            definition: read_lock.get_definitions().get(name).cloned(),
+            stable: read_lock
+                .get_running_containers(name)
+                .map(|containers| containers.iter().cloned().collect())
+                .unwrap_or_default(),
+            canary: read_lock
+                .get_canary_containers(name)
+                .map(|containers| containers.iter().cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns a service to how it was before a failed change, removing any containers started for
+    /// the change and putting old containers that are still running back into the load balancer.
+    /// Supervision starts replacements for any old containers that had already been removed.
+    #[tracing::instrument(skip(self, snapshot))]
+    pub(super) async fn roll_back(&self, name: &str, snapshot: ServiceSnapshot) {
+        let previous: HashSet<_> = snapshot
+            .stable
+            .iter()
+            .chain(&snapshot.canary)
+            .map(|details| details.id.clone())
+            .collect();
+
+        let mut write_lock = self.registry.write().await;
+
+        let started: Vec<_> = write_lock
+            .get_running_containers(name)
+            .into_iter()
+            .chain(write_lock.get_canary_containers(name))
+            .flatten()
+            .filter(|details| !previous.contains(&details.id))
+            .cloned()
+            .collect();
+
+        for details in &started {
+            write_lock.remove_container_by_id(name, &details.id);
+        }
+
+        match snapshot.definition {
+            Some(definition) => write_lock.define(name, definition),
+            None => write_lock.undefine(name),
+        }
+
+        drop(write_lock);
+
+        self.discard_containers(name, &started).await;
+
+        let mut restored = 0;
+
+        for (track, containers) in [
+            (Track::Stable, snapshot.stable),
+            (Track::Canary, snapshot.canary),
+        ] {
+            let mut running = Vec::new();
+
+            for details in containers {
+                if self
+                    .registry
+                    .read()
+                    .await
+                    .find_container(&details.id)
+                    .is_some()
+                {
+                    continue;
+                }
+
+                match self.docker_client.get_container_state(&details.id).await {
+                    Ok(Some(state)) if state.running => running.push(details),
+                    Ok(_) => {}
+                    Err(error) => {
+                        tracing::warn!(id = %details.id, %error, "failed to check container while rolling back");
+                    }
+                }
+            }
+
+            restored += running.len();
+            self.register(name, track, running).await;
+        }
+
+        tracing::info!(removed = %started.len(), %restored, "rolled back the service");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use color_eyre::eyre::Result;
+    use tempfile::NamedTempFile;
+
+    use crate::config::{
+        Config, ContainerOptions, DeploymentStrategy, Diff, ExternalBytes, ReplicaCount, Service,
+    };
+    use crate::docker::api::StartedContainerDetails;
+    use crate::docker::client::DockerClient;
+    use crate::docker::models::ContainerId;
+    use crate::reconciler::tests::{create_reconciler, FakeDockerClient};
+    use crate::reconciler::Reconciler;
+    use crate::service_registry::ServiceRegistry;
+
+    fn create_service(image: &str, tag: &str, replicas: u8) -> Result<Service> {
+        Ok(Service {
+            image: image.to_owned(),
+            tag: tag.to_owned(),
+            replicas: ReplicaCount::try_from(replicas)?,
+            ..Default::default()
+        })
+    }
+
+    /// Starts containers for each of the definitions, registering them as running services.
+    async fn setup_services(
+        docker_client: &FakeDockerClient,
+        services: &HashMap<String, Service>,
+    ) -> Result<(ServiceRegistry, Vec<ContainerId>)> {
+        let mut registry = ServiceRegistry::new();
+        let mut ids = Vec::new();
+
+        for (name, definition) in services {
+            registry.define(name, definition.clone());
+
+            for _ in 0..definition.replicas.get() {
+                let id = docker_client
+                    .create_container(
+                        &format!("{}:{}", definition.image, definition.tag),
+                        &None,
+                        &HashMap::new(),
+                        None,
+                        &[],
+                        &HashMap::new(),
+                        &ContainerOptions::default(),
+                    )
+                    .await?;
+
+                registry.add_container(
+                    name,
+                    StartedContainerDetails {
+                        id: id.clone(),
+                        addr: Ipv4Addr::LOCALHOST,
+                    },
+                );
+
+                ids.push(id);
+            }
+        }
+
+        Ok((registry, ids))
+    }
+
+    #[tokio::test]
+    async fn failed_services_keep_their_containers_and_definition() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let services = HashMap::from([
+            (String::from("backend"), create_service("backend", "v1", 1)?),
+            (
+                String::from("frontend"),
+                create_service("frontend", "v1", 2)?,
+            ),
+        ]);
+
+        let (registry, old_ids) = setup_services(&docker_client, &services).await?;
+
+        let mut file = NamedTempFile::new()?;
+        write!(
+            file,
+            r#"
+            alb:
+              addr: 127.0.0.1
+              ports:
+                http: 5000
+              reconciliation: /reconcile
+            services:
+              backend:
+                image: backend
+                tag: v2
+                replicas: 1
+              frontend:
+                image: frontend
+                tag: v2
+                replicas: 2
+            "#
+        )?;
+
+        // The second frontend container fails to start, after the first has been created
+        docker_client.fail_image_after("frontend:v2", 1).await;
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+        let reconciler = Reconciler {
+            config_location: Arc::new(ExternalBytes::Filesystem {
+                path: file.path().to_owned(),
+            }),
+            ..reconciler
+        };
+
+        reconciler.config.store(Arc::new(Config {
+            services,
+            ..Config::clone(&reconciler.config.load())
+        }));
+
+        assert!(reconciler.reconcile().await.is_err());
+
+        // The backend was updated but the frontend still runs its old containers
+        let config = reconciler.config.load();
+        let registry = reconciler.registry.read().await;
+
+        assert_eq!(config.services["backend"].tag, "v2");
+        assert_eq!(config.services["frontend"].tag, "v1");
+        assert_eq!(registry.get_definitions()["backend"].tag, "v2");
+        assert_eq!(registry.get_definitions()["frontend"].tag, "v1");
+
+        let frontend: Vec<_> = registry
+            .get_running_containers("frontend")
+            .into_iter()
+            .flatten()
+            .map(|details| details.id.clone())
+            .collect();
+
+        assert_eq!(frontend.len(), 2);
+        assert!(frontend.iter().all(|id| old_ids.contains(id)));
+
+        // Only the new backend and the old frontend containers are left running
+        assert_eq!(docker_client.container_ids().await.len(), 3);
+
+        let record = registry.get_last_reconciliation();
+        let failures = record.map(|record| &record.failures);
+
+        assert!(failures
+            .is_some_and(|failures| failures.len() == 1 && failures.contains_key("frontend")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_rolling_deployments_are_rolled_back() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let old_definition = create_service("myapp", "v1", 3)?;
+        let new_definition = Service {
+            tag: String::from("v2"),
+            deployment: DeploymentStrategy::Rolling {
+                max_surge: 1,
+                max_unavailable: 0,
+            },
+            ..old_definition.clone()
+        };
+
+        let services = HashMap::from([(String::from("myapp"), old_definition.clone())]);
+        let (registry, old_ids) = setup_services(&docker_client, &services).await?;
+
+        // The first batch replaces an old container before the second fails to start
+        docker_client.fail_image_after("myapp:v2", 1).await;
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+        let snapshot = reconciler.snapshot("myapp").await;
+
+        let result = reconciler
+            .handle_diff(Diff::Alteration {
+                name: String::from("myapp"),
+                old_definition,
+                new_definition,
+            })
+            .await;
+
+        assert!(result.is_err());
+
+        reconciler.roll_back("myapp", snapshot).await;
+
+        let running = docker_client.container_ids().await;
+
+        assert_eq!(running.len(), 2);
+        assert!(running.iter().all(|id| old_ids.contains(id)));
+
+        // Supervision brings the service back to its old replica count
+        reconciler.supervise(&mut HashMap::new()).await?;
+
+        let registry = reconciler.registry.read().await;
+
+        assert_eq!(registry.get_definitions()["myapp"].tag, "v1");
+        assert_eq!(
+            registry.get_running_containers("myapp").map(|c| c.len()),
+            Some(3)
+        );
+        assert_eq!(docker_client.container_ids().await.len(), 3);
+
+        Ok(())
+    }
+}
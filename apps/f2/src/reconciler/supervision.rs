@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Result};
+use tokio::time::Instant;
+
+use crate::config::{ReplicaCount, Service};
+use crate::docker::api::{remove_container_and_volumes, StartedContainerDetails};
+use crate::docker::client::DockerClient;
+use crate::docker::models::ContainerId;
+use crate::reconciler::{Reconciler, Track};
+
+/// How long to wait before restarting a service that has only just been restarted.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The longest time to wait between restarts of a service.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long a service must run without any restarts before its backoff is reset.
+const STABLE_PERIOD: Duration = Duration::from_secs(300);
+
+/// Tracks restarts for a service, so that containers which keep exiting are restarted less often.
+#[derive(Debug, Default)]
+pub struct RestartBackoff {
+    restarts: u32,
+    last_restart: Option<Instant>,
+}
+
+impl RestartBackoff {
+    /// Gets the delay required after the most recent restart before the next one.
+    fn delay(&self) -> Duration {
+        match self.restarts {
+            0 => Duration::ZERO,
+            restarts => INITIAL_BACKOFF
+                .saturating_mul(2u32.saturating_pow(restarts - 1))
+                .min(MAX_BACKOFF),
+        }
+    }
+
+    fn is_ready(&self, now: Instant) -> bool {
+        self.last_restart
+            .is_none_or(|last_restart| now >= last_restart + self.delay())
+    }
+
+    fn record_restart(&mut self, now: Instant) {
+        self.restarts = self.restarts.saturating_add(1);
+        self.last_restart = Some(now);
+    }
+
+    fn reset_if_stable(&mut self, now: Instant) {
+        if self
+            .last_restart
+            .is_some_and(|last_restart| now >= last_restart + STABLE_PERIOD)
+        {
+            *self = Self::default();
+        }
+    }
+}
+
+impl<C: DockerClient> Reconciler<C> {
+    /// Removes any containers that have exited from the registry and starts replacements until
+    /// each service is back to its configured number of replicas.
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn supervise(
+        &self,
+        backoffs: &mut HashMap<(String, Track), RestartBackoff>,
+    ) -> Result<()> {
+        let container_sets: Vec<_> = {
+            let read_lock = self.registry.read().await;
+
+            read_lock
+                .get_definitions()
+                .iter()
+                .flat_map(|(name, definition)| {
+                    let stable = read_lock
+                        .get_running_containers(name)
+                        .map(|containers| containers.iter().cloned().collect())
+                        .unwrap_or_default();
+
+                    let canary = definition.canary_definition().map(|canary| {
+                        let containers = read_lock
+                            .get_canary_containers(name)
+                            .map(|containers| containers.iter().cloned().collect())
+                            .unwrap_or_default();
+
+                        (name.clone(), Track::Canary, canary, containers)
+                    });
+
+                    std::iter::once((name.clone(), Track::Stable, definition.clone(), stable))
+                        .chain(canary)
+                })
+                .collect::<Vec<(String, Track, Service, Vec<StartedContainerDetails>)>>()
+        };
+
+        backoffs.retain(|key, _| {
+            container_sets
+                .iter()
+                .any(|(name, track, ..)| (name, track) == (&key.0, &key.1))
+        });
+
+        for (name, track, definition, containers) in container_sets {
+            let mut exited = Vec::new();
+
+            for details in &containers {
+                // An error for one container should not stop the others being supervised
+                let state = match self.docker_client.get_container_state(&details.id).await {
+                    Ok(state) => state,
+                    Err(error) => {
+                        tracing::warn!(%name, id = %details.id, %error, "failed to check the state of a container");
+                        continue;
+                    }
+                };
+
+                match state {
+                    Some(state) if state.running => {}
+                    Some(state) => {
+                        tracing::warn!(
+                            %name,
+                            id = %details.id,
+                            status = %state.status,
+                            exit_code = %state.exit_code,
+                            oom_killed = %state.oom_killed,
+                            "container has exited"
+                        );
+
+                        exited.push(details);
+                    }
+                    None => {
+                        tracing::warn!(%name, id = %details.id, "container no longer exists");
+
+                        exited.push(details);
+                    }
+                }
+            }
+
+            if !exited.is_empty() {
+                let mut write_lock = self.registry.write().await;
+
+                for details in &exited {
+                    write_lock.remove_container_by_id(&name, &details.id);
+                }
+
+                drop(write_lock);
+
+                for details in &exited {
+                    if let Err(error) =
+                        remove_container_and_volumes(&self.docker_client, &details.id).await
+                    {
+                        tracing::warn!(%name, id = %details.id, %error, "failed to remove exited container");
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            let backoff = backoffs.entry((name.clone(), track)).or_default();
+
+            let running = u8::try_from(containers.len() - exited.len()).unwrap_or(u8::MAX);
+            let missing = definition.replicas.get().saturating_sub(running);
+
+            if missing == 0 {
+                backoff.reset_if_stable(now);
+                continue;
+            }
+
+            if !backoff.is_ready(now) {
+                tracing::debug!(%name, delay = ?backoff.delay(), "waiting before restarting containers");
+                continue;
+            }
+
+            tracing::info!(%name, ?track, count = %missing, "starting replacement containers");
+
+            backoff.record_restart(now);
+
+            let replicas = ReplicaCount::try_from(missing)?;
+
+            match self.start_containers(&name, &definition, replicas).await {
+                Ok(started) => self.register(&name, track, started).await,
+                Err(error) => {
+                    tracing::error!(%name, ?track, %error, "failed to start replacement containers")
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces a single container with a fresh one, only removing the old container once its
+    /// replacement is healthy and registered with the load balancer.
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn restart_container(&self, id: &ContainerId) -> Result<()> {
+        let (name, details, track, definition) = {
+            let read_lock = self.registry.read().await;
+
+            let (name, details, canary) = read_lock
+                .find_container(id)
+                .ok_or_else(|| eyre!("container {id} is not registered"))?;
+
+            let definition = read_lock
+                .get_definitions()
+                .get(name)
+                .ok_or_else(|| eyre!("no definition found for {name}"))?;
+
+            let (track, definition) = if canary {
+                let canary = definition
+                    .canary_definition()
+                    .ok_or_else(|| eyre!("{name} no longer has a canary"))?;
+
+                (Track::Canary, canary)
+            } else {
+                (Track::Stable, definition.clone())
+            };
+
+            (name.to_owned(), details.clone(), track, definition)
+        };
+
+        tracing::info!(%name, ?track, "starting a replacement container");
+
+        let started = self
+            .start_containers(&name, &definition, ReplicaCount::default())
+            .await?;
+
+        self.register(&name, track, started).await;
+        self.remove_containers(&name, definition.shutdown_mode, &[details])
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use color_eyre::eyre::Result;
+    use tokio::time::Instant;
+
+    use crate::config::Service;
+    use crate::docker::api::StartedContainerDetails;
+    use crate::reconciler::supervision::{
+        RestartBackoff, INITIAL_BACKOFF, MAX_BACKOFF, STABLE_PERIOD,
+    };
+    use crate::reconciler::tests::{create_reconciler, setup_running_service, FakeDockerClient};
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let now = Instant::now();
+        let mut backoff = RestartBackoff::default();
+
+        assert!(backoff.is_ready(now));
+
+        backoff.record_restart(now);
+        assert_eq!(backoff.delay(), INITIAL_BACKOFF);
+        assert!(!backoff.is_ready(now));
+        assert!(backoff.is_ready(now + INITIAL_BACKOFF));
+
+        backoff.record_restart(now);
+        assert_eq!(backoff.delay(), INITIAL_BACKOFF * 2);
+
+        for _ in 0..10 {
+            backoff.record_restart(now);
+        }
+
+        assert_eq!(backoff.delay(), MAX_BACKOFF);
+
+        backoff.reset_if_stable(now + Duration::from_secs(1));
+        assert_eq!(backoff.delay(), MAX_BACKOFF);
+
+        backoff.reset_if_stable(now + STABLE_PERIOD);
+        assert!(backoff.is_ready(now));
+    }
+
+    #[tokio::test]
+    async fn exited_containers_are_replaced() -> Result<()> {
+        let service = "foobar";
+
+        let docker_client = FakeDockerClient::default();
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let (registry, id) = setup_running_service(&docker_client, service, &definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        let mut backoffs = HashMap::new();
+
+        // Nothing should change while the container is running
+        reconciler.supervise(&mut backoffs).await?;
+        assert_eq!(docker_client.container_ids().await, vec![id.clone()]);
+
+        docker_client.exit_container(&id).await;
+        reconciler.supervise(&mut backoffs).await?;
+
+        let running = docker_client.container_ids().await;
+        assert_eq!(running.len(), 1);
+        assert_ne!(running[0], id, "exited container should be removed");
+
+        let registry = reconciler.registry.read().await;
+        let registered: Vec<_> = registry
+            .get_running_containers(service)
+            .expect("service should have containers")
+            .iter()
+            .map(|details| details.id.clone())
+            .collect();
+
+        assert_eq!(registered, running);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn docker_errors_only_skip_the_affected_container() -> Result<()> {
+        let docker_client = FakeDockerClient::default();
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let (mut registry, broken) =
+            setup_running_service(&docker_client, "broken", &definition).await?;
+        let (_, exited) = setup_running_service(&docker_client, "other", &definition).await?;
+
+        registry.define("other", definition.clone());
+        registry.add_container(
+            "other",
+            StartedContainerDetails {
+                id: exited.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let reconciler = create_reconciler(registry, docker_client.clone());
+        let mut backoffs = HashMap::new();
+
+        docker_client.fail_container(&broken).await;
+        docker_client.exit_container(&exited).await;
+
+        reconciler.supervise(&mut backoffs).await?;
+
+        let running = docker_client.container_ids().await;
+
+        assert!(
+            running.contains(&broken),
+            "the broken container is left alone"
+        );
+        assert!(
+            !running.contains(&exited),
+            "the exited container is replaced"
+        );
+        assert_eq!(running.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repeated_exits_are_restarted_with_a_backoff() -> Result<()> {
+        let service = "foobar";
+
+        let docker_client = FakeDockerClient::default();
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let (registry, id) = setup_running_service(&docker_client, service, &definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        let mut backoffs = HashMap::new();
+
+        docker_client.exit_container(&id).await;
+        reconciler.supervise(&mut backoffs).await?;
+
+        let replacement = docker_client.container_ids().await;
+        assert_eq!(replacement.len(), 1);
+
+        // The replacement exits immediately, so it should not be restarted straight away
+        docker_client.exit_container(&replacement[0]).await;
+        reconciler.supervise(&mut backoffs).await?;
+
+        assert!(docker_client.container_ids().await.is_empty());
+
+        let registry = reconciler.registry.read().await;
+        let registered = registry.get_running_containers(service);
+
+        assert!(registered.is_none_or(|containers| containers.is_empty()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn containers_can_be_restarted_individually() -> Result<()> {
+        let service = "foobar";
+
+        let docker_client = FakeDockerClient::default();
+        let definition = Service {
+            image: "myapp".to_owned(),
+            tag: "v1".to_owned(),
+            ..Default::default()
+        };
+
+        let (registry, id) = setup_running_service(&docker_client, service, &definition).await?;
+        let reconciler = create_reconciler(registry, docker_client.clone());
+
+        reconciler.restart_container(&id).await?;
+
+        let running = docker_client.container_ids().await;
+        assert_eq!(running.len(), 1);
+        assert_ne!(running[0], id, "restarted container should be replaced");
+
+        let registry = reconciler.registry.read().await;
+        assert!(registry.find_container(&id).is_none());
+        assert!(registry.find_container(&running[0]).is_some());
+
+        drop(registry);
+
+        // Unknown containers are rejected rather than ignored
+        assert!(reconciler.restart_container(&id).await.is_err());
+
+        Ok(())
+    }
+}
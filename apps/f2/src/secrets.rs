@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+use crate::config::ExternalBytes;
+use crate::crypto::{decrypt, encrypt, fits_in_key, open, seal};
+
+/// The prefix marking an environment value as a secret, rather than a plain value.
+pub const SECRET_PREFIX: &str = "secret:";
+
+/// Where the value of a secret comes from, written after the `secret:` prefix. Base64 never
+/// contains a colon, so values encrypted with the key directly need no further prefix.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SecretSource {
+    /// A value encrypted with the public key, such as `secret:<base64>`, which is limited by the
+    /// size of the key.
+    Inline { encrypted: String },
+    /// A value of any size encrypted with a random AES-256-GCM key, which is encrypted with the
+    /// public key, such as `secret:envelope:<key>:<payload>`.
+    Envelope { key: String, payload: String },
+    /// The content of a file on the host, such as `secret:file:/run/secrets/password`.
+    File { path: PathBuf },
+    /// A variable from the environment f2 is running in, such as `secret:env:DATABASE_PASSWORD`.
+    Environment { variable: String },
+    /// The content of an S3 object, such as `secret:s3:bucket/path/to/secret`.
+    S3 { bucket: String, key: String },
+}
+
+impl SecretSource {
+    /// Parses a secret reference, returning `None` if the value is not a secret at all.
+    pub fn parse(value: &str) -> Option<Result<Self>> {
+        let reference = value.strip_prefix(SECRET_PREFIX)?;
+
+        let Some((scheme, rest)) = reference.split_once(':') else {
+            return Some(Ok(Self::Inline {
+                encrypted: reference.to_owned(),
+            }));
+        };
+
+        let source = match scheme {
+            "envelope" => rest
+                .split_once(':')
+                .map(|(key, payload)| Self::Envelope {
+                    key: key.to_owned(),
+                    payload: payload.to_owned(),
+                })
+                .ok_or_else(|| eyre!("envelope secrets must have a key and a payload")),
+            "file" => Ok(Self::File {
+                path: PathBuf::from(rest),
+            }),
+            "env" => Ok(Self::Environment {
+                variable: rest.to_owned(),
+            }),
+            "s3" => rest
+                .split_once('/')
+                .map(|(bucket, key)| Self::S3 {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                })
+                .ok_or_else(|| eyre!("invalid s3 bucket and key provided: {rest}")),
+            other => Err(eyre!("unknown secret source: {other}")),
+        };
+
+        Some(source)
+    }
+
+    /// Whether the value needs the private key to be read.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Self::Inline { .. } | Self::Envelope { .. })
+    }
+
+    /// Fetches and decrypts the value of the secret.
+    pub async fn resolve(&self, private_key: Option<&RsaPrivateKey>) -> Result<String> {
+        let value = match (self, private_key) {
+            (Self::Inline { encrypted }, Some(private_key)) => decrypt(encrypted, private_key)?,
+            (Self::Envelope { key, payload }, Some(private_key)) => {
+                open(key, payload, private_key)?
+            }
+            (Self::Inline { .. } | Self::Envelope { .. }, None) => {
+                return Err(eyre!("Tried to decrypt secret without a key"));
+            }
+            (Self::File { path }, _) => {
+                let content = tokio::fs::read_to_string(path)
+                    .await
+                    .wrap_err_with(|| format!("failed to read secret from {}", path.display()))?;
+
+                // Files written by editors and `echo` usually end with a newline
+                content.trim_end_matches(['\r', '\n']).to_owned()
+            }
+            (Self::Environment { variable }, _) => std::env::var(variable)
+                .wrap_err_with(|| format!("failed to read secret from ${variable}"))?,
+            (Self::S3 { bucket, key }, _) => {
+                let location = ExternalBytes::S3 {
+                    bucket: bucket.clone(),
+                    key: key.clone(),
+                };
+
+                let bytes = location.resolve().await?;
+
+                String::from_utf8(bytes)
+                    .wrap_err_with(|| format!("secret at s3://{bucket}/{key} is not valid UTF-8"))?
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+/// Encrypts a value into a secret reference, only using an envelope when the value is too large to
+/// encrypt with the key directly.
+pub fn encrypt_secret(value: &str, public_key: &RsaPublicKey) -> Result<String> {
+    if fits_in_key(value, public_key) {
+        return Ok(format!("{SECRET_PREFIX}{}", encrypt(value, public_key)?));
+    }
+
+    let (key, payload) = seal(value, public_key)?;
+
+    Ok(format!("{SECRET_PREFIX}envelope:{key}:{payload}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use color_eyre::eyre::{eyre, Result};
+    use rand::rngs::ThreadRng;
+    use rsa::{RsaPrivateKey, RsaPublicKey};
+    use tempfile::NamedTempFile;
+
+    use crate::secrets::{encrypt_secret, SecretSource};
+
+    #[test]
+    fn references_are_parsed_by_their_scheme() -> Result<()> {
+        let parse = |value: &str| {
+            SecretSource::parse(value).ok_or_else(|| eyre!("{value} is not a secret"))?
+        };
+
+        assert!(SecretSource::parse("file:db.sqlite").is_none());
+
+        assert_eq!(
+            parse("secret:aGVsbG8=")?,
+            SecretSource::Inline {
+                encrypted: String::from("aGVsbG8=")
+            }
+        );
+        assert_eq!(
+            parse("secret:file:/run/secrets/password")?,
+            SecretSource::File {
+                path: PathBuf::from("/run/secrets/password")
+            }
+        );
+        assert_eq!(
+            parse("secret:s3:bucket/path/to/secret")?,
+            SecretSource::S3 {
+                bucket: String::from("bucket"),
+                key: String::from("path/to/secret"),
+            }
+        );
+
+        assert!(parse("secret:envelope:missing-payload").is_err());
+        assert!(parse("secret:vault:kv/password").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn secrets_can_be_read_from_files_and_the_environment() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "hunter2")?;
+
+        let from_file = SecretSource::File {
+            path: file.path().to_owned(),
+        };
+
+        assert_eq!(from_file.resolve(None).await?, "hunter2");
+
+        let from_environment = SecretSource::Environment {
+            variable: String::from("PATH"),
+        };
+
+        assert_eq!(
+            from_environment.resolve(None).await?,
+            std::env::var("PATH")?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_secrets_round_trip() -> Result<()> {
+        let mut rng: ThreadRng = rand::rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 1024)?;
+        let public_key = RsaPublicKey::from(&private_key);
+
+        for value in [String::from("password"), "x".repeat(1000)] {
+            let reference = encrypt_secret(&value, &public_key)?;
+            let source = SecretSource::parse(&reference)
+                .ok_or_else(|| eyre!("{reference} is not a secret"))??;
+
+            assert!(source.is_encrypted());
+            assert_eq!(source.resolve(Some(&private_key)).await?, value);
+            assert!(source.resolve(None).await.is_err());
+        }
+
+        Ok(())
+    }
+}
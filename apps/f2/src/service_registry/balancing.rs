@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use http::HeaderMap;
+
+use crate::config::{HashKey, LoadBalancingStrategy, Route};
+use crate::docker::api::StartedContainerDetails;
+use crate::docker::models::ContainerId;
+
+/// Information about an incoming request or connection that strategies can use to pick a
+/// downstream.
+#[derive(Debug, Default)]
+pub struct SelectionContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub headers: Option<&'a HeaderMap>,
+}
+
+impl SelectionContext<'_> {
+    /// Hashes the configured key for this request, if it is present.
+    fn hash_key(&self, key: &HashKey) -> Option<DefaultHasher> {
+        let mut hasher = DefaultHasher::new();
+
+        match key {
+            HashKey::ClientIp => self.client_ip?.hash(&mut hasher),
+            HashKey::Header { name } => self.headers?.get(name)?.as_bytes().hash(&mut hasher),
+        }
+
+        Some(hasher)
+    }
+}
+
+/// Picks downstreams for a single service according to its load balancing strategy.
+#[derive(Debug, Default)]
+pub struct Balancer {
+    strategy: LoadBalancingStrategy,
+    max_connections: Option<usize>,
+    next: AtomicUsize,
+    split: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(strategy: LoadBalancingStrategy) -> Self {
+        Self {
+            strategy,
+            max_connections: None,
+            next: AtomicUsize::new(0),
+            split: AtomicUsize::new(0),
+        }
+    }
+
+    /// Limits the number of outstanding requests or connections each container can have.
+    pub fn with_max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Decides whether a request should go to the canary containers, given the percentage of
+    /// traffic they should receive.
+    fn prefers_canary(&self, weight: u8, context: &SelectionContext<'_>) -> bool {
+        let weight = u64::from(weight);
+
+        // Keep sticky sessions on the same set of containers
+        if let LoadBalancingStrategy::ConsistentHash { key } = &self.strategy {
+            if let Some(hasher) = context.hash_key(key) {
+                return hasher.finish() % 100 < weight;
+            }
+        }
+
+        // Spread the canary requests evenly, sending exactly `weight` out of every 100
+        let n = self.split.fetch_add(1, Ordering::Relaxed) as u64 % 100;
+
+        (n + 1) * weight / 100 > n * weight / 100
+    }
+
+    fn choose(
+        &self,
+        containers: &[&StartedContainerDetails],
+        outstanding: impl Fn(&ContainerId) -> usize,
+        context: &SelectionContext<'_>,
+    ) -> Option<usize> {
+        let len = containers.len();
+
+        if len == 0 {
+            return None;
+        }
+
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+
+        let index = match &self.strategy {
+            LoadBalancingStrategy::RoundRobin => offset % len,
+            LoadBalancingStrategy::LeastOutstandingRequests => {
+                // Start from a rotating offset so ties are spread across the replicas
+                (0..len)
+                    .map(|i| (offset + i) % len)
+                    .min_by_key(|&i| outstanding(&containers[i].id))
+                    .unwrap_or(0)
+            }
+            LoadBalancingStrategy::ConsistentHash { key } => match context.hash_key(key) {
+                Some(hasher) => rendezvous(hasher, containers),
+                None => offset % len,
+            },
+        };
+
+        Some(index)
+    }
+}
+
+/// Picks the container with the highest hash for the key, so that only keys belonging to a removed
+/// container move when the set of containers changes.
+fn rendezvous(hasher: DefaultHasher, containers: &[&StartedContainerDetails]) -> usize {
+    containers
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, details)| {
+            let mut hasher = hasher.clone();
+            details.id.hash(&mut hasher);
+            hasher.finish()
+        })
+        .map_or(0, |(index, _)| index)
+}
+
+/// The healthy canary containers for a service and the percentage of traffic they should receive.
+#[derive(Debug)]
+pub struct CanaryDownstreams<'a> {
+    pub containers: Vec<&'a StartedContainerDetails>,
+    pub weight: u8,
+}
+
+/// The healthy downstream containers that can serve a request, along with the route it matched.
+#[derive(Debug)]
+pub struct Downstreams<'a> {
+    pub containers: Vec<&'a StartedContainerDetails>,
+    pub canary: Option<CanaryDownstreams<'a>>,
+    pub route: &'a Route,
+    balancer: &'a Balancer,
+    outstanding: &'a HashMap<ContainerId, Arc<AtomicUsize>>,
+}
+
+impl<'a> Downstreams<'a> {
+    pub fn new(
+        containers: Vec<&'a StartedContainerDetails>,
+        route: &'a Route,
+        balancer: &'a Balancer,
+        outstanding: &'a HashMap<ContainerId, Arc<AtomicUsize>>,
+    ) -> Self {
+        Self {
+            containers,
+            canary: None,
+            route,
+            balancer,
+            outstanding,
+        }
+    }
+
+    /// Gets the port on the containers to send traffic to.
+    pub fn port(&self) -> u16 {
+        self.route.port
+    }
+
+    /// Adds canary containers which will receive `weight` percent of the requests.
+    pub fn with_canary(mut self, containers: Vec<&'a StartedContainerDetails>, weight: u8) -> Self {
+        self.canary = Some(CanaryDownstreams { containers, weight });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+            && self
+                .canary
+                .as_ref()
+                .is_none_or(|canary| canary.containers.is_empty())
+    }
+
+    /// Picks whether to use the stable or canary containers, falling back to whichever has healthy
+    /// containers if the other is empty.
+    fn choose_pool(&self, context: &SelectionContext<'_>) -> &[&'a StartedContainerDetails] {
+        match &self.canary {
+            Some(canary)
+                if !canary.containers.is_empty()
+                    && (self.containers.is_empty()
+                        || self.balancer.prefers_canary(canary.weight, context)) =>
+            {
+                &canary.containers
+            }
+            _ => &self.containers,
+        }
+    }
+
+    /// Chooses a downstream using the service's strategy, returning `None` if there are none or
+    /// they are all at their connection limit.
+    pub fn select(&self, context: &SelectionContext<'_>) -> Option<SelectedDownstream> {
+        let outstanding = |id: &ContainerId| {
+            self.outstanding
+                .get(id)
+                .map_or(0, |count| count.load(Ordering::Relaxed))
+        };
+
+        let max_connections = self.balancer.max_connections;
+
+        let pool: Vec<_> = self
+            .choose_pool(context)
+            .iter()
+            .filter(|details| max_connections.is_none_or(|max| outstanding(&details.id) < max))
+            .copied()
+            .collect();
+
+        let index = self.balancer.choose(&pool, outstanding, context)?;
+        let details = pool[index];
+
+        let counter = self
+            .outstanding
+            .get(&details.id)
+            .cloned()
+            .unwrap_or_default();
+
+        SelectedDownstream::reserve(details, counter, max_connections)
+    }
+}
+
+/// A downstream chosen to handle a request, which counts as outstanding until it is dropped.
+#[derive(Debug)]
+pub struct SelectedDownstream {
+    pub id: ContainerId,
+    pub addr: Ipv4Addr,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl SelectedDownstream {
+    /// Counts a new outstanding request for the container, unless it has reached the limit since
+    /// it was chosen.
+    fn reserve(
+        details: &StartedContainerDetails,
+        outstanding: Arc<AtomicUsize>,
+        max_connections: Option<usize>,
+    ) -> Option<Self> {
+        outstanding
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                max_connections
+                    .is_none_or(|max| count < max)
+                    .then_some(count + 1)
+            })
+            .ok()?;
+
+        Some(Self {
+            id: details.id.clone(),
+            addr: details.addr,
+            outstanding,
+        })
+    }
+}
+
+impl Drop for SelectedDownstream {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use http::{HeaderMap, HeaderValue};
+
+    use crate::config::{HashKey, LoadBalancingStrategy, Route};
+    use crate::docker::api::StartedContainerDetails;
+    use crate::docker::models::ContainerId;
+    use crate::service_registry::balancing::{Balancer, Downstreams, SelectionContext};
+
+    fn create_containers(count: u8) -> Vec<StartedContainerDetails> {
+        (0..count)
+            .map(|i| StartedContainerDetails {
+                id: ContainerId::random(),
+                addr: Ipv4Addr::new(127, 0, 0, i),
+            })
+            .collect()
+    }
+
+    fn create_route() -> Route {
+        Route {
+            port: 80,
+            ..Default::default()
+        }
+    }
+
+    fn create_counters(
+        containers: &[StartedContainerDetails],
+    ) -> HashMap<ContainerId, Arc<AtomicUsize>> {
+        containers
+            .iter()
+            .map(|details| (details.id.clone(), Arc::default()))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_cycles_through_containers() {
+        let containers = create_containers(3);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer = Balancer::new(LoadBalancingStrategy::RoundRobin);
+
+        let downstreams =
+            Downstreams::new(containers.iter().collect(), &route, &balancer, &counters);
+        let context = SelectionContext::default();
+
+        let selected: Vec<_> = (0..6)
+            .map(|_| downstreams.select(&context).unwrap().addr)
+            .collect();
+
+        let expected: Vec<_> = containers
+            .iter()
+            .chain(containers.iter())
+            .map(|details| details.addr)
+            .collect();
+
+        assert_eq!(selected, expected);
+    }
+
+    #[test]
+    fn least_outstanding_requests_avoids_busy_containers() {
+        let containers = create_containers(2);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer = Balancer::new(LoadBalancingStrategy::LeastOutstandingRequests);
+
+        let downstreams =
+            Downstreams::new(containers.iter().collect(), &route, &balancer, &counters);
+        let context = SelectionContext::default();
+
+        // Hold on to the first selection so it stays outstanding
+        let busy = downstreams.select(&context).unwrap();
+
+        for _ in 0..4 {
+            let selected = downstreams.select(&context).unwrap();
+            assert_ne!(selected.id, busy.id);
+        }
+
+        // Once finished, the container is eligible again
+        let busy_id = busy.id.clone();
+        drop(busy);
+
+        let selected: Vec<_> = (0..2)
+            .map(|_| downstreams.select(&context).unwrap().id.clone())
+            .collect();
+
+        assert!(selected.contains(&busy_id));
+    }
+
+    #[test]
+    fn canaries_receive_their_share_of_requests() {
+        let containers = create_containers(4);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer = Balancer::new(LoadBalancingStrategy::RoundRobin);
+
+        let (stable, canary) = containers.split_at(2);
+        let downstreams = Downstreams::new(stable.iter().collect(), &route, &balancer, &counters)
+            .with_canary(canary.iter().collect(), 10);
+
+        let context = SelectionContext::default();
+
+        let canary_requests = (0..1000)
+            .filter(|_| {
+                let selected = downstreams.select(&context).unwrap();
+                canary.iter().any(|details| details.id == selected.id)
+            })
+            .count();
+
+        assert_eq!(canary_requests, 100);
+    }
+
+    #[test]
+    fn requests_fall_back_to_stable_containers_without_healthy_canaries() {
+        let containers = create_containers(1);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer = Balancer::new(LoadBalancingStrategy::RoundRobin);
+
+        let downstreams =
+            Downstreams::new(containers.iter().collect(), &route, &balancer, &counters)
+                .with_canary(Vec::new(), 100);
+
+        let context = SelectionContext::default();
+
+        for _ in 0..5 {
+            assert_eq!(downstreams.select(&context).unwrap().id, containers[0].id);
+        }
+    }
+
+    #[test]
+    fn consistent_hashing_on_headers_is_sticky() {
+        let containers = create_containers(5);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer = Balancer::new(LoadBalancingStrategy::ConsistentHash {
+            key: HashKey::Header {
+                name: String::from("x-session"),
+            },
+        });
+
+        let downstreams =
+            Downstreams::new(containers.iter().collect(), &route, &balancer, &counters);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-session", HeaderValue::from_static("abc123"));
+
+        let context = SelectionContext {
+            client_ip: None,
+            headers: Some(&headers),
+        };
+
+        let first = downstreams.select(&context).unwrap().id.clone();
+
+        for _ in 0..10 {
+            assert_eq!(downstreams.select(&context).unwrap().id, first);
+        }
+    }
+
+    #[test]
+    fn consistent_hashing_only_moves_keys_for_removed_containers() {
+        let containers = create_containers(5);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer = Balancer::new(LoadBalancingStrategy::ConsistentHash {
+            key: HashKey::ClientIp,
+        });
+
+        let all = Downstreams::new(containers.iter().collect(), &route, &balancer, &counters);
+        let remaining = Downstreams::new(
+            containers.iter().skip(1).collect(),
+            &route,
+            &balancer,
+            &counters,
+        );
+
+        for i in 0..50 {
+            let context = SelectionContext {
+                client_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))),
+                headers: None,
+            };
+
+            let before = all.select(&context).unwrap().id.clone();
+            let after = remaining.select(&context).unwrap().id.clone();
+
+            if before != containers[0].id {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn containers_at_their_connection_limit_are_skipped() {
+        let containers = create_containers(2);
+        let counters = create_counters(&containers);
+        let route = create_route();
+        let balancer =
+            Balancer::new(LoadBalancingStrategy::RoundRobin).with_max_connections(Some(1));
+
+        let downstreams =
+            Downstreams::new(containers.iter().collect(), &route, &balancer, &counters);
+        let context = SelectionContext::default();
+
+        let first = downstreams.select(&context).unwrap();
+        let second = downstreams.select(&context).unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert!(downstreams.select(&context).is_none());
+
+        // Finishing a request frees up a slot on that container
+        let freed = first.id.clone();
+        drop(first);
+
+        assert_eq!(downstreams.select(&context).unwrap().id, freed);
+    }
+}
@@ -17,9 +17,40 @@ impl<'a> PathMatchCalculator<'a> {
     }
 }
 
+/// Removes the port from a host, such as one taken from the `Host` header.
+pub fn strip_port(host: &str) -> &str {
+    // IPv6 literals contain colons, so only the part after the brackets can be a port
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+
+    match host.rsplit_once(':') {
+        Some((hostname, port)) if port.bytes().all(|b| b.is_ascii_digit()) => hostname,
+        _ => host,
+    }
+}
+
+/// Gets how specifically a host pattern matches a host, or `None` if it does not match.
+///
+/// Patterns are either exact hosts or wildcards such as `*.example.com`, which match a single
+/// label like certificates do. Exact matches are the most specific, followed by wildcards with the
+/// longest suffix.
+pub fn host_match_specificity(pattern: &str, host: &str) -> Option<usize> {
+    if pattern.eq_ignore_ascii_case(host) {
+        return Some(usize::MAX);
+    }
+
+    let suffix = pattern.strip_prefix("*.")?;
+    let (label, rest) = host.split_once('.')?;
+
+    (!label.is_empty() && rest.eq_ignore_ascii_case(suffix)).then_some(suffix.len())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::service_registry::matching::PathMatchCalculator;
+    use crate::service_registry::matching::{
+        host_match_specificity, strip_port, PathMatchCalculator,
+    };
 
     #[test]
     fn computes_correctly_for_matching_prefix() {
@@ -92,4 +123,34 @@ mod tests {
         let result = PathMatchCalculator::new(path, prefix).compute_match_length();
         assert_eq!(result, 10); // Length of "/resource/"
     }
+
+    #[test]
+    fn ports_are_stripped_from_hosts() {
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn exact_hosts_are_more_specific_than_wildcards() {
+        let exact = host_match_specificity("api.example.com", "api.example.com");
+        let wildcard = host_match_specificity("*.example.com", "api.example.com");
+        let nested = host_match_specificity("*.api.example.com", "v1.api.example.com");
+
+        assert!(exact > wildcard);
+        assert!(nested > host_match_specificity("*.example.com", "api.example.com"));
+        assert_eq!(
+            host_match_specificity("API.example.com", "api.EXAMPLE.com"),
+            exact
+        );
+    }
+
+    #[test]
+    fn wildcards_only_match_a_single_label() {
+        assert!(host_match_specificity("*.example.com", "example.com").is_none());
+        assert!(host_match_specificity("*.example.com", "v1.api.example.com").is_none());
+        assert!(host_match_specificity("*.example.com", ".example.com").is_none());
+        assert!(host_match_specificity("*.example.com", "api.example.org").is_none());
+    }
 }
@@ -1,19 +1,43 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use indexmap::IndexSet;
 
-use crate::config::Service;
+use crate::config::{Diff, HealthCheckDefinition, Route, Service};
 use crate::docker::api::StartedContainerDetails;
 use crate::docker::models::ContainerId;
+use crate::service_registry::balancing::Balancer;
 use crate::service_registry::matching::PathMatchCalculator;
 
+pub use balancing::{Downstreams, SelectedDownstream, SelectionContext};
+pub use matching::{host_match_specificity, strip_port};
+
+mod balancing;
 mod matching;
 
+/// The changes found by the most recent reconciliation that found any.
+#[derive(Clone, Debug)]
+pub struct ReconciliationRecord {
+    pub timestamp: DateTime<Utc>,
+    pub changes: Vec<Diff>,
+    /// The error for each service whose change failed and was rolled back.
+    pub failures: HashMap<String, String>,
+}
+
 /// Registry of all of the running services.
 #[derive(Debug, Default)]
 pub struct ServiceRegistry {
     definitions: HashMap<String, Service>,
     containers: HashMap<String, IndexSet<StartedContainerDetails>>,
+    canaries: HashMap<String, IndexSet<StartedContainerDetails>>,
+    unhealthy: HashSet<ContainerId>,
+    draining: HashSet<ContainerId>,
+    balancers: HashMap<String, Balancer>,
+    outstanding: HashMap<ContainerId, Arc<AtomicUsize>>,
+    last_reconciliation: Option<ReconciliationRecord>,
 }
 
 impl ServiceRegistry {
@@ -22,13 +46,22 @@ impl ServiceRegistry {
     }
 
     pub fn define(&mut self, service: &str, definition: Service) {
+        let balancer = Balancer::new(definition.load_balancing.clone())
+            .with_max_connections(definition.max_connections);
+
+        self.balancers.insert(service.to_string(), balancer);
         self.definitions.insert(service.to_string(), definition);
     }
 
     pub fn undefine(&mut self, service: &str) {
+        self.balancers.remove(service);
         self.definitions.remove(service);
     }
 
+    pub fn get_definitions(&self) -> &HashMap<String, Service> {
+        &self.definitions
+    }
+
     pub fn get_running_containers(
         &self,
         service: &str,
@@ -42,46 +75,220 @@ impl ServiceRegistry {
     pub fn add_container(&mut self, service: &str, details: StartedContainerDetails) {
         tracing::info!("adding a downstream container");
 
+        self.outstanding.entry(details.id.clone()).or_default();
+
         self.containers
             .entry(service.to_string())
             .or_default()
             .insert(details);
     }
 
+    pub fn get_canary_containers(
+        &self,
+        service: &str,
+    ) -> Option<&IndexSet<StartedContainerDetails>> {
+        self.canaries.get(service)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn add_canary_container(&mut self, service: &str, details: StartedContainerDetails) {
+        tracing::info!("adding a canary container");
+
+        self.outstanding.entry(details.id.clone()).or_default();
+
+        self.canaries
+            .entry(service.to_string())
+            .or_default()
+            .insert(details);
+    }
+
     pub fn remove_all_containers(&mut self, service: &str) {
-        self.containers.remove(service);
+        if let Some(containers) = self.containers.remove(service) {
+            self.forget_containers(&containers);
+        }
+    }
+
+    pub fn remove_all_canary_containers(&mut self, service: &str) {
+        if let Some(containers) = self.canaries.remove(service) {
+            self.forget_containers(&containers);
+        }
+    }
+
+    fn forget_containers(&mut self, containers: &IndexSet<StartedContainerDetails>) {
+        for details in containers {
+            self.unhealthy.remove(&details.id);
+            self.draining.remove(&details.id);
+            self.outstanding.remove(&details.id);
+        }
     }
 
     pub fn remove_container_by_id(&mut self, service: &str, id: &ContainerId) {
-        if let Some(containers) = self.containers.get_mut(service) {
-            containers.retain(|c| c.id != *id);
+        for containers in [&mut self.containers, &mut self.canaries] {
+            if let Some(containers) = containers.get_mut(service) {
+                containers.retain(|c| c.id != *id);
+            }
         }
+
+        self.unhealthy.remove(id);
+        self.draining.remove(id);
+        self.outstanding.remove(id);
     }
 
-    pub fn find_downstreams(
+    /// Finds the service a container belongs to, along with whether it is a canary.
+    pub fn find_container(
         &self,
-        host: &str,
-        path: &str,
-    ) -> Option<(&IndexSet<StartedContainerDetails>, u16)> {
-        tracing::debug!(host, path, "finding downstream containers");
+        id: &ContainerId,
+    ) -> Option<(&str, &StartedContainerDetails, bool)> {
+        [(&self.containers, false), (&self.canaries, true)]
+            .into_iter()
+            .flat_map(|(containers, canary)| {
+                containers.iter().flat_map(move |(name, containers)| {
+                    containers
+                        .iter()
+                        .map(move |details| (name.as_str(), details, canary))
+                })
+            })
+            .find(|(_, details, _)| details.id == *id)
+    }
+
+    /// Stops sending new traffic to a container while leaving it running, returning whether the
+    /// container was found.
+    pub fn drain(&mut self, id: &ContainerId) -> bool {
+        if self.find_container(id).is_none() {
+            return false;
+        }
+
+        self.draining.insert(id.clone());
+
+        true
+    }
 
+    /// Sends new traffic to a drained container again, returning whether the container was found.
+    pub fn undrain(&mut self, id: &ContainerId) -> bool {
+        if self.find_container(id).is_none() {
+            return false;
+        }
+
+        self.draining.remove(id);
+
+        true
+    }
+
+    pub fn is_draining(&self, id: &ContainerId) -> bool {
+        self.draining.contains(id)
+    }
+
+    pub fn record_reconciliation(&mut self, changes: Vec<Diff>, failures: HashMap<String, String>) {
+        self.last_reconciliation = Some(ReconciliationRecord {
+            timestamp: Utc::now(),
+            changes,
+            failures,
+        });
+    }
+
+    pub fn get_last_reconciliation(&self) -> Option<&ReconciliationRecord> {
+        self.last_reconciliation.as_ref()
+    }
+
+    /// Marks a container as unhealthy, excluding it from the downstreams until it recovers.
+    pub fn mark_unhealthy(&mut self, id: &ContainerId) {
+        self.unhealthy.insert(id.clone());
+    }
+
+    /// Marks a container as healthy, allowing it to receive traffic again.
+    pub fn mark_healthy(&mut self, id: &ContainerId) {
+        self.unhealthy.remove(id);
+    }
+
+    pub fn is_healthy(&self, id: &ContainerId) -> bool {
+        !self.unhealthy.contains(id)
+    }
+
+    /// Gets the health check and running containers, including canaries, for each service that
+    /// defines a health check.
+    pub fn get_health_checked_containers(
+        &self,
+    ) -> Vec<(String, HealthCheckDefinition, Vec<StartedContainerDetails>)> {
         self.definitions
             .iter()
             .filter_map(|(name, service)| {
-                service
-                    .routes
-                    .iter()
-                    .find(|route| route.host == host)
-                    .map(|route| {
-                        let calculator = PathMatchCalculator::new(path, route.prefix.as_deref());
-                        (name, calculator.compute_match_length(), route.port)
-                    })
+                let health_check = service.health_check.clone()?;
+                let containers = [&self.containers, &self.canaries]
+                    .into_iter()
+                    .filter_map(|containers| containers.get(name))
+                    .flatten()
+                    .cloned()
+                    .collect();
+
+                Some((name.clone(), health_check, containers))
             })
-            .min_by_key(|(_, match_length, _)| *match_length)
-            .and_then(|(name, _, port)| {
-                self.get_running_containers(name)
-                    .map(|downstreams| (downstreams, port))
+            .collect()
+    }
+
+    fn healthy_containers<'a>(
+        &'a self,
+        containers: &'a IndexSet<StartedContainerDetails>,
+    ) -> Vec<&'a StartedContainerDetails> {
+        containers
+            .iter()
+            .filter(|details| self.is_healthy(&details.id) && !self.is_draining(&details.id))
+            .collect()
+    }
+
+    /// Finds the service and route that best match a host and path, where the host can be one of
+    /// the route's aliases. The most specific host match wins, followed by the longest path prefix.
+    fn match_route(&self, host: &str, path: &str) -> Option<(&String, &Route)> {
+        let host = strip_port(host);
+
+        self.definitions
+            .iter()
+            .flat_map(|(name, service)| service.routes.iter().map(move |route| (name, route)))
+            .filter_map(|(name, route)| {
+                let specificity = std::iter::once(&route.host)
+                    .chain(&route.aliases)
+                    .filter_map(|pattern| host_match_specificity(pattern, host))
+                    .max()?;
+
+                let calculator = PathMatchCalculator::new(path, route.prefix.as_deref());
+                let match_length = calculator.compute_match_length();
+
+                // Routes whose prefix does not match the path are never candidates
+                (match_length != usize::MAX).then_some((name, route, specificity, match_length))
             })
+            .min_by_key(|(_, _, specificity, match_length)| (Reverse(*specificity), *match_length))
+            .map(|(name, route, _, _)| (name, route))
+    }
+
+    /// Finds the service and route for a given host and path, regardless of whether it has any
+    /// containers.
+    pub fn find_route(&self, host: &str, path: &str) -> Option<(&str, &Route)> {
+        self.match_route(host, path)
+            .map(|(name, route)| (name.as_str(), route))
+    }
+
+    /// Finds the healthy downstream containers and port for a given host and path, along with any
+    /// canary containers that should receive a share of the traffic.
+    pub fn find_downstreams(&self, host: &str, path: &str) -> Option<Downstreams<'_>> {
+        tracing::debug!(host, path, "finding downstream containers");
+
+        let (name, route) = self.match_route(host, path)?;
+
+        let balancer = self.balancers.get(name)?;
+        let stable = self.healthy_containers(self.get_running_containers(name)?);
+        let mut downstreams = Downstreams::new(stable, route, balancer, &self.outstanding);
+
+        let weight = self
+            .definitions
+            .get(name)?
+            .canary
+            .as_ref()
+            .map(|c| *c.weight);
+
+        if let (Some(weight), Some(canaries)) = (weight, self.canaries.get(name)) {
+            downstreams = downstreams.with_canary(self.healthy_containers(canaries), weight);
+        }
+
+        Some(downstreams)
     }
 }
 
@@ -90,7 +297,9 @@ mod tests {
     use std::collections::HashSet;
     use std::net::Ipv4Addr;
 
-    use crate::config::{Route, Service};
+    use color_eyre::eyre::Result;
+
+    use crate::config::{CanaryDefinition, CanaryWeight, ReplicaCount, Route, Service};
     use crate::docker::api::StartedContainerDetails;
     use crate::docker::models::ContainerId;
     use crate::service_registry::ServiceRegistry;
@@ -196,9 +405,9 @@ mod tests {
         host: &str,
         path: &str,
     ) -> Option<HashSet<ContainerId>> {
-        registry.find_downstreams(host, path).map(|value| {
-            value
-                .0
+        registry.find_downstreams(host, path).map(|downstreams| {
+            downstreams
+                .containers
                 .into_iter()
                 .map(|details| details.id.clone())
                 .collect()
@@ -296,6 +505,45 @@ mod tests {
         assert!(registry.get_running_containers(name).is_none());
     }
 
+    #[test]
+    fn unhealthy_containers_are_not_returned_as_downstreams() {
+        let mut registry = ServiceRegistry::new();
+        let name = "foobar";
+        let host = "foo.bar";
+
+        define_service(&mut registry, name, host, None);
+
+        let healthy = add_container(&mut registry, name);
+        let unhealthy = add_container(&mut registry, name);
+
+        registry.mark_unhealthy(&unhealthy);
+
+        let downstreams = find_matching_container_ids(&registry, host, "/");
+
+        assert_eq!(downstreams, Some(HashSet::from([healthy.clone()])));
+
+        registry.mark_healthy(&unhealthy);
+
+        let downstreams = find_matching_container_ids(&registry, host, "/");
+
+        assert_eq!(downstreams, Some(HashSet::from([healthy, unhealthy])));
+    }
+
+    #[test]
+    fn removing_containers_forgets_their_health() {
+        let mut registry = ServiceRegistry::new();
+        let name = "foobar";
+
+        define_service(&mut registry, name, "foo.bar", None);
+
+        let container = add_container(&mut registry, name);
+        registry.mark_unhealthy(&container);
+
+        registry.remove_container_by_id(name, &container);
+
+        assert!(registry.is_healthy(&container));
+    }
+
     #[test]
     fn can_find_downstream_by_multiple_hosts_if_configured() {
         let mut registry = ServiceRegistry::new();
@@ -322,15 +570,166 @@ mod tests {
         registry.define(name, service);
         let container_id = add_container(&mut registry, name);
 
-        let internal_downstreams = registry.find_downstreams(internal_host, path);
-        let external_downstreams = registry.find_downstreams(external_host, path);
+        let internal_downstreams = find_matching_container_ids(&registry, internal_host, path);
+        let external_downstreams = find_matching_container_ids(&registry, external_host, path);
 
         assert_eq!(internal_downstreams, external_downstreams);
+        assert_eq!(internal_downstreams, Some(HashSet::from([container_id])));
+    }
+
+    #[test]
+    fn canary_containers_are_returned_with_their_weight() -> Result<()> {
+        let mut registry = ServiceRegistry::new();
+        let name = "foobar";
+        let host = "foo.bar";
+
+        let service = Service {
+            routes: HashSet::from([Route {
+                host: host.to_string(),
+                ..Default::default()
+            }]),
+            canary: Some(CanaryDefinition {
+                tag: String::from("v2"),
+                replicas: ReplicaCount::default(),
+                weight: CanaryWeight::try_from(25)?,
+            }),
+            ..Default::default()
+        };
+
+        registry.define(name, service);
+
+        let stable = add_container(&mut registry, name);
+        let canary = ContainerId::random();
+
+        registry.add_canary_container(
+            name,
+            StartedContainerDetails {
+                id: canary.clone(),
+                addr: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let downstreams = registry
+            .find_downstreams(host, "/")
+            .expect("service should have downstreams");
+
+        let stable_ids: Vec<_> = downstreams.containers.iter().map(|d| &d.id).collect();
+        let canary_downstreams = downstreams
+            .canary
+            .map(|c| (c.containers.iter().map(|d| &d.id).collect(), c.weight));
+
+        assert_eq!(stable_ids, vec![&stable]);
+        assert_eq!(canary_downstreams, Some((vec![&canary], 25)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn routes_can_be_found_by_alias_without_any_containers() {
+        let mut registry = ServiceRegistry::new();
+
+        let route = Route {
+            host: String::from("example.com"),
+            aliases: vec![String::from("www.example.com")],
+            ..Default::default()
+        };
+
+        let service = Service {
+            routes: HashSet::from([route.clone()]),
+            ..Default::default()
+        };
+
+        registry.define("frontend", service);
+
+        assert_eq!(
+            registry.find_route("www.example.com", "/"),
+            Some(("frontend", &route))
+        );
+        assert_eq!(
+            registry.find_route("example.com", "/"),
+            Some(("frontend", &route))
+        );
+        assert_eq!(registry.find_route("example.org", "/"), None);
+        assert!(registry.find_downstreams("example.com", "/").is_none());
+    }
+
+    #[test]
+    fn the_most_specific_host_match_wins() {
+        let mut registry = ServiceRegistry::new();
+
+        define_service(&mut registry, "tenants", "*.example.com", None);
+        define_service(&mut registry, "api", "api.example.com", None);
+
+        let tenants_id = add_container(&mut registry, "tenants");
+        let api_id = add_container(&mut registry, "api");
+
+        assert_eq!(
+            find_matching_container_ids(&registry, "api.example.com:8080", "/"),
+            Some(HashSet::from([api_id]))
+        );
+        assert_eq!(
+            find_matching_container_ids(&registry, "acme.example.com", "/"),
+            Some(HashSet::from([tenants_id]))
+        );
+        assert_eq!(
+            find_matching_container_ids(&registry, "example.com", "/"),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_with_a_non_matching_prefix_are_ignored() {
+        let mut registry = ServiceRegistry::new();
+
+        define_service(
+            &mut registry,
+            "api",
+            "app.example.com",
+            Some(String::from("/api")),
+        );
+        define_service(
+            &mut registry,
+            "tenants",
+            "*.example.com",
+            Some(String::from("/")),
+        );
+
+        let api_id = add_container(&mut registry, "api");
+        let tenants_id = add_container(&mut registry, "tenants");
+
+        assert_eq!(
+            find_matching_container_ids(&registry, "app.example.com", "/api/users"),
+            Some(HashSet::from([api_id]))
+        );
+        assert_eq!(
+            find_matching_container_ids(&registry, "app.example.com", "/other"),
+            Some(HashSet::from([tenants_id]))
+        );
+        assert_eq!(registry.find_route("app.example.org", "/other"), None);
+    }
+
+    #[test]
+    fn drained_containers_receive_no_new_traffic() {
+        let mut registry = ServiceRegistry::new();
+
+        define_service(&mut registry, "backend", "example.com", None);
+
+        let drained = add_container(&mut registry, "backend");
+        let remaining = add_container(&mut registry, "backend");
+
+        assert!(registry.drain(&drained));
+        assert!(!registry.drain(&ContainerId::random()));
+
+        assert_eq!(
+            find_matching_container_ids(&registry, "example.com", "/"),
+            Some(HashSet::from([remaining]))
+        );
+
+        // Draining does not affect whether the container is still running
+        assert!(registry.find_container(&drained).is_some());
+
+        registry.remove_container_by_id("backend", &drained);
 
-        assert!(internal_downstreams.is_some_and(|(containers, _)| {
-            containers
-                .iter()
-                .any(|container| container.id == container_id)
-        }));
+        assert!(!registry.is_draining(&drained));
     }
 }